@@ -0,0 +1,272 @@
+//! Everything the client can be configured with.
+//!
+//! Settings come from config.toml in rusty_chat under the user's config directory (~/.config on
+//! Linux) or the file given with --config, then environment variables, then command line flags,
+//! later ones winning. Anything left open keeps its default, the files the client keeps go into
+//! that rusty_chat directory as well.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use clap::builder::BoolishValueParser;
+use console::Style;
+use serde_derive::Deserialize;
+use common::ChatMode;
+use common::validation;
+
+const DEFAULT_SERVER: &str = "localhost:3333";
+const DEFAULT_P2P_PORT: u16 = 3334;
+const DEFAULT_IDENTITY_FILE: &str = "identity.key";
+const DEFAULT_KNOWN_PEERS_FILE: &str = "known_peers.txt";
+/// What console's dotted styles are made of, anything else would be dropped silently.
+const STYLE_PARTS: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white", "bright",
+    "on_black", "on_red", "on_green", "on_yellow", "on_blue", "on_magenta", "on_cyan", "on_white", "on_bright",
+    "bold", "dim", "underlined", "blink", "reverse", "hidden"
+];
+
+/// Client of Rusty Chat. Flags override the config file.
+#[derive(Parser, Debug)]
+#[command(name = "simple_client")]
+pub struct Args {
+    /// TOML file with the settings, the flags below have the same names with underscores.
+    /// Defaults to rusty_chat/config.toml in the user's config directory
+    #[arg(long, env = "RUSTY_CHAT_CLIENT_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Discovery server to log in at, host:port
+    #[arg(long, env = "RUSTY_CHAT_SERVER", value_name = "ADDRESS")]
+    server: Option<String>,
+    /// Name to log in with instead of asking for it
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+    /// Chat mode to pick right after logging in instead of asking for it
+    #[arg(long, value_name = "direct|rooms|wait")]
+    mode: Option<String>,
+    /// Port direct chat partners connect to when we are the master
+    #[arg(long, env = "RUSTY_CHAT_P2P_PORT", value_name = "PORT")]
+    p2p_port: Option<u16>,
+    /// Style of system messages, like green or cyan.bold
+    #[arg(long, value_name = "STYLE")]
+    system_color: Option<String>,
+    #[arg(long, value_name = "STYLE")]
+    chat_color: Option<String>,
+    #[arg(long, value_name = "STYLE")]
+    error_color: Option<String>,
+    /// Chat messages are appended to this file, none are kept if not set
+    #[arg(long, value_name = "FILE")]
+    history_file: Option<PathBuf>,
+    #[arg(long, env = "RUSTY_CHAT_IDENTITY_FILE", value_name = "FILE")]
+    identity_file: Option<PathBuf>,
+    #[arg(long, env = "RUSTY_CHAT_KNOWN_PEERS_FILE", value_name = "FILE")]
+    known_peers_file: Option<PathBuf>,
+    /// PEM file of the one certificate the discovery server may use
+    #[arg(long, env = "RUSTY_CHAT_TLS_PINNED_CERT", value_name = "FILE")]
+    tls_pinned_cert: Option<PathBuf>,
+    /// Use TLS with a certificate from one of the public CAs
+    #[arg(long, env = "RUSTY_CHAT_TLS", value_name = "BOOL", value_parser = BoolishValueParser::new(),
+          num_args = 0..=1, default_missing_value = "true")]
+    tls: Option<bool>
+}
+
+/// What the config file may contain, everything is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    server: Option<String>,
+    name: Option<String>,
+    mode: Option<String>,
+    p2p_port: Option<u16>,
+    system_color: Option<String>,
+    chat_color: Option<String>,
+    error_color: Option<String>,
+    history_file: Option<PathBuf>,
+    identity_file: Option<PathBuf>,
+    known_peers_file: Option<PathBuf>,
+    tls_pinned_cert: Option<PathBuf>,
+    tls: Option<bool>
+}
+
+pub struct Colors {
+    pub system: Style,
+    pub chat: Style,
+    pub error: Style
+}
+
+impl Default for Colors {
+    fn default() -> Colors {
+        Colors{system: Style::new().green(), chat: Style::new().yellow(), error: Style::new().red()}
+    }
+}
+
+pub struct Config {
+    pub server: String,
+    /// Asked for if not set
+    pub name: Option<String>,
+    /// Asked for if not set
+    pub mode: Option<ChatMode>,
+    pub p2p_port: u16,
+    pub colors: Colors,
+    pub history_file: Option<PathBuf>,
+    pub identity_file: PathBuf,
+    pub known_peers_file: PathBuf,
+    pub tls_pinned_cert: Option<PathBuf>,
+    /// Ignored if there is a pinned certificate
+    pub tls: bool
+}
+
+impl Config {
+    /// Reads the config file, if any, and checks the outcome.
+    /// A missing file in the config directory is fine, one given explicitly has to be there.
+    pub fn load(args: Args) -> Result<Config, String> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => match default_path() {
+                Some(path) if path.exists() => read_file(&path)?,
+                _ => ConfigFile::default()
+            }
+        };
+
+        let server = args.server.or(file.server).unwrap_or_else(|| String::from(DEFAULT_SERVER));
+        if !server.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(format!("server '{}' is not a host:port address", server));
+        }
+        let name = args.name.or(file.name);
+        if let Some(name) = &name {
+            validation::validate_user_name(name).map_err(|e| format!("name '{}': {}", name, e))?;
+        }
+        let mode = args.mode.or(file.mode).map(|mode| parse_mode(&mode)).transpose()?;
+        let p2p_port = args.p2p_port.or(file.p2p_port).unwrap_or(DEFAULT_P2P_PORT);
+        if p2p_port == 0 {
+            return Err(String::from("p2p_port has to be a port number from 1 to 65535"));
+        }
+        let defaults = Colors::default();
+        let colors = Colors{
+            system: style("system_color", args.system_color.or(file.system_color), defaults.system)?,
+            chat: style("chat_color", args.chat_color.or(file.chat_color), defaults.chat)?,
+            error: style("error_color", args.error_color.or(file.error_color), defaults.error)?
+        };
+
+        Ok(Config{
+            server, name, mode, p2p_port, colors,
+            history_file: args.history_file.or(file.history_file),
+            identity_file: args.identity_file.or(file.identity_file).unwrap_or_else(|| default_file(DEFAULT_IDENTITY_FILE)),
+            known_peers_file: args.known_peers_file.or(file.known_peers_file).unwrap_or_else(|| default_file(DEFAULT_KNOWN_PEERS_FILE)),
+            tls_pinned_cert: args.tls_pinned_cert.or(file.tls_pinned_cert),
+            tls: args.tls.or(file.tls).unwrap_or(false)
+        })
+    }
+}
+
+fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rusty_chat"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+/// In the config directory, or the working directory on systems without one.
+fn default_file(name: &str) -> PathBuf {
+    config_dir().map_or_else(|| PathBuf::from(name), |dir| dir.join(name))
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("{} is not a valid config file: {}", path.display(), e))
+}
+
+fn parse_mode(mode: &str) -> Result<ChatMode, String> {
+    match mode {
+        "direct" => Ok(ChatMode::DIRECT),
+        "rooms" => Ok(ChatMode::ROOM),
+        "wait" => Ok(ChatMode::WAIT),
+        _ => Err(format!("mode '{}' is none of direct, rooms or wait", mode))
+    }
+}
+
+fn style(key: &str, value: Option<String>, default: Style) -> Result<Style, String> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(default)
+    };
+    match value.split('.').find(|part| !STYLE_PARTS.contains(part)) {
+        Some(part) => Err(format!("{}: '{}' is not a color or style, try one of {}", key, part, STYLE_PARTS.join(", "))),
+        None => Ok(Style::from_dotted_str(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Mutex;
+
+    /// Arguments read the environment, tests that set it must not run alongside the others.
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    /// Loads what the flags and the config file with the given content say.
+    fn load(name: &str, file: &str, flags: &[&str]) -> Result<Config, String> {
+        let path = env::temp_dir().join(format!("rusty_chat_client_{}_{}.toml", std::process::id(), name));
+        fs::write(&path, file).unwrap();
+        let config = path.to_str().unwrap();
+        let args = Args::try_parse_from(["simple_client", "--config", config].iter().chain(flags)).unwrap();
+        let loaded = Config::load(args);
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn defaults_fill_in_whatever_is_left_open() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let config = load("defaults", "", &[]).unwrap();
+
+        assert_eq!(config.server, DEFAULT_SERVER);
+        assert_eq!((config.name, config.mode, config.p2p_port), (None, None, DEFAULT_P2P_PORT));
+        assert_eq!(config.history_file, None);
+        assert_eq!(config.identity_file, default_file(DEFAULT_IDENTITY_FILE));
+        assert_eq!(config.known_peers_file, default_file(DEFAULT_KNOWN_PEERS_FILE));
+        if let Some(dir) = dirs::config_dir() {
+            assert_eq!(config.identity_file, dir.join("rusty_chat").join("identity.key"));
+        }
+        assert!(config.tls_pinned_cert.is_none() && !config.tls);
+    }
+
+    #[test]
+    fn flags_win_over_the_environment_which_wins_over_the_file() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let file = "server = \"file.example.org:3333\"\nname = \"alice\"\nmode = \"rooms\"\np2p_port = 4000\n\
+                    identity_file = \"file.key\"\nknown_peers_file = \"file_peers.txt\"\n";
+        env::set_var("RUSTY_CHAT_P2P_PORT", "5000");
+        env::set_var("RUSTY_CHAT_IDENTITY_FILE", "env.key");
+        let config = load("precedence", file, &["--identity-file", "flag.key", "--mode", "wait"]);
+        env::remove_var("RUSTY_CHAT_P2P_PORT");
+        env::remove_var("RUSTY_CHAT_IDENTITY_FILE");
+        let config = config.unwrap();
+
+        assert_eq!(config.server, "file.example.org:3333");
+        assert_eq!(config.name.as_deref(), Some("alice"));
+        assert_eq!(config.mode, Some(ChatMode::WAIT));
+        assert_eq!(config.p2p_port, 5000);
+        assert_eq!(config.identity_file, PathBuf::from("flag.key"));
+        assert_eq!(config.known_peers_file, PathBuf::from("file_peers.txt"));
+    }
+
+    #[test]
+    fn mistakes_stop_the_client_before_it_connects() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let unknown = load("unknown", "servr = \"localhost:3333\"\n", &[]).err().unwrap();
+        assert!(unknown.contains("unknown field `servr`"), "{}", unknown);
+
+        for server in ["localhost", ":3333", "localhost:port", "localhost:70000"] {
+            assert!(load("server", "", &["--server", server]).is_err(), "{}", server);
+        }
+        assert!(load("ipv6", "", &["--server", "[::1]:3333"]).is_ok());
+        assert!(load("mode", "mode = \"group\"\n", &[]).is_err());
+        assert!(load("port", "p2p_port = 0\n", &[]).is_err());
+        assert!(load("name", "", &["--name", "no one"]).is_err());
+        assert!(load("color", "chat_color = \"purple\"\n", &[]).is_err());
+
+        let missing = env::temp_dir().join(format!("rusty_chat_client_{}_missing.toml", std::process::id()));
+        assert!(Config::load(Args::try_parse_from(["simple_client", "--config", missing.to_str().unwrap()]).unwrap()).is_err());
+    }
+}
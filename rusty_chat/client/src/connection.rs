@@ -0,0 +1,207 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::net::{TcpStream, Shutdown};
+use crossbeam_channel::{self as channel, select, Sender, Receiver};
+use common::{Error, Result};
+use common::codec::{self, FrameReader};
+use common::protocol::{ClientEnvelope, ClientMessage, ServerEnvelope, ServerMessage, RequestId};
+use common::handshake::{Hello, HandshakeReply};
+use common::heartbeat::{HeartbeatConfig, Pinger};
+use common::identity::{self, Identity, ProofRole, PublicKey};
+use rustls::ClientConfig;
+use crate::tls::{self, TlsWriter};
+
+/// What the writing half of the connection goes through.
+enum Outbound {
+    Plain(TcpStream),
+    Tls(TlsWriter)
+}
+
+impl Outbound {
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Outbound::Plain(stream) => stream.shutdown(Shutdown::Both),
+            Outbound::Tls(writer) => {
+                // the server may be gone already, the socket has to be shut down either way
+                let _ = writer.close();
+                writer.socket().shutdown(Shutdown::Both)
+            }
+        }
+    }
+}
+
+impl Write for Outbound {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Outbound::Plain(stream) => stream.write(buf),
+            Outbound::Tls(writer) => writer.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Outbound::Plain(stream) => stream.flush(),
+            Outbound::Tls(writer) => writer.flush()
+        }
+    }
+}
+
+type Inbound = Box<dyn Read + Send>;
+
+/// See DiscoveryConnection::receive_or.
+pub enum Received<T> {
+    Server(ServerEnvelope),
+    Other(T)
+}
+
+/// Writing half of the connection, shared with the threads keeping it alive.
+struct Writer {
+    stream: Outbound,
+    next_request_id: RequestId
+}
+
+impl Writer {
+    fn send(&mut self, message: ClientMessage) -> Result<RequestId> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        codec::write_frame(&mut self.stream, &ClientEnvelope{request_id, message})?;
+        Ok(request_id)
+    }
+}
+
+/// Our connection to the discovery server.
+/// Numbers outgoing requests and keeps track of the ones still waiting for a response.
+/// A background thread reads everything the server sends and answers its pings, so the
+/// connection stays alive while the user takes their time typing.
+pub struct DiscoveryConnection {
+    writer: Arc<Mutex<Writer>>,
+    incoming: Receiver<Result<ServerEnvelope>>,
+    reader_thread: thread::JoinHandle<()>,
+    pinger: Pinger,
+    pending: Vec<RequestId>
+}
+
+impl DiscoveryConnection {
+    /// Runs the TLS handshake if there is a config, sends our Hello and waits for the server's
+    /// verdict, then both sides prove their identities and the heartbeat starts. Returns the
+    /// server's Hello carrying the negotiated capabilities and the key the server proved to hold
+    /// along with the connection. `address` is what the stream is connected to.
+    pub fn open(stream: TcpStream, address: &str, tls: Option<Arc<ClientConfig>>, hello: &Hello, heartbeat: HeartbeatConfig,
+                identity: &Identity) -> Result<(DiscoveryConnection, Hello, PublicKey)> {
+        stream.set_read_timeout(Some(heartbeat.timeout))?;
+        let (inbound, mut stream): (Inbound, Outbound) = match tls {
+            Some(config) => {
+                let (reader, writer) = tls::connect(stream, address, config)?;
+                (Box::new(reader), Outbound::Tls(writer))
+            },
+            None => (Box::new(stream.try_clone()?), Outbound::Plain(stream))
+        };
+        let mut reader = FrameReader::new(inbound);
+        codec::write_frame(&mut stream, hello)?;
+        let server_hello = match reader.read_frame()? {
+            HandshakeReply::Accepted(server_hello) => server_hello,
+            HandshakeReply::Rejected(reason) => return Err(Error::Protocol(format!("the discovery server rejected us: {}", reason)))
+        };
+        let server_key = identity::exchange_proofs(&mut stream, &mut reader, identity, ProofRole::Client)?;
+
+        let writer = Arc::new(Mutex::new(Writer{stream, next_request_id: 0}));
+        let (sender, incoming) = channel::unbounded();
+        let reader_thread = thread::spawn({
+            let writer = Arc::clone(&writer);
+            move || read_envelopes(reader, sender, writer)
+        });
+        let pinger = Pinger::spawn(heartbeat.interval, {
+            let writer = Arc::clone(&writer);
+            move || writer.lock().unwrap().send(ClientMessage::Ping).map(|_| ())
+        });
+
+        let connection = DiscoveryConnection{writer, incoming, reader_thread, pinger, pending: Vec::new()};
+        Ok((connection, server_hello, server_key))
+    }
+
+    /// Only requests the server responds to wait for a response.
+    pub fn send(&mut self, message: ClientMessage) -> Result<RequestId> {
+        let expects_response = message.expects_response();
+        let request_id = self.writer.lock().unwrap().send(message)?;
+        if expects_response {
+            self.pending.push(request_id);
+        }
+        Ok(request_id)
+    }
+
+    /// Blocks until the server sends something.
+    /// Responses to requests we never sent (or that were already answered) are skipped.
+    pub fn receive(&mut self) -> Result<ServerEnvelope> {
+        loop {
+            let envelope = self.incoming.recv().map_err(|_| Error::PeerGone)??;
+            if self.is_expected(&envelope) {
+                return Ok(envelope);
+            }
+        }
+    }
+
+    /// Like receive, but whatever arrives on `other` first is returned as well.
+    /// Once `other` is disconnected this waits for the server alone.
+    pub fn receive_or<T>(&mut self, other: &Receiver<T>) -> Result<Received<T>> {
+        loop {
+            select! {
+                recv(self.incoming) -> envelope => {
+                    let envelope = envelope.map_err(|_| Error::PeerGone)??;
+                    if self.is_expected(&envelope) {
+                        return Ok(Received::Server(envelope));
+                    }
+                },
+                recv(other) -> item => match item {
+                    Ok(item) => return Ok(Received::Other(item)),
+                    Err(_) => return self.receive().map(Received::Server)
+                }
+            }
+        }
+    }
+
+    /// Pushed envelopes and responses to requests still waiting for one.
+    fn is_expected(&mut self, envelope: &ServerEnvelope) -> bool {
+        match envelope.request_id {
+            Some(id) => match self.pending.iter().position(|pending| *pending == id) {
+                Some(index) => {
+                    self.pending.remove(index);
+                    true
+                },
+                None => false
+            },
+            None => true
+        }
+    }
+
+    pub fn close(self) -> Result<()> {
+        drop(self.pinger);
+        let result = self.writer.lock().unwrap().stream.shutdown();
+        // the reader notices the shutdown and stops
+        let _ = self.reader_thread.join();
+        result?;
+        Ok(())
+    }
+}
+
+/// Hands everything but heartbeats over to `sender`. The first error is handed over as well
+/// and ends the thread, a silent server shows up as Error::Timeout.
+fn read_envelopes(mut reader: FrameReader<Inbound>, sender: Sender<Result<ServerEnvelope>>, writer: Arc<Mutex<Writer>>) {
+    loop {
+        let result = match reader.read_frame::<ServerEnvelope>() {
+            Ok(ServerEnvelope{message: ServerMessage::Ping, ..}) => writer.lock().unwrap().send(ClientMessage::Pong).map(|_| ()),
+            Ok(ServerEnvelope{message: ServerMessage::Pong, ..}) => Ok(()),
+            Ok(envelope) => {
+                if sender.send(Ok(envelope)).is_err() {
+                    break;
+                }
+                Ok(())
+            },
+            Err(e) => Err(e)
+        };
+        if let Err(e) = result {
+            let _ = sender.send(Err(e));
+            break;
+        }
+    }
+}
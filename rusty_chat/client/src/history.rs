@@ -0,0 +1,27 @@
+//! Chat messages kept on disk, one line each, appended as they come in.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+pub struct History {
+    file: File
+}
+
+impl History {
+    /// Creates the file and the directories it is in if needed.
+    pub fn open(path: &Path) -> io::Result<History> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(History{file: OpenOptions::new().create(true).append(true).open(path)?})
+    }
+
+    pub fn record(&mut self, writer: &str, message: &str) -> io::Result<()> {
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let timestamp = now.format(&Rfc3339).map_err(io::Error::other)?;
+        writeln!(self.file, "{} {}: {}", timestamp, writer, message)
+    }
+}
@@ -0,0 +1,201 @@
+//! Trust on first use. The first key we see for a chat partner or discovery server is
+//! remembered, every later connection is checked against it.
+//!
+//! The file has one `<label> <key>` line per peer, labels look like `peer:<name>` or
+//! `server:<address>`. Chat partners whose safety number the user compared get ` verified`
+//! at the end of their line. Removing a line or verifying the new key is how the user accepts
+//! a new key.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use common::{Error, Result};
+use common::identity::PublicKey;
+
+/// Marks the lines of verified peers.
+const VERIFIED_SUFFIX: &str = " verified";
+/// Keeps safety numbers from matching any other hash of the same keys.
+const SAFETY_NUMBER_CONTEXT: &[u8] = b"rusty chat safety number v1\0";
+/// Groups of five digits in a safety number.
+const SAFETY_NUMBER_GROUPS: usize = 6;
+
+pub enum Trust {
+    /// Never seen before, remembered from now on
+    New,
+    /// Verified if the user compared safety numbers for this key
+    Known{verified: bool},
+    /// Somebody else may be pretending to be them, the remembered key stays
+    Changed{remembered: PublicKey}
+}
+
+struct Entry {
+    key: PublicKey,
+    verified: bool
+}
+
+pub struct KnownPeers {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>
+}
+
+impl KnownPeers {
+    /// A missing file is fine, it gets created along with the first key.
+    pub fn load(path: PathBuf) -> Result<KnownPeers> {
+        let mut entries = BTreeMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (index, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                    let (line, verified) = match line.trim_end().strip_suffix(VERIFIED_SUFFIX) {
+                        Some(line) => (line, true),
+                        None => (line, false)
+                    };
+                    let entry = line.split_once(' ').and_then(|(label, key)| Some((label.to_string(), PublicKey::from_hex(key)?)));
+                    match entry {
+                        Some((label, key)) => entries.insert(label, Entry{key, verified}),
+                        None => return Err(Error::Protocol(format!("line {} of {} is not '<label> <key>'", index + 1, path.display())))
+                    };
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into())
+        }
+        Ok(KnownPeers{path, entries})
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Chat partners are known by name, regardless of case like on the server.
+    pub fn check_peer(&mut self, name: &str, key: PublicKey) -> Result<Trust> {
+        self.check(peer_label(name), key)
+    }
+
+    pub fn check_server(&mut self, address: &str, key: PublicKey) -> Result<Trust> {
+        self.check(format!("server:{}", address), key)
+    }
+
+    /// The user compared safety numbers with the partner. Replaces whatever key we remembered
+    /// for the name, comparing them is the best proof that a new key is alright.
+    pub fn mark_verified(&mut self, name: &str, key: PublicKey) -> Result<()> {
+        self.entries.insert(peer_label(name), Entry{key, verified: true});
+        self.save()
+    }
+
+    fn check(&mut self, label: String, key: PublicKey) -> Result<Trust> {
+        match self.entries.get(&label) {
+            Some(entry) if entry.key == key => Ok(Trust::Known{verified: entry.verified}),
+            Some(entry) => Ok(Trust::Changed{remembered: entry.key}),
+            None => {
+                self.entries.insert(label, Entry{key, verified: false});
+                self.save()?;
+                Ok(Trust::New)
+            }
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let content: String = self.entries.iter()
+            .map(|(label, entry)| format!("{} {}{}\n", label, entry.key, if entry.verified { VERIFIED_SUFFIX } else { "" }))
+            .collect();
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+fn peer_label(name: &str) -> String {
+    format!("peer:{}", name.to_lowercase())
+}
+
+/// Digits both chat partners can read out to each other. They only match if both sides see the
+/// same two keys, it doesn't matter whose key is whose.
+pub fn safety_number(own_key: &PublicKey, partner_key: &PublicKey) -> String {
+    let (first, second) = if own_key.as_bytes() <= partner_key.as_bytes() { (own_key, partner_key) } else { (partner_key, own_key) };
+    let digest = Sha256::new()
+        .chain_update(SAFETY_NUMBER_CONTEXT)
+        .chain_update(first.as_bytes())
+        .chain_update(second.as_bytes())
+        .finalize();
+    let groups: Vec<String> = digest.chunks(5).take(SAFETY_NUMBER_GROUPS).map(|chunk| {
+        let value = chunk.iter().fold(0u64, |value, byte| value << 8 | u64::from(*byte));
+        format!("{:05}", value % 100_000)
+    }).collect();
+    groups.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use common::identity::Identity;
+
+    /// A file of its own for every test, the caller removes it.
+    fn peers_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rusty_chat_known_peers_{}_{}.txt", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn safety_numbers_match_whoever_computes_them() {
+        let (alice, bob, mallory) = (Identity::generate().public_key(), Identity::generate().public_key(), Identity::generate().public_key());
+
+        let number = safety_number(&alice, &bob);
+        assert_eq!(number, safety_number(&bob, &alice));
+        assert_ne!(number, safety_number(&alice, &mallory));
+        let groups: Vec<&str> = number.split(' ').collect();
+        assert_eq!(groups.len(), SAFETY_NUMBER_GROUPS);
+        assert!(groups.iter().all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+    }
+
+    #[test]
+    fn changed_keys_are_never_trusted_silently() {
+        let path = peers_file("changed");
+        let (bob, mallory) = (Identity::generate().public_key(), Identity::generate().public_key());
+        let mut peers = KnownPeers::load(path.clone()).unwrap();
+        assert!(matches!(peers.check_peer("Bob", bob).unwrap(), Trust::New));
+        assert!(matches!(peers.check_peer("bob", bob).unwrap(), Trust::Known{verified: false}));
+        assert!(matches!(peers.check_server("10.0.0.1:3333", mallory).unwrap(), Trust::New));
+
+        for _ in 0..2 {
+            assert!(matches!(peers.check_peer("BOB", mallory).unwrap(), Trust::Changed{remembered} if remembered == bob));
+        }
+        assert!(matches!(peers.check_server("10.0.0.1:3333", bob).unwrap(), Trust::Changed{remembered} if remembered == mallory));
+        let mut reloaded = KnownPeers::load(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(reloaded.check_peer("bob", mallory).unwrap(), Trust::Changed{remembered} if remembered == bob));
+        assert!(matches!(reloaded.check_peer("bob", bob).unwrap(), Trust::Known{verified: false}));
+    }
+
+    #[test]
+    fn verified_keys_are_saved_and_reloaded() {
+        let path = peers_file("verified");
+        let (bob, new_bob) = (Identity::generate().public_key(), Identity::generate().public_key());
+        let mut peers = KnownPeers::load(path.clone()).unwrap();
+        peers.check_peer("bob", bob).unwrap();
+
+        // comparing safety numbers is how a new key gets accepted
+        peers.mark_verified("Bob", new_bob).unwrap();
+        assert!(matches!(peers.check_peer("bob", new_bob).unwrap(), Trust::Known{verified: true}));
+        let content = fs::read_to_string(&path).unwrap();
+        let mut reloaded = KnownPeers::load(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, format!("peer:bob {} verified\n", new_bob));
+        assert!(matches!(reloaded.check_peer("bob", new_bob).unwrap(), Trust::Known{verified: true}));
+        assert!(matches!(reloaded.check_peer("bob", bob).unwrap(), Trust::Changed{remembered} if remembered == new_bob));
+    }
+
+    #[test]
+    fn broken_lines_are_refused() {
+        let path = peers_file("broken");
+        fs::write(&path, "peer:bob not-a-key\n").unwrap();
+        let loaded = KnownPeers::load(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+}
@@ -1,21 +1,110 @@
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, Shutdown};
+use std::io;
+use std::process;
 use std::thread;
+use std::sync::{Arc, Mutex};
 use std::time;
-use std::error::Error;
-use common::{LoginRequest, ChatRoom, ChatMode, User, MasterSelectionResult, Message};
+use common::{LoginRequest, ChatMode, MasterSelectionResult, Message, Error, Result};
+use common::codec::{self, FrameReader};
+use common::protocol::{ClientMessage, ServerMessage, Rejection, PeerMessage, UserSummary, Moderation, Access, AccessMode, HistoryMessage, NewRoom, RoomRole, RoomEvent, RoomSummary, Visibility};
+use common::multipart::{self, MessageId, Reassembler};
+use common::validation::{self, ValidationError};
+use common::handshake::{Hello, HandshakeReply, Capabilities};
+use common::heartbeat::{HeartbeatConfig, Pinger};
+use common::identity::{self, Identity, ProofRole, PublicKey};
+use clap::Parser;
 use crossbeam_channel::{Sender, Receiver};
+use config::{Args, Config};
+use connection::{DiscoveryConnection, Received};
+use history::History;
+use known_peers::{KnownPeers, Trust};
+use secure::{Role, SecureReader, SecureWriter};
 
-extern crate bincode;
 extern crate crossbeam_channel;
 extern crate console;
 
+mod config;
+mod connection;
+mod history;
+mod known_peers;
+mod ratchet;
+mod secure;
+mod tls;
 mod ui;
 
+/// Optional features this client supports, both towards the discovery server and other clients.
+const CAPABILITIES: Capabilities = Capabilities::ROOMS;
+/// The connector gives the master this long to start listening.
+const CONNECT_DELAY: time::Duration = time::Duration::from_secs(5);
+/// How often the master looks for the partner while waiting for it to connect.
+const ACCEPT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+#[allow(clippy::enum_variant_names)]
 enum InternMessage {
     SystemMessage(String),
     ErrorMessage(String),
-    ChatMessage(MessageInfo)
+    ChatMessage(MessageInfo),
+    /// Said in a room before we joined, that many seconds ago
+    HistoryMessage(MessageInfo, u64),
+    /// Something the title shows about the chat partner changed
+    PartnerChanged(Arc<Mutex<ChatPartner>>)
+}
+
+/// Who we chat with on the direct link, shared by the threads of the chat.
+struct ChatPartner {
+    summary: UserSummary,
+    /// The server vouched for this name, their key is remembered for it even if they pick another one
+    paired_name: String,
+    key: PublicKey,
+    /// The user compared safety numbers for this key
+    safety_number_verified: bool
+}
+
+/// Who we log in as. Registering only happens once, reconnecting logs in to the new account.
+struct Credentials {
+    request: LoginRequest,
+    register: bool
+}
+
+impl Credentials {
+    fn login_message(&self) -> ClientMessage {
+        if self.register {
+            ClientMessage::Register(self.request.clone())
+        } else {
+            ClientMessage::Login(self.request.clone())
+        }
+    }
+}
+
+/// Everything that outlives a single connection.
+struct Session {
+    /// Address of the discovery server
+    server: String,
+    /// Picked right after logging in if set
+    mode: Option<ChatMode>,
+    /// Where we accept direct chats when we are the master
+    p2p_port: u16,
+    credentials: Credentials,
+    identity: Identity,
+    known_peers: KnownPeers,
+    heartbeat: HeartbeatConfig,
+    /// Plain TCP to the discovery server if not set
+    tls: Option<Arc<rustls::ClientConfig>>
+}
+
+impl Session {
+    fn load(credentials: Credentials, config: &Config) -> Result<Session> {
+        Ok(Session{
+            server: config.server.clone(),
+            mode: config.mode,
+            p2p_port: config.p2p_port,
+            credentials,
+            identity: Identity::load_or_generate(&config.identity_file)?,
+            known_peers: KnownPeers::load(config.known_peers_file.clone())?,
+            heartbeat: HeartbeatConfig::from_env(),
+            tls: tls::client_config(config.tls_pinned_cert.as_deref(), config.tls)?
+        })
+    }
 }
 
 struct MessageInfo {
@@ -44,206 +133,671 @@ macro_rules! chat_message {
 }
 
 fn main() {
-    let print_term = ui::create_ui();
+    let mut config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            process::exit(2);
+        }
+    };
+    let history = match config.history_file.as_deref().map(History::open).transpose() {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("cannot open the history file: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut print_term = ui::create_ui();
+    print_term.set_colors(std::mem::take(&mut config.colors));
     print_term.update_title("Rusty Chat");
     print_term.clear_screen_and_reset_cursor();
 
     let (snd, rcv): (Sender<InternMessage>, Receiver<InternMessage>) = crossbeam_channel::unbounded();
-    thread::spawn(move || {
-        print_messages_to_ui(rcv, print_term);
+    let print_thread = thread::spawn(move || {
+        print_messages_to_ui(rcv, print_term, history);
     });
 
     let term = ui::create_ui();
     term.move_to_input_pos();
-    let user = get_user(&term, &snd);
-    match TcpStream::connect("localhost:3333") {
-        Ok(mut stream) => {
-            sys_message!("connected to port 3333" => snd);
+    match Session::load(get_credentials(&term, &snd, config.name.clone(), config.p2p_port), &config) {
+        Ok(mut session) => {
+            sys_message!(&format!("your key: {}", session.identity.public_key()) => snd);
+            while let Err(e) = run(&mut session, &term, &snd) {
+                report_error(&e, &snd);
+                if !ask_to_reconnect(&term, &snd) {
+                    break;
+                }
+            }
+        },
+        Err(e) => report_error(&e, &snd)
+    }
 
-            send_request(user, &mut stream, &snd);
+    // stops the print loop once everything before it is on the screen, unless /exit already did
+    let _ = snd.send(InternMessage::ChatMessage(MessageInfo{message_writer: String::new(), message: String::from("/exit")}));
+    print_thread.join().unwrap();
+}
 
-            let mode = get_and_send_chat_mode(&mut stream, &term, &snd);
+/// Everything that can go wrong while chatting ends up here.
+fn report_error(error: &Error, snd: &Sender<InternMessage>) {
+    match error {
+        Error::PeerGone => err_message!("the other side closed the connection" => snd),
+        Error::Timeout => err_message!("the other side did not answer in time" => snd),
+        e => err_message!(&e.to_string() => snd)
+    }
+}
 
-            if mode == ChatMode::DIRECT {
-                direct_mode(&mut stream, term, snd);
-            } else if mode == ChatMode::WAIT {
-                wait_mode(&mut stream, term, snd);
-            } else {
+fn ask_to_reconnect(term: &ui::UI, snd: &Sender<InternMessage>) -> bool {
+    sys_message!("press enter to reconnect to the discovery server or type /quit to exit" => snd);
+    term.read_line() != "/quit"
+}
 
-            }
+/// Logs in at the discovery server and chats with whoever we get paired with.
+/// The session's credentials keep track of renames, so reconnecting uses the latest name.
+fn run(session: &mut Session, term: &ui::UI, snd: &Sender<InternMessage>) -> Result<()> {
+    let stream = TcpStream::connect(&session.server)?;
+    sys_message!(&format!("connected to {}", session.server) => snd);
+    let (mut connection, hello, server_key) = DiscoveryConnection::open(stream, &session.server, session.tls.clone(), &Hello::new(CAPABILITIES),
+                                                                         session.heartbeat, &session.identity)?;
+    if session.tls.is_some() {
+        sys_message!("the connection to the discovery server is encrypted with TLS" => snd);
+    } else {
+        sys_message!("the connection to the discovery server is not encrypted, everyone in between can see your name and address" => snd);
+    }
+    sys_message!(&format!("server speaks protocol version {}, capabilities: {}", hello.protocol_version, hello.capabilities) => snd);
+    check_server_key(&mut session.known_peers, &session.server, server_key, snd)?;
+
+    connection.send(session.credentials.login_message())?;
+
+    let selection = discovery_loop(&mut connection, &mut session.credentials, session.mode, term, snd)?;
+    close_discovery_connection(connection, snd);
+    match selection {
+        Some(selection) => start_direct_chat(selection, session, term, snd),
+        None => Ok(())
+    }
+}
+
+/// A discovery server with a different key could pair us with anyone, so we don't talk to it.
+fn check_server_key(known_peers: &mut KnownPeers, server: &str, server_key: PublicKey, snd: &Sender<InternMessage>) -> Result<()> {
+    match known_peers.check_server(server, server_key)? {
+        Trust::Known{..} => Ok(()),
+        Trust::New => {
+            sys_message!(&format!("first connection to {}, remembering its key: {}", server, server_key) => snd);
+            Ok(())
         },
-        Err(e) => {
-            err_message!(&format!("failed to connect: {}", e) => snd)
+        Trust::Changed{remembered} => {
+            err_message!(&format!("WARNING: the key of {} changed! remembered: {}", server, remembered) => snd);
+            err_message!(&format!("WARNING: it now claims to be: {}", server_key) => snd);
+            Err(Error::Protocol(format!("refusing to use {}, remove its line from {} if the new key is expected",
+                                        server, known_peers.path().display())))
         }
     }
 }
 
-fn wait_mode(stream: &mut TcpStream, term: ui::UI, snd: Sender<InternMessage>) {
-    let master_selection = receive_master_selection(stream, &snd).expect("no selection submitted");
-    close_connection(stream, &snd);
-    drop(stream);
+/// Dispatches everything the discovery server sends us until we know who we are going to chat with.
+/// `preferred_mode` is picked without asking once we are logged in, later on the user decides.
+/// Returns None if the user quit in a chat room.
+fn discovery_loop(connection: &mut DiscoveryConnection, credentials: &mut Credentials, mut preferred_mode: Option<ChatMode>,
+                  term: &ui::UI, snd: &Sender<InternMessage>) -> Result<Option<MasterSelectionResult>> {
+    let mut logged_in = false;
+    loop {
+        let envelope = connection.receive()?;
+        match envelope.message {
+            ServerMessage::LoggedIn{name, verified} => {
+                if verified {
+                    sys_message!(&format!("logged in as {}", name) => snd);
+                } else {
+                    sys_message!(&format!("logged in as {}, a guest: others see your name as unverified", name) => snd);
+                }
+                credentials.request.name = name;
+                credentials.register = false;
+                logged_in = true;
+                match preferred_mode.take() {
+                    Some(mode) => {
+                        connection.send(ClientMessage::SelectChatMode(mode))?;
+                    },
+                    None => select_chat_mode(connection, term, snd)?
+                }
+            },
+            // the response to our own rename, everyone else's are pushed
+            ServerMessage::Renamed{new_name, ..} if envelope.request_id.is_some() => {
+                sys_message!(&format!("you are now known as {}", new_name) => snd);
+                credentials.request.name = new_name;
+            },
+            ServerMessage::Renamed{old_name, new_name} => sys_message!(&format!("{} is now known as {}", old_name, new_name) => snd),
+            ServerMessage::UserList{users, unlisted} => {
+                print_string_vec(&users.iter().map(|user| user.to_string()).collect(), snd);
+                if unlisted > 0 {
+                    sys_message!(&format!("and {} more, any of them can be picked by name", unlisted) => snd);
+                }
+                let chat_partner = select_chat_partner(term, snd);
+                connection.send(ClientMessage::ChatRequest(chat_partner))?;
+            },
+            ServerMessage::RoomList{rooms, unlisted} => {
+                print_string_vec(&rooms.iter().map(|room| room.to_string()).collect(), snd);
+                if unlisted > 0 {
+                    sys_message!(&format!("and {} more, any of them can be joined by name", unlisted) => snd);
+                }
+                let request = select_room(&rooms, term, snd);
+                connection.send(request)?;
+            },
+            ServerMessage::JoinedRoom{room, topic, members, unlisted_members, history} => {
+                if !room_chat(connection, credentials, room, topic, members, unlisted_members, history, term, snd)? {
+                    return Ok(None);
+                }
+                term.update_title("Rusty Chat");
+                select_chat_mode(connection, term, snd)?;
+            },
+            ServerMessage::Invited{room, by, expires_in_secs} => sys_message!(&invitation(&room, &by, expires_in_secs) => snd),
+            // whatever was on its way while we left a room
+            ServerMessage::RoomMessage{..} | ServerMessage::RoomEvent(_) | ServerMessage::LeftRoom(_)
+            | ServerMessage::RoomDeleted(_) | ServerMessage::RoomRenamed{..} | ServerMessage::TopicSet{..} | ServerMessage::Moderated(_)
+            | ServerMessage::InviteSent{..} => (),
+            ServerMessage::MasterSelection(selection) => return Ok(Some(selection)),
+            // other credentials won't get us in any sooner
+            ServerMessage::Rejected(Rejection::ServerFull) if !logged_in => return Err(Error::Protocol(Rejection::ServerFull.to_string())),
+            // until we are logged in, everything refused is our login
+            ServerMessage::Rejected(rejection) if !logged_in => {
+                err_message!(&format!("the server did not accept your login: {}", rejection) => snd);
+                *credentials = get_credentials(term, snd, None, credentials.request.p2p_port);
+                connection.send(credentials.login_message())?;
+            },
+            ServerMessage::Error(text) if !logged_in => {
+                err_message!(&text => snd);
+                *credentials = get_credentials(term, snd, None, credentials.request.p2p_port);
+                connection.send(credentials.login_message())?;
+            },
+            // a refused rename leaves us with the name we had, whatever we were doing goes on
+            ServerMessage::Rejected(rejection @ Rejection::InvalidName(_))
+            | ServerMessage::Rejected(rejection @ Rejection::NameTaken{..})
+            | ServerMessage::Rejected(rejection @ Rejection::NameRegistered(_))
+            | ServerMessage::Rejected(rejection @ Rejection::NotAGuest) =>
+                err_message!(&format!("the server did not accept your new name: {}", rejection) => snd),
+            ServerMessage::Rejected(rejection) => {
+                err_message!(&format!("the server refused: {}", rejection) => snd);
+                select_chat_mode(connection, term, snd)?;
+            },
+            ServerMessage::Error(text) => {
+                err_message!(&text => snd);
+                select_chat_mode(connection, term, snd)?;
+            },
+            // the connection deals with heartbeats on its own
+            ServerMessage::Ping | ServerMessage::Pong => ()
+        }
+    }
+}
 
-    if master_selection.is_own_ip {
-        start_master_server_direct(snd, master_selection.chat_partner_name, term);
-    } else {
-        sys_message!("waiting 5 seconds before connecting to elected master server" => snd);
-        thread::sleep(time::Duration::from_millis(5000));
-        connect_to_master(master_selection.target_ip, master_selection.chat_partner_name, term, snd);
+/// Chats in the room we just joined until the user types /leave or /exit, false for the latter.
+/// Lines are read on a thread of their own, so whatever the server pushes shows up while the user is typing.
+#[allow(clippy::too_many_arguments)]
+fn room_chat(connection: &mut DiscoveryConnection, credentials: &mut Credentials, room: String, topic: Option<String>, members: Vec<UserSummary>,
+             unlisted_members: usize, history: Vec<HistoryMessage>, term: &ui::UI, snd: &Sender<InternMessage>) -> Result<bool> {
+    sys_message!(&format!("you joined {}, type /leave to pick another chat mode or /exit to quit", room) => snd);
+    sys_message!("anyone in here can /invite <name>, the invite gets them in whatever it takes to join otherwise" => snd);
+    sys_message!("operators keep order with /kick, /ban, /banip, /unban, /mute and /unmute <name> and set the /topic [text]" => snd);
+    sys_message!("the owner also hands out roles with /op, /deop and /owner <name>, and can /rename <name> or /delete the room" => snd);
+    sys_message!("messages in rooms go through the discovery server, they are not end-to-end encrypted" => snd);
+    if let Some(topic) = topic {
+        sys_message!(&format!("topic: {}", topic) => snd);
     }
+    let mut listed: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+    if unlisted_members > 0 {
+        listed.push(format!("and {} more", unlisted_members));
+    }
+    sys_message!(&format!("in here: {}", listed.join(", ")) => snd);
+    if !history.is_empty() {
+        sys_message!("said in here before you joined:" => snd);
+        for HistoryMessage{sender, message, age_secs} in history {
+            snd.send(InternMessage::HistoryMessage(MessageInfo{message_writer: sender, message: message.message}, age_secs)).unwrap();
+        }
+        sys_message!("end of history" => snd);
+    }
+    term.update_title(&format!("{} (chat room)", room));
+
+    let (lines, input) = crossbeam_channel::unbounded();
+    thread::scope(|scope| {
+        // dropped before the scope waits for the reader, so its next line ends it
+        let input = input;
+        let reader = scope.spawn(move || loop {
+            let line = term.read_line();
+            let last = line == "/leave" || line == "/exit";
+            if lines.send(line).is_err() || last {
+                break;
+            }
+        });
+        let result = room_loop(connection, credentials, room, &input, term, snd);
+        drop(input);
+        if !reader.is_finished() {
+            sys_message!("press enter to go on" => snd);
+        }
+        result
+    })
 }
 
-fn direct_mode(stream: &mut TcpStream, term: ui::UI, snd: Sender<InternMessage>) {
-    let mut buffer = vec!(0; 20 * User::NAME_SIZE);
-    // TODO: whats up with that empty name?
-    let chat_partner = match receive_string_vec(stream, &mut buffer, &snd){
-        Some(names) => {
-            print_string_vec(&names, &snd);
-            select_chat_partner(&term, &snd)
-        },
-        None => {
-            String::from("")
+fn room_loop(connection: &mut DiscoveryConnection, credentials: &mut Credentials, mut room: String, input: &Receiver<String>,
+             term: &ui::UI, snd: &Sender<InternMessage>) -> Result<bool> {
+    loop {
+        let envelope = match connection.receive_or(input)? {
+            Received::Server(envelope) => envelope,
+            Received::Other(line) => {
+                if line == "/exit" {
+                    return Ok(false);
+                }
+                if line == "/leave" {
+                    connection.send(ClientMessage::LeaveRoom)?;
+                } else if line == "/delete" {
+                    connection.send(ClientMessage::DeleteRoom(room.clone()))?;
+                } else if line == "/topic" {
+                    connection.send(ClientMessage::SetTopic{room: room.clone(), topic: None})?;
+                } else if let Some(topic) = line.strip_prefix("/topic ") {
+                    match validation::validate_topic(topic) {
+                        Ok(()) => {
+                            connection.send(ClientMessage::SetTopic{room: room.clone(), topic: Some(topic.to_string())})?;
+                        },
+                        Err(e) => err_message!(&format!("topic {}", e) => snd)
+                    }
+                } else if let Some(new_name) = line.strip_prefix("/rename ") {
+                    match validation::validate_room_name(new_name) {
+                        Ok(()) => {
+                            connection.send(ClientMessage::RenameRoom{name: room.clone(), new_name: new_name.to_string()})?;
+                        },
+                        Err(e) => err_message!(&format!("room name {}", e) => snd)
+                    }
+                } else if let Some(name) = line.strip_prefix("/invite ") {
+                    match validation::validate_user_name(name) {
+                        Ok(()) => {
+                            connection.send(ClientMessage::Invite{room: room.clone(), name: name.to_string()})?;
+                        },
+                        Err(e) => err_message!(&format!("name {}", e) => snd)
+                    }
+                } else if let Some(action) = parse_moderation(&line) {
+                    match validation::validate_user_name(action.target()) {
+                        Ok(()) => {
+                            connection.send(ClientMessage::Moderate{room: room.clone(), action})?;
+                        },
+                        Err(e) => err_message!(&format!("name {}", e) => snd)
+                    }
+                } else if let Some(new_name) = line.strip_prefix("/nick ") {
+                    match validation::validate_user_name(new_name) {
+                        Ok(()) => {
+                            connection.send(ClientMessage::Rename(new_name.to_string()))?;
+                        },
+                        Err(e) => err_message!(&format!("name {}", e) => snd)
+                    }
+                } else {
+                    let message = Message{message: line};
+                    match message.validate() {
+                        Ok(()) => {
+                            connection.send(ClientMessage::RoomMessage(message.clone()))?;
+                            chat_message!(String::from("me"), message.message => snd);
+                        },
+                        Err(ValidationError::Empty) => (),
+                        Err(e) => err_message!(&format!("not sent, message {}", e) => snd)
+                    }
+                }
+                continue;
+            }
+        };
+        match envelope.message {
+            ServerMessage::RoomMessage{sender, message} => match message.validate() {
+                Ok(()) => chat_message!(sender, message.message => snd),
+                Err(e) => err_message!(&format!("dropped a message from {}: message {}", sender, e) => snd)
+            },
+            ServerMessage::RoomEvent(event) => {
+                sys_message!(&event.to_string() => snd);
+                if let RoomEvent::Renamed{new_name, ..} = event {
+                    room = new_name;
+                    term.update_title(&format!("{} (chat room)", room));
+                }
+            },
+            ServerMessage::RoomRenamed{new_name, ..} => {
+                sys_message!(&format!("the room is now called {}", new_name) => snd);
+                room = new_name;
+                term.update_title(&format!("{} (chat room)", room));
+            },
+            ServerMessage::TopicSet{topic: Some(topic), ..} => sys_message!(&format!("the topic is now: {}", topic) => snd),
+            ServerMessage::TopicSet{topic: None, ..} => sys_message!("the topic is cleared" => snd),
+            ServerMessage::Moderated(action) => sys_message!(&RoomEvent::Moderated{by: String::from("you"), action}.to_string() => snd),
+            ServerMessage::InviteSent{name, ..} => sys_message!(&format!("you invited {}", name) => snd),
+            ServerMessage::Invited{room, by, expires_in_secs} => sys_message!(&invitation(&room, &by, expires_in_secs) => snd),
+            // everyone is out of the room, a LeftRoom follows
+            ServerMessage::RoomDeleted(room) => sys_message!(&format!("you deleted {}", room) => snd),
+            // the response to our /leave, pushed if the room was deleted
+            ServerMessage::LeftRoom(room) => {
+                if envelope.request_id.is_some() {
+                    sys_message!(&format!("you left {}", room) => snd);
+                }
+                return Ok(true);
+            },
+            ServerMessage::Renamed{new_name, ..} if envelope.request_id.is_some() => {
+                sys_message!(&format!("you are now known as {}", new_name) => snd);
+                credentials.request.name = new_name;
+            },
+            ServerMessage::Renamed{old_name, new_name} => sys_message!(&format!("{} is now known as {}", old_name, new_name) => snd),
+            ServerMessage::Rejected(rejection) => err_message!(&format!("the server refused: {}", rejection) => snd),
+            ServerMessage::Error(text) => err_message!(&text => snd),
+            ServerMessage::Ping | ServerMessage::Pong => (),
+            message => return Err(Error::Protocol(format!("expected nothing but room traffic while in a room, got {:?}", message)))
         }
-    };
-    // TODO: better to return Result? saves me handing in that sender
-    send_string(chat_partner.clone(), stream, &snd);
+    }
+}
+
+fn invitation(room: &str, by: &str, expires_in_secs: u64) -> String {
+    format!("{} invited you to {}, the invite is good for {} minutes, no password needed", by, room, expires_in_secs.div_ceil(60))
+}
 
-    // TODO: handle bad case
-    let master_selection = receive_master_selection(stream, &snd).expect("no selection submitted");
-    close_connection(stream, &snd);
-    drop(stream);
+/// Moderation commands in a room, like /kick bob.
+fn parse_moderation(line: &str) -> Option<Moderation> {
+    let (command, name) = line.split_once(' ')?;
+    let name = name.to_string();
+    let action = match command {
+        "/kick" => Moderation::Kick(name),
+        "/ban" => Moderation::Ban{name, by_ip: false},
+        "/banip" => Moderation::Ban{name, by_ip: true},
+        "/unban" => Moderation::Unban(name),
+        "/mute" => Moderation::Mute(name),
+        "/unmute" => Moderation::Unmute(name),
+        "/op" => Moderation::SetRole{name, role: RoomRole::Operator},
+        "/deop" => Moderation::SetRole{name, role: RoomRole::Member},
+        "/owner" => Moderation::SetRole{name, role: RoomRole::Owner},
+        _ => return None
+    };
+    Some(action)
+}
 
-    if master_selection.is_own_ip {
-        start_master_server_direct(snd, chat_partner, term);
+fn start_direct_chat(selection: MasterSelectionResult, session: &mut Session, term: &ui::UI, snd: &Sender<InternMessage>) -> Result<()> {
+    let chat_partner = UserSummary{name: selection.chat_partner_name, verified: selection.chat_partner_verified};
+    if selection.is_own_ip {
+        start_master_server_direct(snd, &selection.target_ip, chat_partner, selection.chat_partner_key, session, term)
     } else {
-        sys_message!("waiting 5 seconds before connecting to elected master server" => snd);
-        thread::sleep(time::Duration::from_millis(5000));
-        connect_to_master(master_selection.target_ip, chat_partner, term, snd);
+        sys_message!(&format!("waiting {} seconds before connecting to elected master server", CONNECT_DELAY.as_secs()) => snd);
+        thread::sleep(CONNECT_DELAY);
+        connect_to_master(&selection.target_ip, selection.target_port, chat_partner, selection.chat_partner_key, session, term, snd)
     }
 }
-fn connect_to_master(master_ip: String, chat_partner: String, term: ui::UI, snd: Sender<InternMessage>) {
-    let server_address = master_ip + ":3334";
-    match TcpStream::connect(server_address) {
-        Ok(mut stream) => {
-            term.update_title(&chat_partner);
 
-            create_network_listener(&snd, &chat_partner, &stream);
+fn connect_to_master(master_ip: &str, master_port: u16, chat_partner: UserSummary, partner_key: PublicKey, session: &mut Session, term: &ui::UI,
+                     snd: &Sender<InternMessage>) -> Result<()> {
+    let mut stream = TcpStream::connect((master_ip, master_port))?;
+    stream.set_read_timeout(Some(session.heartbeat.timeout))?;
+    let mut reader = FrameReader::new(stream.try_clone()?);
+    connect_handshake(&mut stream, &mut reader)?;
+    let verified = verify_partner(&mut stream, &mut reader, &chat_partner, partner_key, ProofRole::Connector, session, snd)?;
+    let (writer, reader) = secure::establish(stream, reader, &session.identity, partner_key, Role::Connector)?;
+    let chat_partner = chat_started(chat_partner, partner_key, verified, term, snd);
+
+    chat_with_peer(snd, chat_partner, writer, reader, session, term)
+}
+
+/// Listens on the address family the server saw us come from, that is what the partner is told to connect to.
+/// A partner that doesn't show up in time is given up on like one that stops answering.
+fn start_master_server_direct(sender: &Sender<InternMessage>, own_ip: &str, chat_partner: UserSummary, partner_key: PublicKey, session: &mut Session,
+                              term: &ui::UI) -> Result<()> {
+    let any: IpAddr = match own_ip.parse() {
+        Ok(IpAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        _ => Ipv4Addr::UNSPECIFIED.into()
+    };
+    let listener = TcpListener::bind((any, session.p2p_port))?;
+    let mut stream = accept_partner(&listener, CONNECT_DELAY + session.heartbeat.timeout)?;
+    stream.set_read_timeout(Some(session.heartbeat.timeout))?;
+    let mut reader = FrameReader::new(stream.try_clone()?);
+    accept_handshake(&mut stream, &mut reader)?;
+    let verified = verify_partner(&mut stream, &mut reader, &chat_partner, partner_key, ProofRole::Master, session, sender)?;
+    let (writer, reader) = secure::establish(stream, reader, &session.identity, partner_key, Role::Master)?;
+
+    sys_message!(&format!("{} connected successfully", &chat_partner) => sender);
+    let chat_partner = chat_started(chat_partner, partner_key, verified, term, sender);
+
+    // TODO: user_input_loop all the way, give means to get input
+    // then join on network thread
+    // then restart (whole?) flow
+    chat_with_peer(sender, chat_partner, writer, reader, session, term)
+}
+
+/// Waits for the partner to connect, Error::Timeout if it doesn't within `patience`.
+fn accept_partner(listener: &TcpListener, patience: time::Duration) -> Result<TcpStream> {
+    let deadline = time::Instant::now() + patience;
+    listener.set_nonblocking(true)?;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // some systems hand down non-blocking mode to the accepted socket
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && time::Instant::now() < deadline => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(Error::Timeout),
+            Err(e) => return Err(e.into())
+        }
+    }
+}
 
-            user_input_loop(snd, &mut stream, &term);
+/// Makes sure whoever connected holds the key the server told us about, then compares that key
+/// to the one we remember for the name. A changed key is worth a loud warning, not a hang up:
+/// the server vouches for the new one and people do lose their keys.
+/// Returns whether the user compared safety numbers for this key before.
+fn verify_partner(stream: &mut TcpStream, reader: &mut FrameReader<TcpStream>, chat_partner: &UserSummary, partner_key: PublicKey,
+                  role: ProofRole, session: &mut Session, snd: &Sender<InternMessage>) -> Result<bool> {
+    let proven_key = identity::exchange_proofs(stream, reader, &session.identity, role)?;
+    if proven_key != partner_key {
+        return Err(Error::Protocol(format!("whoever connected is not the {} the discovery server paired us with", chat_partner.name)));
+    }
+    match session.known_peers.check_peer(&chat_partner.name, partner_key)? {
+        Trust::Known{verified} => Ok(verified),
+        Trust::New => {
+            sys_message!(&format!("first chat with {}, remembering their key: {}", chat_partner.name, partner_key) => snd);
+            Ok(false)
         },
-        Err(e) => {
-           err_message!(&format!("failed to connect: {}", e) => snd)
+        Trust::Changed{remembered} => {
+            err_message!(&format!("WARNING: {} has a different key than last time!", chat_partner) => snd);
+            err_message!(&format!("WARNING: remembered: {}", remembered) => snd);
+            err_message!(&format!("WARNING: now:        {}", partner_key) => snd);
+            err_message!(&format!("WARNING: this may be someone else using their name. if they really got a new key, compare safety numbers with /verify or remove their line from {}",
+                                  session.known_peers.path().display()) => snd);
+            Ok(false)
         }
     }
 }
 
-fn start_master_server_direct(sender: Sender<InternMessage>, chat_partner: String, term: ui::UI) {
-    let listener = TcpListener::bind("0.0.0.0:3334").unwrap();
-    // TODO: do i really wait for multiple connections here?
-    //for stream in listener.incoming() {
-        match listener.accept() {
-            Ok((stream, _)) => {
-                term.update_title(&chat_partner);
+/// Tells the user how far they can trust the chat that just started.
+fn chat_started(summary: UserSummary, key: PublicKey, safety_number_verified: bool, term: &ui::UI, snd: &Sender<InternMessage>) -> Arc<Mutex<ChatPartner>> {
+    if safety_number_verified {
+        sys_message!(&format!("your chat with {} is end-to-end encrypted, you verified their safety number", &summary) => snd);
+    } else {
+        sys_message!(&format!("your chat with {} is end-to-end encrypted, type /verify to compare safety numbers", &summary) => snd);
+    }
+    let chat_partner = ChatPartner{paired_name: summary.name.clone(), summary, key, safety_number_verified};
+    term.update_title(&chat_title(&chat_partner));
+    Arc::new(Mutex::new(chat_partner))
+}
 
-                sys_message!(&format!("{} connected successfully", &chat_partner) => sender);
+/// The title only ever shows links that went through secure::establish.
+fn chat_title(chat_partner: &ChatPartner) -> String {
+    let verified = if chat_partner.safety_number_verified { "verified" } else { "unverified" };
+    format!("{} (end-to-end encrypted, {})", chat_partner.summary, verified)
+}
 
-                create_network_listener(&sender, &chat_partner, &stream);
+/// Runs the chat on an established P2P link. The writer is shared between the user input,
+/// the network listener answering pings and the pinger.
+fn chat_with_peer(sender: &Sender<InternMessage>, chat_partner: Arc<Mutex<ChatPartner>>, writer: SecureWriter<TcpStream>, reader: SecureReader<TcpStream>,
+                  session: &mut Session, term: &ui::UI) -> Result<()> {
+    let writer = Arc::new(Mutex::new(writer));
+    create_network_listener(sender, Arc::clone(&chat_partner), reader, Arc::clone(&writer));
+    let _pinger = Pinger::spawn(session.heartbeat.interval, {
+        let writer = Arc::clone(&writer);
+        move || writer.lock().unwrap().send(&PeerMessage::Ping)
+    });
 
-                let mut write_stream = stream.try_clone().unwrap();
-                let input_clone = sender.clone();
-                // TODO: user_input_loop all the way, give means to get input
-                // then join on network thread
-                // then restart (whole?) flow
-                user_input_loop(input_clone, &mut write_stream, &term);
-            },
-            Err(e) => err_message!(&format!("Error: {}", e) => sender)
+    user_input_loop(sender, &writer, &chat_partner, session, term)
+}
+
+/// Connecting side of the P2P handshake: send our Hello, wait for the master's verdict.
+fn connect_handshake(stream: &mut TcpStream, reader: &mut FrameReader<TcpStream>) -> Result<Capabilities> {
+    codec::write_frame(stream, &Hello::new(CAPABILITIES))?;
+    match reader.read_frame()? {
+        HandshakeReply::Accepted(hello) => Ok(hello.capabilities),
+        HandshakeReply::Rejected(reason) => Err(Error::Protocol(format!("chat partner rejected us: {}", reason)))
+    }
+}
+
+/// Accepting side of the P2P handshake: check the partner's Hello and answer it.
+fn accept_handshake(stream: &mut TcpStream, reader: &mut FrameReader<TcpStream>) -> Result<Capabilities> {
+    let peer_hello: Hello = reader.read_frame()?;
+    let own_hello = Hello::new(CAPABILITIES);
+    match own_hello.negotiate(&peer_hello) {
+        Ok(capabilities) => {
+            codec::write_frame(stream, &HandshakeReply::Accepted(Hello::new(capabilities)))?;
+            Ok(capabilities)
+        },
+        Err(reason) => {
+            codec::write_frame(stream, &HandshakeReply::Rejected(reason.clone()))?;
+            Err(Error::Protocol(reason))
         }
-    //}
+    }
 }
 
 /// Spins up a thread which listens on incoming messages.
 /// Each chat participant should have his own listener thread at the moment.
-fn create_network_listener(sender: &Sender<InternMessage>, chat_partner: &String, stream: &TcpStream) {
+fn create_network_listener(sender: &Sender<InternMessage>, chat_partner: Arc<Mutex<ChatPartner>>, mut reader: SecureReader<TcpStream>,
+                           writer: Arc<Mutex<SecureWriter<TcpStream>>>) {
     let network_sender = sender.clone();
-    let mut read_stream = stream.try_clone().unwrap();
-    let partner_clone = chat_partner.clone();
     thread::spawn(move || {
-        read_incoming_messages(network_sender, &mut read_stream, partner_clone)
+        read_incoming_messages(network_sender, &mut reader, &writer, chat_partner)
     });
 }
 
-fn user_input_loop(sender: Sender<InternMessage>, stream: &mut TcpStream, term: &ui::UI) {
+fn user_input_loop(sender: &Sender<InternMessage>, writer: &Mutex<SecureWriter<TcpStream>>, chat_partner: &Arc<Mutex<ChatPartner>>,
+                   session: &mut Session, term: &ui::UI) -> Result<()> {
+    let user = &mut session.credentials.request;
     term.move_to_input_pos();
-    while match term.read_line() {
-        input => {
-            let mut continue_chat = true;
-            if &input == "/exit" {
-                chat_message!(String::from(""), String::from("/exit") => sender);
-                close_connection(stream, &sender);
-
-                continue_chat = false;
-            } else {
-                let message_to_send = Message{message: input.clone()};
-                // TODO: how can we query whether or not the other client terminated the connection?
-                send_message(message_to_send, stream, &sender);
-
-                chat_message!(String::from("me"), input => sender);
+    let mut next_message_id: MessageId = 0;
+    loop {
+        let input = term.read_line();
+        if &input == "/exit" {
+            chat_message!(String::from(""), String::from("/exit") => sender);
+            close_connection(writer.lock().unwrap().get_ref(), sender);
+            return Ok(());
+        }
+        if input == "/verify" || input == "/verify confirm" {
+            verify_safety_number(sender, chat_partner, &input, session.identity.public_key(), &mut session.known_peers);
+            continue;
+        }
+        if let Some(new_name) = input.strip_prefix("/nick ") {
+            // the server only learns about it on our next login, it will refuse it then if taken
+            match validation::validate_user_name(new_name) {
+                Ok(()) if user.password.is_some() => err_message!("registered users can't change their name" => sender),
+                Ok(()) => {
+                    writer.lock().unwrap().send(&PeerMessage::Nick(new_name.to_string()))?;
+                    sys_message!(&format!("you are now known as {}", new_name) => sender);
+                    user.name = new_name.to_string();
+                },
+                Err(e) => err_message!(&format!("name {}", e) => sender)
             }
-            continue_chat
+            continue;
         }
-    } {}
+        let message_to_send = Message{message: input.clone()};
+        match message_to_send.validate() {
+            Ok(()) => {
+                send_message(&mut writer.lock().unwrap(), next_message_id, &message_to_send)?;
+                next_message_id = next_message_id.wrapping_add(1);
+            },
+            Err(ValidationError::Empty) => continue,
+            Err(e) => {
+                err_message!(&format!("not sent, message {}", e) => sender);
+                continue;
+            }
+        }
+
+        chat_message!(String::from("me"), input => sender);
+    }
 }
 
-/// Reads from the given TcpStream and processes the incoming messages.
-/// sender: PrintLoop-Sender
-/// stream: Stream whose messages we want processed
-/// chat_partner: Name of our chat partner
-fn read_incoming_messages(sender: Sender<InternMessage>, stream: &mut TcpStream, chat_partner: String) {
-    let mut buffer = vec!(0; Message::SIZE);
-    while match stream.read(&mut buffer) {
-        Ok(size) => {
-            let mut continue_reading = true;
-            if size == 0 {
-                sys_message!("/terminated" => sender);
-                continue_reading = false;
-            } else {
-                let msg = deserialize_message(&buffer);
-                chat_message!(chat_partner.clone(), msg.message => sender);
-            }
-            continue_reading
+/// `/verify` shows the safety number, `/verify confirm` remembers that it matched what the partner sees.
+fn verify_safety_number(sender: &Sender<InternMessage>, chat_partner: &Arc<Mutex<ChatPartner>>, input: &str, own_key: PublicKey, known_peers: &mut KnownPeers) {
+    let mut partner = chat_partner.lock().unwrap();
+    if input == "/verify" {
+        sys_message!(&format!("safety number of your chat with {}: {}", partner.summary.name, known_peers::safety_number(&own_key, &partner.key)) => sender);
+        sys_message!(&format!("if {} sees the same number, type /verify confirm", partner.summary.name) => sender);
+        return;
+    }
+    match known_peers.mark_verified(&partner.paired_name, partner.key) {
+        Ok(()) => {
+            partner.safety_number_verified = true;
+            sys_message!(&format!("{} is verified now", partner.summary.name) => sender);
+            sender.send(InternMessage::PartnerChanged(Arc::clone(chat_partner))).unwrap();
         },
-        Err(e) => {
-            err_message!(&format!("Error: {}, Caused By: {}", e.description(), e.source().unwrap()) => sender);
-            false
-        }
-    } {}
+        Err(e) => err_message!(&format!("could not remember {} as verified: {}", partner.summary.name, e) => sender)
+    }
 }
 
-/// Sends a message to a chat partner
-/// msg: Message to send
-/// stream: Connection to a chat partner
-/// snd: Possibly needed to print an error to the UI
-fn send_message(msg: Message, stream: &mut TcpStream, snd: &Sender<InternMessage>) {
-    let serialized = bincode::serialize(&msg).unwrap();
-    match stream.write(&serialized) {
-        Ok(_) => {},
-        Err(_) => {
-            // Client crashed or terminated connection.
-            // We should be able to discern that, for now just log it
-            // TODO: Logging
-            err_message!("chat partner terminated connection or crashed" => snd);
-        }
+/// Sends the message as one or more chunks, the partner puts them back together.
+fn send_message(writer: &mut SecureWriter<TcpStream>, message_id: MessageId, message: &Message) -> Result<()> {
+    for chunk in multipart::split_message(message_id, message) {
+        writer.send(&PeerMessage::Chunk(chunk))?;
     }
+    Ok(())
 }
 
-fn deserialize_message(buffer: &[u8]) -> Message {
-    bincode::deserialize(buffer).unwrap()
+/// Reads from the given link and processes the incoming messages.
+/// sender: PrintLoop-Sender
+/// reader: Decrypting end of the link whose messages we want processed
+/// writer: Encrypting end of the same link, pings are answered on it
+/// shared_partner: Who we are chatting with, the user input changes it as well
+fn read_incoming_messages(sender: Sender<InternMessage>, reader: &mut SecureReader<TcpStream>, writer: &Mutex<SecureWriter<TcpStream>>,
+                          shared_partner: Arc<Mutex<ChatPartner>>) {
+    let mut reassembler = Reassembler::new();
+    loop {
+        let received = reader.receive();
+        let chat_partner = shared_partner.lock().unwrap().summary.clone();
+        match received {
+            Ok(PeerMessage::Chunk(chunk)) => match reassembler.add(chunk) {
+                Ok(Some(msg)) => match msg.validate() {
+                    Ok(()) => chat_message!(chat_partner.name.clone(), msg.message => sender),
+                    Err(e) => err_message!(&format!("dropped a message from {}: message {}", chat_partner.name, e) => sender)
+                },
+                Ok(None) => (),
+                Err(e) => err_message!(&format!("dropped a message from {}: {}", chat_partner.name, e) => sender)
+            },
+            // the server vouched for the name of a registered partner, it stays
+            Ok(PeerMessage::Nick(_)) if chat_partner.verified =>
+                err_message!(&format!("{} tried to change their name, registered users can't", chat_partner.name) => sender),
+            Ok(PeerMessage::Nick(new_name)) => match validation::validate_user_name(&new_name) {
+                Ok(()) => {
+                    let old_name = std::mem::replace(&mut shared_partner.lock().unwrap().summary.name, new_name.clone());
+                    sys_message!(&format!("{} is now known as {}", old_name, new_name) => sender);
+                    sender.send(InternMessage::PartnerChanged(Arc::clone(&shared_partner))).unwrap();
+                },
+                Err(e) => err_message!(&format!("{} wanted to change their name: name {}", chat_partner.name, e) => sender)
+            },
+            Ok(PeerMessage::Ping) => {
+                if let Err(e) = writer.lock().unwrap().send(&PeerMessage::Pong) {
+                    report_error(&e, &sender);
+                    break;
+                }
+            },
+            Ok(PeerMessage::Pong) => (),
+            Err(Error::PeerGone) => {
+                sys_message!("/terminated" => sender);
+                break;
+            },
+            Err(Error::Timeout) => {
+                sys_message!("/timedout" => sender);
+                break;
+            },
+            Err(e) => {
+                report_error(&e, &sender);
+                break;
+            }
+        }
+    }
 }
 
 /// Prints to the UI. Loops over the given receiver.
 /// Every component that wants to write something on the screen needs a sender to this channel.
 /// receiver: Consuming end of a multi producer channel
 /// term: The UI. This print loop owns it.
-fn print_messages_to_ui(receiver: Receiver<InternMessage>, mut term: ui::UI) {
+/// history: Chat messages are written to it as well if set
+fn print_messages_to_ui(receiver: Receiver<InternMessage>, mut term: ui::UI, mut history: Option<History>) {
     while match receiver.recv() {
         Ok(message) => {
             let mut continue_loop = false;
@@ -252,13 +806,25 @@ fn print_messages_to_ui(receiver: Receiver<InternMessage>, mut term: ui::UI) {
                 InternMessage::ChatMessage(info) => {
                     if &info.message != "/exit" {
                         term.write_info_message(&format!("{}: {}", &info.message_writer, &info.message));
+                        if let Err(e) = history.as_mut().map_or(Ok(()), |history| history.record(&info.message_writer, &info.message)) {
+                            term.write_err_message(&format!("could not write the history, no longer keeping it: {}", e));
+                            history = None;
+                        }
                         continue_loop = true
                     }
                 },
+                // not kept in the history file, it would show up again on every join
+                InternMessage::HistoryMessage(info, age_secs) => {
+                    term.write_history_message(&format!("[{} ago] {}: {}", age(age_secs), &info.message_writer, &info.message));
+                    continue_loop = true
+                },
                 InternMessage::SystemMessage(text) => {
                     if &text == "/terminated" {
                         term.write_sys_message("connection with your chat partner was terminated");
                         continue_loop = true
+                    } else if &text == "/timedout" {
+                        term.write_sys_message("your chat partner timed out");
+                        continue_loop = true
                     } else {
                         term.write_sys_message(&text);
                         continue_loop = true
@@ -267,6 +833,10 @@ fn print_messages_to_ui(receiver: Receiver<InternMessage>, mut term: ui::UI) {
                 InternMessage::ErrorMessage(text) => {
                     term.write_err_message(&text);
                     continue_loop = true
+                },
+                InternMessage::PartnerChanged(partner) => {
+                    term.update_title(&chat_title(&partner.lock().unwrap()));
+                    continue_loop = true
                 }
             }
             
@@ -279,41 +849,120 @@ fn print_messages_to_ui(receiver: Receiver<InternMessage>, mut term: ui::UI) {
     } {}
 }
 
+/// Like 5m, roughly.
+fn age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600)
+    }
+}
+
 // TODO: switch to numbers + 'exit' to go back
 fn select_chat_partner(term: &ui::UI, snd: &Sender<InternMessage>) -> String {
     sys_message!("Select chat partner: " => snd);
-    term.read_line()
+    loop {
+        let name = term.read_line();
+        match validation::validate_user_name(&name) {
+            Ok(()) => return name,
+            Err(e) => err_message!(&format!("name {}, try again", e) => snd)
+        }
+    }
 }
 
-fn send_string(name: String, stream: &mut TcpStream, snd: &Sender<InternMessage>) {
-    let serialized_name = bincode::serialize(&name).unwrap();
-    match stream.write(&serialized_name) {
-        Ok(size) => sys_message!(&format!("string transmitted, wrote {} bytes", size) => snd),
-        Err(e) => err_message!(&format!("error transmitting the string: {}", e) => snd)
+/// Asks for a room to join, or a new one to create. Listed rooms ask for their password if they have one,
+/// unlisted ones only do if the name was given with /password. Whoever was invited leaves the password empty.
+fn select_room(rooms: &[RoomSummary], term: &ui::UI, snd: &Sender<InternMessage>) -> ClientMessage {
+    sys_message!("Select chat room, /create to make a new one or /password <room> for an unlisted room with a password: " => snd);
+    loop {
+        let line = term.read_line();
+        if line == "/create" {
+            return ClientMessage::CreateRoom(get_new_room(term, snd));
+        }
+        let (name, password) = match line.strip_prefix("/password ") {
+            Some(name) => (name.to_string(), true),
+            None => {
+                let password = rooms.iter().any(|room| room.access == AccessMode::Password && room.name.eq_ignore_ascii_case(&line));
+                (line, password)
+            }
+        };
+        match validation::validate_room_name(&name) {
+            Ok(()) if password => return ClientMessage::JoinRoom{name, password: get_room_password(term, snd)},
+            Ok(()) => return ClientMessage::JoinRoom{name, password: None},
+            Err(e) => err_message!(&format!("room name {}, try again", e) => snd)
+        }
+    }
+}
+
+fn get_new_room(term: &ui::UI, snd: &Sender<InternMessage>) -> NewRoom {
+    sys_message!("name of the new room:" => snd);
+    let name = loop {
+        let name = term.read_line();
+        match validation::validate_room_name(&name) {
+            Ok(()) => break name,
+            Err(e) => err_message!(&format!("room name {}, try again", e) => snd)
+        }
+    };
+    sys_message!("topic, leave it empty for none:" => snd);
+    let topic = loop {
+        let topic = term.read_line();
+        match validation::validate_topic(&topic) {
+            Ok(()) => break Some(topic),
+            Err(ValidationError::Empty) => break None,
+            Err(e) => err_message!(&format!("topic {}, try again", e) => snd)
+        }
+    };
+    sys_message!("(1) anyone can join, (2) joining takes a password, (3) invite only: " => snd);
+    let access = loop {
+        match term.read_line().as_str() {
+            "1" => break Access::Open,
+            "2" => break Access::Password(get_password("please enter the password for the room", term, snd)),
+            "3" => break Access::InviteOnly,
+            x => err_message!(x => snd)
+        }
+    };
+    sys_message!("(1) public, (2) unlisted, only whoever knows the name finds it: " => snd);
+    let visibility = loop {
+        match term.read_line().as_str() {
+            "1" => break Visibility::Public,
+            "2" => break Visibility::Unlisted,
+            x => err_message!(x => snd)
+        }
+    };
+    NewRoom{name, topic, access, visibility}
+}
+
+/// None if left empty, for whoever has an invite.
+fn get_room_password(term: &ui::UI, snd: &Sender<InternMessage>) -> Option<String> {
+    sys_message!("please enter the password of the room, leave it empty if you were invited" => snd);
+    loop {
+        let password = term.read_secure_line();
+        match validation::validate_password(&password) {
+            Ok(()) => return Some(password),
+            Err(ValidationError::Empty) => return None,
+            Err(e) => err_message!(&format!("password {}, try again", e) => snd)
+        }
     }
 }
 
 // TODO: Better print it enumerated and pick with numbers
 fn print_string_vec(names: &Vec<String>, snd: &Sender<InternMessage>) {
     for name in names {
-        sys_message!(&format!("{}", name) => snd);
+        sys_message!(name => snd);
     }
 }
 
-fn get_and_send_chat_mode(stream: &mut TcpStream, term: &ui::UI, snd: &Sender<InternMessage>) -> ChatMode {
-    let mode = get_chat_mode(term, snd);
-    let serialized = bincode::serialize(&mode).unwrap();
-    match stream.write(&serialized) {
-        Ok(size) => sys_message!(&format!("chat mode transmitted, wrote {} bytes", size) => snd),
-        Err(e) => err_message!(&format!("error transmitting the chat mode: {}", e) => snd)
-    }
-    mode
+fn select_chat_mode(connection: &mut DiscoveryConnection, term: &ui::UI, snd: &Sender<InternMessage>) -> Result<()> {
+    let mode = get_chat_mode(connection, term, snd)?;
+    connection.send(ClientMessage::SelectChatMode(mode))?;
+    Ok(())
 }
 
-fn get_chat_mode(term: &ui::UI, snd: &Sender<InternMessage>) -> ChatMode {
+/// Renames requested in between are sent right away, the server's answer arrives once the mode is picked.
+fn get_chat_mode(connection: &mut DiscoveryConnection, term: &ui::UI, snd: &Sender<InternMessage>) -> Result<ChatMode> {
     let mut mode: ChatMode = ChatMode::DIRECT;
     // term.move_to_input_pos();
-    sys_message!("(1) direct chat, (2) chat rooms, (3) wait: " => snd);
+    sys_message!("(1) direct chat, (2) chat rooms, (3) wait, /nick <name> to change your name: " => snd);
     while match term.read_line().as_str() {
         "1" => {
             mode = ChatMode::DIRECT;
@@ -327,64 +976,76 @@ fn get_chat_mode(term: &ui::UI, snd: &Sender<InternMessage>) -> ChatMode {
             mode = ChatMode::WAIT;
             false
         },
+        x if x.starts_with("/nick ") => {
+            let new_name = &x["/nick ".len()..];
+            match validation::validate_user_name(new_name) {
+                Ok(()) => {
+                    connection.send(ClientMessage::Rename(new_name.to_string()))?;
+                },
+                Err(e) => err_message!(&format!("name {}, try again", e) => snd)
+            }
+            true
+        },
         x => {
             err_message!(x => snd);
             true
         }
     } {}
-    mode
-}
-
-fn receive_master_selection(stream: &mut TcpStream, snd: &Sender<InternMessage>) -> Option<MasterSelectionResult> {
-    let mut buffer = vec!(0; MasterSelectionResult::SIZE);
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            if size == 0 {
-                None
-            } else {
-                let result: MasterSelectionResult = bincode::deserialize(&buffer).unwrap();
-                Some(result)
-            }
-        },
-        Err(e) => {
-            err_message!(&format!("error receiving master selection result: {}", e) => snd);
-            None
+    Ok(mode)
+}
+
+/// Asks whether to log in, register or stay a guest, then for the name and password that takes.
+/// The name is only asked for if none is given.
+fn get_credentials(term: &ui::UI, snd: &Sender<InternMessage>, name: Option<String>, p2p_port: u16) -> Credentials {
+    sys_message!("(1) log in, (2) register a new account, (3) continue as guest: " => snd);
+    let register = loop {
+        match term.read_line().as_str() {
+            "1" => break Some(false),
+            "2" => break Some(true),
+            "3" => break None,
+            x => err_message!(x => snd)
         }
+    };
+    let user = match name {
+        Some(name) => LoginRequest::guest(name, p2p_port),
+        None => get_user(term, snd, p2p_port)
+    };
+    match register {
+        Some(register) => Credentials{request: LoginRequest{password: Some(get_password("please enter your password", term, snd)), ..user}, register},
+        None => Credentials{request: user, register: false}
     }
 }
 
-fn receive_string_vec(stream: &mut TcpStream, buffer: &mut Vec<u8>, snd: &Sender<InternMessage>) -> Option<Vec<String>> {
-    match stream.read(buffer) {
-        Ok(size) => {
-            if size == 0 {
-                None
-            } else {
-                let names: Vec<String> = bincode::deserialize(&buffer).unwrap();
-                Some(names)
-            }
-        },
-        Err(e) => {
-            err_message!(&format!("error receiving room names: {}", e) => snd);
-            None
+fn get_user(term: &ui::UI, snd: &Sender<InternMessage>, p2p_port: u16) -> LoginRequest {
+    sys_message!("please enter you name" => snd);
+    loop {
+        let request = LoginRequest::guest(term.read_line(), p2p_port);
+        match request.validate() {
+            Ok(()) => return request,
+            Err(e) => err_message!(&format!("name {}, try again", e) => snd)
         }
     }
 }
 
-fn get_user(term: &ui::UI, snd: &Sender<InternMessage>) -> LoginRequest {
-    sys_message!("please enter you name" => snd);
-    let input = term.read_line();
-    LoginRequest{name: input}
+fn get_password(prompt: &str, term: &ui::UI, snd: &Sender<InternMessage>) -> String {
+    sys_message!(prompt => snd);
+    loop {
+        let password = term.read_secure_line();
+        match validation::validate_password(&password) {
+            Ok(()) => return password,
+            Err(e) => err_message!(&format!("password {}, try again", e) => snd)
+        }
+    }
 }
 
-fn send_request(user: LoginRequest, stream: &mut TcpStream, snd: &Sender<InternMessage>) {
-    let serialized = user.to_bytes();
-    match stream.write(&serialized){
-        Ok(size) => sys_message!(&format!("wrote {} bytes", size) => snd),
-        Err(e) => err_message!(&format!("failed to transmit user name: {}", e) => snd)
-    };
+fn close_discovery_connection(connection: DiscoveryConnection, snd: &Sender<InternMessage>) {
+    match connection.close() {
+        Ok(_) => sys_message!("connection with discovery server terminated" => snd),
+        Err(e) => err_message!(&format!("failed to properly close connection to server: {}", e) => snd)
+    }
 }
 
-fn close_connection(connection: &mut TcpStream, snd: &Sender<InternMessage>) {
+fn close_connection(connection: &TcpStream, snd: &Sender<InternMessage>) {
     match connection.shutdown(Shutdown::Both) {
         Ok(_) => {
             sys_message!("connection with peer terminated" => snd);
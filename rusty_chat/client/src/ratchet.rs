@@ -0,0 +1,354 @@
+//! Double Ratchet, as in Signal, for the direct chat link.
+//!
+//! Every message is encrypted with a key of its own. Message keys come from chain keys that move
+//! forward with every message and are thrown away once used, so keys taken from a running chat
+//! don't open anything sent before. Whenever the partner answers with a new ratchet key, both
+//! sides mix a fresh Diffie-Hellman result into the root key, which locks a thief out again.
+//!
+//! Messages may arrive out of order. The keys of the ones skipped over are kept for a while,
+//! replays and messages that fall too far behind are refused.
+
+use std::collections::{HashMap, VecDeque};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+use x25519_dalek::{StaticSecret, PublicKey as RatchetKey};
+use zeroize::Zeroize;
+use common::{Error, Result};
+use common::protocol::{RatchetHeader, RatchetMessage};
+
+/// Mixed into every new root key.
+const ROOT_INFO: &[u8] = b"rusty chat ratchet root v1\0";
+/// Turns a message key into an AEAD key and nonce.
+const MESSAGE_INFO: &[u8] = b"rusty chat ratchet message v1\0";
+/// How many messages of a single chain we skip over at once.
+const MAX_SKIP: u32 = 1000;
+/// How many keys of skipped messages we keep at most, the oldest go first.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+type ChainKey = [u8; 32];
+type MessageKey = [u8; 32];
+
+/// One side's state of a ratcheting chat.
+#[derive(Clone)]
+pub struct Ratchet {
+    own_secret: StaticSecret,
+    own_public: RatchetKey,
+    /// The partner's ratchet key we last heard of
+    partner_public: RatchetKey,
+    root_key: [u8; 32],
+    sending_chain: ChainKey,
+    /// None until the partner sent anything with its current ratchet key
+    receiving_chain: Option<ChainKey>,
+    sent: u32,
+    received: u32,
+    /// Messages sent on the previous sending chain, the partner needs it to skip over the rest of them
+    previous_sent: u32,
+    skipped: HashMap<(RatchetKey, u32), MessageKey>,
+    /// Order the skipped keys were stored in
+    skipped_order: VecDeque<(RatchetKey, u32)>,
+    /// Authenticated along with every message, ties it to both identities
+    associated_data: Vec<u8>
+}
+
+impl Ratchet {
+    /// The side that listened for the partner. It makes the first ratchet step right away, its
+    /// first message takes the connector along.
+    /// `root_key` and `initial_chain` come from the key exchange, the connector sends with that chain
+    /// until then.
+    pub fn master(root_key: [u8; 32], initial_chain: ChainKey, partner_public: RatchetKey, associated_data: Vec<u8>) -> Result<Ratchet> {
+        let own_secret = StaticSecret::from(thread_rng().gen::<[u8; 32]>());
+        let (root_key, sending_chain) = advance_root(&root_key, &own_secret, &partner_public)?;
+        Ok(Ratchet{
+            own_public: RatchetKey::from(&own_secret), own_secret, partner_public, root_key, sending_chain,
+            receiving_chain: Some(initial_chain), sent: 0, received: 0, previous_sent: 0,
+            skipped: HashMap::new(), skipped_order: VecDeque::new(), associated_data
+        })
+    }
+
+    /// The side that connected, `own_secret` is the one of its key share.
+    pub fn connector(root_key: [u8; 32], initial_chain: ChainKey, own_secret: StaticSecret, partner_public: RatchetKey,
+                     associated_data: Vec<u8>) -> Ratchet {
+        Ratchet{
+            own_public: RatchetKey::from(&own_secret), own_secret, partner_public, root_key, sending_chain: initial_chain,
+            receiving_chain: None, sent: 0, received: 0, previous_sent: 0,
+            skipped: HashMap::new(), skipped_order: VecDeque::new(), associated_data
+        }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage> {
+        let header = RatchetHeader{public: self.own_public.to_bytes(), previous_count: self.previous_sent, number: self.sent};
+        self.sent = self.sent.checked_add(1).ok_or_else(|| Error::protocol("this chat ran out of message numbers, reconnect to go on chatting"))?;
+        let mut message_key = step_chain(&mut self.sending_chain);
+        let ciphertext = seal(&message_key, &self.associated_data, &header, plaintext);
+        message_key.zeroize();
+        Ok(RatchetMessage{header, ciphertext: ciphertext?})
+    }
+
+    /// Leaves the state as it was if the message does not check out, so a forged message can't
+    /// knock the chat out of step.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_message(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_message(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
+        let header = &message.header;
+        let partner_public = RatchetKey::from(header.public);
+        if let Some(mut message_key) = self.skipped.remove(&(partner_public, header.number)) {
+            self.skipped_order.retain(|skipped| *skipped != (partner_public, header.number));
+            let plaintext = open(&message_key, &self.associated_data, message);
+            message_key.zeroize();
+            return plaintext;
+        }
+        if partner_public != self.partner_public {
+            self.skip_until(header.previous_count)?;
+            self.turn(partner_public)?;
+        }
+        if header.number < self.received {
+            return Err(Error::protocol("your chat partner's message was already received, someone may be replaying old messages"));
+        }
+        self.skip_until(header.number)?;
+        let receiving_chain = self.receiving_chain.as_mut().expect("turning the ratchet starts a receiving chain");
+        let mut message_key = step_chain(receiving_chain);
+        self.received += 1;
+        let plaintext = open(&message_key, &self.associated_data, message);
+        message_key.zeroize();
+        plaintext
+    }
+
+    /// Keeps the keys of the messages up to `until` on the current receiving chain for when they show up.
+    fn skip_until(&mut self, until: u32) -> Result<()> {
+        let receiving_chain = match self.receiving_chain.as_mut() {
+            Some(chain) => chain,
+            None => return Ok(())
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(Error::Protocol(format!("your chat partner skipped more than {} messages", MAX_SKIP)));
+        }
+        while self.received < until {
+            let index = (self.partner_public, self.received);
+            self.skipped.insert(index, step_chain(receiving_chain));
+            self.skipped_order.push_back(index);
+            self.received += 1;
+        }
+        while self.skipped_order.len() > MAX_SKIPPED_KEYS {
+            if let Some(oldest) = self.skipped_order.pop_front() {
+                if let Some(mut key) = self.skipped.remove(&oldest) {
+                    key.zeroize();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The partner moved on to a new ratchet key, we follow with a new one of our own.
+    fn turn(&mut self, partner_public: RatchetKey) -> Result<()> {
+        let (root_key, receiving_chain) = advance_root(&self.root_key, &self.own_secret, &partner_public)?;
+        self.own_secret = StaticSecret::from(thread_rng().gen::<[u8; 32]>());
+        self.own_public = RatchetKey::from(&self.own_secret);
+        let (root_key, sending_chain) = advance_root(&root_key, &self.own_secret, &partner_public)?;
+        self.zeroize_chains();
+        self.partner_public = partner_public;
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = sending_chain;
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        Ok(())
+    }
+
+    fn zeroize_chains(&mut self) {
+        self.root_key.zeroize();
+        self.sending_chain.zeroize();
+        if let Some(chain) = self.receiving_chain.as_mut() {
+            chain.zeroize();
+        }
+    }
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.zeroize_chains();
+        for key in self.skipped.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+/// Mixes the Diffie-Hellman result into the root key, returns the new root key and chain key.
+fn advance_root(root_key: &[u8; 32], own_secret: &StaticSecret, partner_public: &RatchetKey) -> Result<([u8; 32], ChainKey)> {
+    let shared = own_secret.diffie_hellman(partner_public);
+    if !shared.was_contributory() {
+        return Err(Error::protocol("your chat partner sent a ratchet key that can't be used"));
+    }
+    let mut output = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), shared.as_bytes()).expand(ROOT_INFO, &mut output).expect("64 bytes are a valid HKDF-SHA256 output length");
+    let mut keys = ([0u8; 32], [0u8; 32]);
+    keys.0.copy_from_slice(&output[..32]);
+    keys.1.copy_from_slice(&output[32..]);
+    output.zeroize();
+    Ok(keys)
+}
+
+/// Moves the chain one step forward and returns the message key for the step.
+fn step_chain(chain: &mut ChainKey) -> MessageKey {
+    let message_key = chain_hmac(chain, 1);
+    *chain = chain_hmac(chain, 2);
+    message_key
+}
+
+fn chain_hmac(chain: &ChainKey, constant: u8) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain).expect("HMAC takes keys of any length");
+    mac.update(&[constant]);
+    mac.finalize().into_bytes().into()
+}
+
+fn seal(message_key: &MessageKey, associated_data: &[u8], header: &RatchetHeader, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    let aad = authenticated_data(associated_data, header)?;
+    cipher.encrypt(&nonce, Payload{msg: plaintext, aad: &aad}).map_err(|_| Error::protocol("failed to encrypt a message"))
+}
+
+fn open(message_key: &MessageKey, associated_data: &[u8], message: &RatchetMessage) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    let aad = authenticated_data(associated_data, &message.header)?;
+    cipher.decrypt(&nonce, Payload{msg: &message.ciphertext, aad: &aad})
+        .map_err(|_| Error::protocol("a message from your chat partner was tampered with"))
+}
+
+fn message_cipher(message_key: &MessageKey) -> (ChaCha20Poly1305, Nonce) {
+    let mut output = [0u8; 44];
+    Hkdf::<Sha256>::new(None, message_key).expand(MESSAGE_INFO, &mut output).expect("44 bytes are a valid HKDF-SHA256 output length");
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&output[..32]));
+    let nonce = *Nonce::from_slice(&output[32..]);
+    output.zeroize();
+    (cipher, nonce)
+}
+
+/// The header is authenticated too, nobody may move a message to another place in the chain.
+fn authenticated_data(associated_data: &[u8], header: &RatchetHeader) -> Result<Vec<u8>> {
+    let mut data = associated_data.to_vec();
+    data.extend_from_slice(&bincode::serialize(header).map_err(Error::Encode)?);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both sides right after the key exchange, the connector's key share goes into the master's first step.
+    fn pair() -> (Ratchet, Ratchet) {
+        let root_key = thread_rng().gen::<[u8; 32]>();
+        let initial_chain = thread_rng().gen::<[u8; 32]>();
+        let connector_secret = StaticSecret::from(thread_rng().gen::<[u8; 32]>());
+        let master_share = RatchetKey::from(&StaticSecret::from(thread_rng().gen::<[u8; 32]>()));
+        let master = Ratchet::master(root_key, initial_chain, RatchetKey::from(&connector_secret), b"alice bob".to_vec()).unwrap();
+        let connector = Ratchet::connector(root_key, initial_chain, connector_secret, master_share, b"alice bob".to_vec());
+        (master, connector)
+    }
+
+    fn send(ratchet: &mut Ratchet, text: &str) -> RatchetMessage {
+        ratchet.encrypt(text.as_bytes()).unwrap()
+    }
+
+    fn receive(ratchet: &mut Ratchet, message: &RatchetMessage) -> Result<String> {
+        ratchet.decrypt(message).map(|plaintext| String::from_utf8(plaintext).unwrap())
+    }
+
+    #[test]
+    fn messages_in_order_get_through_both_ways() {
+        let (mut master, mut connector) = pair();
+        for round in 0..3 {
+            for text in ["hi", "there"] {
+                let message = send(&mut connector, &format!("{} {}", text, round));
+                assert_eq!(receive(&mut master, &message).unwrap(), format!("{} {}", text, round));
+            }
+            let message = send(&mut master, "hello");
+            assert_eq!(receive(&mut connector, &message).unwrap(), "hello");
+        }
+    }
+
+    #[test]
+    fn messages_out_of_order_get_through_within_a_chain_and_across_turns() {
+        let (mut master, mut connector) = pair();
+        let first = send(&mut connector, "first");
+        let second = send(&mut connector, "second");
+        let third = send(&mut connector, "third");
+        assert_eq!(receive(&mut master, &third).unwrap(), "third");
+        assert_eq!(receive(&mut master, &first).unwrap(), "first");
+
+        // the connector turns the ratchet while "second" is still on its way
+        let answer = send(&mut master, "answer");
+        assert_eq!(receive(&mut connector, &answer).unwrap(), "answer");
+        let late = send(&mut connector, "late");
+        let later = send(&mut connector, "later");
+        assert_eq!(later.header.previous_count, 3);
+        assert_eq!(receive(&mut master, &later).unwrap(), "later");
+        assert_eq!(receive(&mut master, &second).unwrap(), "second");
+        assert_eq!(receive(&mut master, &late).unwrap(), "late");
+
+        // messages sent before the connector saw the master's next key
+        let (mut master, mut connector) = pair();
+        let before_turn = send(&mut connector, "before the turn");
+        let answer = send(&mut master, "answer");
+        let unanswered = send(&mut connector, "still on the old chain");
+        assert_eq!(receive(&mut connector, &answer).unwrap(), "answer");
+        let after_turn = send(&mut connector, "after the turn");
+        assert_eq!(after_turn.header.previous_count, 2);
+        assert_eq!(receive(&mut master, &after_turn).unwrap(), "after the turn");
+        assert_eq!(receive(&mut master, &unanswered).unwrap(), "still on the old chain");
+        assert_eq!(receive(&mut master, &before_turn).unwrap(), "before the turn");
+    }
+
+    #[test]
+    fn replays_are_refused() {
+        let (mut master, mut connector) = pair();
+        let first = send(&mut connector, "first");
+        let second = send(&mut connector, "second");
+        assert_eq!(receive(&mut master, &second).unwrap(), "second");
+        assert!(receive(&mut master, &second).is_err());
+        assert_eq!(receive(&mut master, &first).unwrap(), "first");
+        assert!(receive(&mut master, &first).is_err());
+
+        let third = send(&mut connector, "third");
+        assert_eq!(receive(&mut master, &third).unwrap(), "third");
+    }
+
+    #[test]
+    fn skipping_too_many_messages_at_once_is_refused() {
+        let (mut master, mut connector) = pair();
+        let messages: Vec<RatchetMessage> = (0..=MAX_SKIP + 1).map(|number| send(&mut connector, &number.to_string())).collect();
+        assert!(receive(&mut master, &messages[MAX_SKIP as usize + 1]).is_err());
+        assert_eq!(receive(&mut master, &messages[MAX_SKIP as usize]).unwrap(), MAX_SKIP.to_string());
+        assert_eq!(receive(&mut master, &messages[0]).unwrap(), "0");
+        assert_eq!(receive(&mut master, &messages[MAX_SKIP as usize + 1]).unwrap(), (MAX_SKIP + 1).to_string());
+    }
+
+    #[test]
+    fn tampered_messages_leave_the_state_as_it_was() {
+        let (mut master, mut connector) = pair();
+        let mut first = send(&mut connector, "first");
+        first.ciphertext[0] ^= 1;
+        assert!(receive(&mut master, &first).is_err());
+        first.ciphertext[0] ^= 1;
+
+        let moved = RatchetMessage{header: RatchetHeader{number: 5, ..first.header}, ciphertext: first.ciphertext.clone()};
+        assert!(receive(&mut master, &moved).is_err());
+        let forged_turn = RatchetMessage{
+            header: RatchetHeader{public: RatchetKey::from(&StaticSecret::from(thread_rng().gen::<[u8; 32]>())).to_bytes(), ..first.header},
+            ciphertext: first.ciphertext.clone()
+        };
+        assert!(receive(&mut master, &forged_turn).is_err());
+
+        assert_eq!(receive(&mut master, &first).unwrap(), "first");
+        let answer = send(&mut master, "answer");
+        assert_eq!(receive(&mut connector, &answer).unwrap(), "answer");
+    }
+}
@@ -0,0 +1,227 @@
+//! End-to-end encryption of the direct chat link.
+//!
+//! Once both sides proved who they are, each sends a fresh X25519 key signed with its identity
+//! key. HKDF-SHA256 turns the shared secret into the first root and chain key of a Double
+//! Ratchet, see ratchet. From then on every PeerMessage travels as a RatchetMessage.
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use hkdf::Hkdf;
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+use x25519_dalek::{StaticSecret, PublicKey as EphemeralKey};
+use zeroize::Zeroize;
+use common::{Error, Result};
+use common::codec::{self, FrameReader};
+use common::identity::{Identity, PublicKey};
+use common::protocol::{KeyShare, PeerMessage, RatchetMessage};
+use crate::ratchet::Ratchet;
+
+/// Key shares are signed with this in front, see Identity::sign.
+const KEY_SHARE_CONTEXT: &[u8] = b"rusty chat key share v1\0";
+/// Keys derived for anything else will differ from ours.
+const KEY_INFO: &[u8] = b"rusty chat direct chat keys v1\0";
+
+/// Which end of the link we are. Both ends have to agree on it, they start the ratchet differently.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    /// Listened for the partner
+    Master,
+    /// Connected to the master
+    Connector
+}
+
+/// Agrees on the keys with the partner whose identity key we already checked.
+/// Everything sent on the link afterwards has to go through the returned writer and reader.
+pub fn establish<S: Read + Write>(mut stream: S, mut reader: FrameReader<S>, identity: &Identity, partner_key: PublicKey,
+                                  role: Role) -> Result<(SecureWriter<S>, SecureReader<S>)> {
+    let secret = StaticSecret::from(thread_rng().gen::<[u8; 32]>());
+    let own_share = EphemeralKey::from(&secret);
+    // naming the partner in the signature keeps the share from being passed on to anybody else
+    let signature = identity.sign(KEY_SHARE_CONTEXT, &share_data(&own_share, &partner_key));
+    codec::write_frame(&mut stream, &KeyShare{public: own_share.to_bytes(), signature})?;
+
+    let partner_share: KeyShare = reader.read_frame()?;
+    let partner_share_key = EphemeralKey::from(partner_share.public);
+    if !partner_key.verify_signature(KEY_SHARE_CONTEXT, &share_data(&partner_share_key, &identity.public_key()), &partner_share.signature) {
+        return Err(Error::protocol("the key share of your chat partner is not signed with their key"));
+    }
+    let shared = secret.diffie_hellman(&partner_share_key);
+    if !shared.was_contributory() {
+        return Err(Error::protocol("your chat partner sent a key share that can't be used"));
+    }
+
+    let own = (identity.public_key(), own_share);
+    let partner = (partner_key, partner_share_key);
+    let ((master_key, master_share), (connector_key, connector_share)) = match role {
+        Role::Master => (own, partner),
+        Role::Connector => (partner, own)
+    };
+    let mut info = KEY_INFO.to_vec();
+    for part in [master_key.as_bytes(), connector_key.as_bytes(), master_share.as_bytes(), connector_share.as_bytes()] {
+        info.extend_from_slice(part);
+    }
+    let mut keys = [0u8; 64];
+    Hkdf::<Sha256>::new(None, shared.as_bytes()).expand(&info, &mut keys).expect("64 bytes are a valid HKDF-SHA256 output length");
+    let mut root_key = [0u8; 32];
+    let mut initial_chain = [0u8; 32];
+    root_key.copy_from_slice(&keys[..32]);
+    initial_chain.copy_from_slice(&keys[32..]);
+    keys.zeroize();
+
+    let mut associated_data = master_key.as_bytes().to_vec();
+    associated_data.extend_from_slice(connector_key.as_bytes());
+    let ratchet = match role {
+        Role::Master => Ratchet::master(root_key, initial_chain, partner_share_key, associated_data)?,
+        Role::Connector => Ratchet::connector(root_key, initial_chain, secret, partner_share_key, associated_data)
+    };
+    root_key.zeroize();
+    initial_chain.zeroize();
+    let ratchet = Arc::new(Mutex::new(ratchet));
+    Ok((SecureWriter{stream, ratchet: Arc::clone(&ratchet)}, SecureReader{reader, ratchet}))
+}
+
+fn share_data(share: &EphemeralKey, recipient: &PublicKey) -> Vec<u8> {
+    let mut data = share.as_bytes().to_vec();
+    data.extend_from_slice(recipient.as_bytes());
+    data
+}
+
+/// Encrypting half of the link.
+/// The ratchet is shared with the reader, receiving a new ratchet key changes the one we send with.
+pub struct SecureWriter<W> {
+    stream: W,
+    ratchet: Arc<Mutex<Ratchet>>
+}
+
+impl<W: Write> SecureWriter<W> {
+    pub fn send(&mut self, message: &PeerMessage) -> Result<()> {
+        let plaintext = bincode::serialize(message).map_err(Error::Encode)?;
+        let message = self.ratchet.lock().unwrap().encrypt(&plaintext)?;
+        codec::write_frame(&mut self.stream, &message)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.stream
+    }
+}
+
+/// Decrypting half of the link.
+pub struct SecureReader<R> {
+    reader: FrameReader<R>,
+    ratchet: Arc<Mutex<Ratchet>>
+}
+
+impl<R: Read> SecureReader<R> {
+    /// Like FrameReader::read_frame, a timeout keeps what arrived so far for the next call.
+    pub fn receive(&mut self) -> Result<PeerMessage> {
+        let message: RatchetMessage = self.reader.read_frame()?;
+        let plaintext = self.ratchet.lock().unwrap().decrypt(&message)?;
+        bincode::deserialize(&plaintext).map_err(Error::Decode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+
+    /// One end of an in-memory link, its clones share it like those of a TcpStream do.
+    #[derive(Clone)]
+    struct End {
+        incoming: Arc<Mutex<Incoming>>,
+        outgoing: Sender<Vec<u8>>
+    }
+
+    struct Incoming {
+        receiver: Receiver<Vec<u8>>,
+        /// What was received but not read yet
+        pending: Vec<u8>
+    }
+
+    impl Read for End {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Incoming{receiver, pending} = &mut *self.incoming.lock().unwrap();
+            if pending.is_empty() {
+                match receiver.recv() {
+                    Ok(bytes) => *pending = bytes,
+                    // the other end is gone
+                    Err(_) => return Ok(0)
+                }
+            }
+            let size = buf.len().min(pending.len());
+            buf[..size].copy_from_slice(&pending[..size]);
+            pending.drain(..size);
+            Ok(size)
+        }
+    }
+
+    impl Write for End {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn link() -> (End, End) {
+        let (to_connector, from_master) = mpsc::channel();
+        let (to_master, from_connector) = mpsc::channel();
+        let end = |receiver, outgoing| End{incoming: Arc::new(Mutex::new(Incoming{receiver, pending: Vec::new()})), outgoing};
+        (end(from_connector, to_connector), end(from_master, to_master))
+    }
+
+    /// Runs the connector's side on a thread of its own, it signs its key share with `signer`
+    /// and addresses it to `addressee`.
+    fn connect(end: End, signer: Identity, addressee: PublicKey) -> thread::JoinHandle<Result<(SecureWriter<End>, SecureReader<End>)>> {
+        thread::spawn(move || establish(end.clone(), FrameReader::new(end), &signer, addressee, Role::Connector))
+    }
+
+    #[test]
+    fn both_ends_agree_on_the_keys() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let (master_end, connector_end) = link();
+        let bob_key = bob.public_key();
+        let connector = connect(connector_end, bob, alice.public_key());
+        let (mut master_writer, mut master_reader) = establish(master_end.clone(), FrameReader::new(master_end), &alice, bob_key, Role::Master).unwrap();
+        let (mut connector_writer, mut connector_reader) = connector.join().unwrap().unwrap();
+
+        for round in 0..3 {
+            master_writer.send(&PeerMessage::Nick(format!("alice{}", round))).unwrap();
+            assert_eq!(connector_reader.receive().unwrap(), PeerMessage::Nick(format!("alice{}", round)));
+            connector_writer.send(&PeerMessage::Nick(format!("bob{}", round))).unwrap();
+            connector_writer.send(&PeerMessage::Ping).unwrap();
+            assert_eq!(master_reader.receive().unwrap(), PeerMessage::Nick(format!("bob{}", round)));
+            assert_eq!(master_reader.receive().unwrap(), PeerMessage::Ping);
+        }
+    }
+
+    #[test]
+    fn key_shares_signed_by_anyone_but_the_partner_are_refused() {
+        let (alice, bob, mallory) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let (master_end, connector_end) = link();
+        let connector = connect(connector_end, mallory, alice.public_key());
+
+        let established = establish(master_end.clone(), FrameReader::new(master_end), &alice, bob.public_key(), Role::Master);
+        assert!(matches!(established, Err(Error::Protocol(_))));
+        let _ = connector.join().unwrap();
+    }
+
+    #[test]
+    fn key_shares_meant_for_someone_else_are_refused() {
+        let (alice, bob, carol) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let (master_end, connector_end) = link();
+        let bob_key = bob.public_key();
+        // bob's share for carol, passed on to alice
+        let connector = connect(connector_end, bob, carol.public_key());
+
+        let established = establish(master_end.clone(), FrameReader::new(master_end), &alice, bob_key, Role::Master);
+        assert!(matches!(established, Err(Error::Protocol(_))));
+        assert!(connector.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Optional TLS towards the discovery server.
+//!
+//! With a pinned certificate nothing but exactly that certificate is accepted, so a self-signed
+//! one works without any CA. Otherwise the server needs a certificate from one of the public CAs
+//! webpki-roots knows about.
+//!
+//! The connection is read by one thread and written by others, which a rustls stream can't do on
+//! its own. Both halves share the TLS state behind a mutex and use their own clone of the socket.
+//! The reader only takes the lock once bytes arrived, so waiting for the server never holds up writers.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, DigitallySignedStruct, SignatureScheme, CertificateError};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, WebPkiSupportedAlgorithms};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls_pki_types::pem::PemObject;
+use common::{Error, Result};
+
+/// How many bytes the reader takes from the socket at once.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Accepts nothing but the pinned certificate if there is one, otherwise the public CAs
+/// if `public_cas` is set. None means plain TCP.
+pub fn client_config(pinned_cert: Option<&Path>, public_cas: bool) -> Result<Option<Arc<ClientConfig>>> {
+    if let Some(path) = pinned_cert {
+        return pinned_config(path).map(Some);
+    }
+    if !public_cas {
+        return Ok(None);
+    }
+    let roots = RootCertStore{roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()};
+    Ok(Some(Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth())))
+}
+
+fn pinned_config(path: &Path) -> Result<Arc<ClientConfig>> {
+    let certificate = CertificateDer::from_pem_file(path)
+        .map_err(|e| Error::Protocol(format!("cannot read the pinned certificate from {}: {}", path.display(), e)))?;
+    let algorithms = crypto::ring::default_provider().signature_verification_algorithms;
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate{certificate, algorithms}))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Accepts the one certificate it was given and nothing else. Names and expiry don't matter,
+/// the pin says more about the server than either of them. The handshake signatures are still
+/// checked, so only whoever holds the certificate's key gets through.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer, _intermediates: &[CertificateDer], _server_name: &ServerName,
+                          _ocsp_response: &[u8], _now: UnixTime) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer, signature: &DigitallySignedStruct)
+                              -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, certificate, signature, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer, signature: &DigitallySignedStruct)
+                              -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, certificate, signature, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Runs the TLS handshake on the socket and splits it up.
+/// `address` is what we connected to, its host part is the name the certificate has to be for.
+pub fn connect(mut socket: TcpStream, address: &str, config: Arc<ClientConfig>) -> Result<(TlsReader, TlsWriter)> {
+    let server_name = server_name(address)?;
+    let mut tls = ClientConnection::new(config, server_name).map_err(|e| Error::Protocol(format!("TLS: {}", e)))?;
+    while tls.is_handshaking() {
+        tls.complete_io(&mut socket).map_err(tls_error)?;
+    }
+    let tls = Arc::new(Mutex::new(tls));
+    Ok((TlsReader{socket: socket.try_clone()?, tls: Arc::clone(&tls)}, TlsWriter{socket, tls}))
+}
+
+/// IP addresses are taken as they are, IPv6 ones come in brackets like [::1]:3333.
+fn server_name(address: &str) -> Result<ServerName<'static>> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(ServerName::IpAddress(address.ip().into()));
+    }
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    ServerName::try_from(host.to_string()).map_err(|e| Error::Protocol(format!("{} is not a valid server name: {}", host, e)))
+}
+
+/// rustls reports its own errors as InvalidData, they are nothing the peer could recover from.
+fn tls_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData => Error::Protocol(format!("TLS: {}", e)),
+        _ => e.into()
+    }
+}
+
+pub struct TlsReader {
+    socket: TcpStream,
+    tls: Arc<Mutex<ClientConnection>>
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            match self.tls.lock().unwrap().reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result
+            }
+            // a read timeout ends up here as well and leaves the TLS state as it was
+            let size = self.socket.read(&mut chunk)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            let mut tls = self.tls.lock().unwrap();
+            let mut received = &chunk[..size];
+            while !received.is_empty() {
+                tls.read_tls(&mut received)?;
+                tls.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            // alerts and key updates may want an answer
+            while tls.wants_write() {
+                tls.write_tls(&mut self.socket)?;
+            }
+        }
+    }
+}
+
+pub struct TlsWriter {
+    socket: TcpStream,
+    tls: Arc<Mutex<ClientConnection>>
+}
+
+impl TlsWriter {
+    /// Tells the server we are done, so it can tell a hang up from a cut connection.
+    pub fn close(&mut self) -> io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        tls.send_close_notify();
+        while tls.wants_write() {
+            tls.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tls = self.tls.lock().unwrap();
+        let size = tls.writer().write(buf)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut self.socket)?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn certificates_are_checked_for_the_host_without_port_or_brackets() {
+        let ip = |address: IpAddr| ServerName::IpAddress(address.into());
+        assert_eq!(server_name("[::1]:3333").unwrap(), ip(Ipv6Addr::LOCALHOST.into()));
+        assert_eq!(server_name("127.0.0.1:3333").unwrap(), ip(Ipv4Addr::LOCALHOST.into()));
+        assert_eq!(server_name("chat.example.org:3333").unwrap(), ServerName::try_from("chat.example.org").unwrap());
+        assert!(server_name("not a host:3333").is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use console::{Term, Style};
 use std::sync::Mutex;
+use crate::config::Colors;
 
 pub struct UI {
     console: Term,
+    colors: Colors,
     write_index: usize,
     max_row: usize,
     position: Mutex<usize>
@@ -11,11 +13,15 @@ pub struct UI {
 pub fn create_ui() -> UI {
     let crate_term = Term::stdout();
     let (rows, _) = crate_term.size();
-    let pos = Mutex::new(0 as usize);
-    UI{console: crate_term, write_index: 0, max_row: rows as usize, position: pos}
+    let pos = Mutex::new(0usize);
+    UI{console: crate_term, colors: Colors::default(), write_index: 0, max_row: rows as usize, position: pos}
 }
 
 impl UI {
+    pub fn set_colors(&mut self, colors: Colors) {
+        self.colors = colors;
+    }
+
     pub fn clear_screen_and_reset_cursor(&self) {
         self.console.clear_screen().unwrap();
         self.console.move_cursor_to(0, self.max_row).unwrap();
@@ -31,11 +37,11 @@ impl UI {
 
     fn write_string_to_console(&mut self, message: &str, style: Style) {
         // not entirely sure i still need that lock...maybe in chat rooms
-        let lock = self.position.lock().unwrap();
+        let _lock = self.position.lock().unwrap();
         self.console.move_cursor_to(0, self.write_index).unwrap();
         self.console.write_line(format!("{}", style.apply_to(message)).as_str()).unwrap();
         // is that in regular win10 cmd needed?
-        self.write_index = self.write_index + 1;
+        self.write_index += 1;
         if self.write_index >= self.max_row {
             self.console.clear_line().unwrap();
             self.console.move_cursor_to(0, self.write_index + 1).unwrap();
@@ -45,25 +51,33 @@ impl UI {
         }
     }
 
-    pub fn reset_input_line(&self) {
-        self.console.move_cursor_to(0, self.write_index - 1).unwrap();
-        self.console.clear_line().unwrap();
-        self.move_to_input_pos(); // TODO: Wrong if we are beyond initial max_row
-    }
-
     pub fn read_line(&self) -> String {
         self.console.read_line().unwrap()
     }
 
+    /// Doesn't echo what is typed.
+    pub fn read_secure_line(&self) -> String {
+        self.console.read_secure_line().unwrap()
+    }
+
     pub fn write_sys_message(&mut self, message: &str) {
-        self.write_string_to_console(message, Style::new().green());
+        let style = self.colors.system.clone();
+        self.write_string_to_console(message, style);
     }
 
     pub fn write_info_message(&mut self, message: &str) {
-        self.write_string_to_console(message, Style::new().yellow());
+        let style = self.colors.chat.clone();
+        self.write_string_to_console(message, style);
+    }
+
+    /// Chat style, dimmed.
+    pub fn write_history_message(&mut self, message: &str) {
+        let style = self.colors.chat.clone().dim();
+        self.write_string_to_console(message, style);
     }
 
     pub fn write_err_message(&mut self, message: &str) {
-        self.write_string_to_console(message, Style::new().red());
+        let style = self.colors.error.clone();
+        self.write_string_to_console(message, style);
     }
 }
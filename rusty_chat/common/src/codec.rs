@@ -0,0 +1,227 @@
+//! Length-prefixed framing for everything we send over a TcpStream.
+//!
+//! Every frame is a 4 byte big endian length header followed by exactly that many bytes of
+//! bincode payload. TCP is free to coalesce or split our writes, so readers buffer incoming
+//! bytes until a whole frame is available instead of hoping one `read` returns one value.
+
+use std::io::{self, Read, Write};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::error::{Error, Result};
+
+/// Size of the length header in front of every frame.
+pub const HEADER_SIZE: usize = 4;
+
+/// Largest payload we are willing to buffer for a single frame.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// How many bytes FrameReader asks the underlying reader for at once.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Serializes the value and puts the length header in front of it.
+pub fn encode_frame<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(value).map_err(Error::Encode)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(too_large(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+fn too_large(size: usize) -> Error {
+    Error::Protocol(format!("frame of {} bytes exceeds the maximum of {} bytes", size, MAX_FRAME_SIZE))
+}
+
+/// Writes one complete frame. The frame goes out in a single write_all so two threads
+/// sharing a cloned stream don't interleave their headers and payloads.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let frame = encode_frame(value)?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Collects raw bytes and hands out complete frames.
+/// Doesn't do any I/O itself, so it works for blocking and non-blocking readers alike.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder{buffer: Vec::new()}
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// True if there are no buffered bytes of a partially received frame.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the next frame, or None if it has not fully arrived yet.
+    /// A frame that fails to decode is still consumed, so the stream stays in sync.
+    pub fn next_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..HEADER_SIZE]);
+        let size = u32::from_be_bytes(header) as usize;
+        if size > MAX_FRAME_SIZE {
+            return Err(too_large(size));
+        }
+        if self.buffer.len() < HEADER_SIZE + size {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = self.buffer.drain(..HEADER_SIZE + size).collect();
+        bincode::deserialize(&frame[HEADER_SIZE..]).map(Some).map_err(Error::Decode)
+    }
+}
+
+/// Blocking frame reader on top of any Read implementation.
+/// Has to live as long as the stream it reads from, otherwise buffered bytes of the next frame get lost.
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: FrameDecoder
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> FrameReader<R> {
+        FrameReader{reader, decoder: FrameDecoder::new()}
+    }
+
+    /// Reads until one complete frame is available.
+    /// If the reader has a timeout and it expires, Error::Timeout is returned but the bytes
+    /// received so far are kept for the next call.
+    pub fn read_frame<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(value) = self.decoder.next_frame()? {
+                return Ok(value);
+            }
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    if self.decoder.is_empty() {
+                        return Err(Error::PeerGone);
+                    }
+                    return Err(Error::protocol("connection closed in the middle of a frame"));
+                },
+                Ok(size) => self.decoder.extend(&chunk[..size]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Hands out the scripted reads one by one, then end of stream.
+    struct ScriptedReader {
+        reads: VecDeque<io::Result<Vec<u8>>>
+    }
+
+    impl Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                },
+                Some(Err(e)) => Err(e),
+                None => Ok(0)
+            }
+        }
+    }
+
+    fn scripted(reads: Vec<io::Result<Vec<u8>>>) -> FrameReader<ScriptedReader> {
+        FrameReader::new(ScriptedReader{reads: reads.into()})
+    }
+
+    fn frame(text: &str) -> Vec<u8> {
+        encode_frame(&text.to_string()).unwrap()
+    }
+
+    #[test]
+    fn frames_split_anywhere_are_put_back_together() {
+        let bytes = frame("hello there");
+        let mut decoder = FrameDecoder::new();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert_eq!(decoder.next_frame::<String>().unwrap(), None);
+        }
+        decoder.extend(&bytes[bytes.len() - 1..]);
+        assert_eq!(decoder.next_frame::<String>().unwrap().as_deref(), Some("hello there"));
+        assert!(decoder.is_empty());
+
+        let mut reader = scripted(bytes.iter().map(|byte| Ok(vec![*byte])).collect());
+        assert_eq!(reader.read_frame::<String>().unwrap(), "hello there");
+    }
+
+    #[test]
+    fn coalesced_frames_come_out_one_at_a_time() {
+        let mut bytes = frame("one");
+        bytes.extend(frame("two"));
+        let third = frame("three");
+        bytes.extend(&third[..3]);
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.next_frame::<String>().unwrap().as_deref(), Some("one"));
+        assert_eq!(decoder.next_frame::<String>().unwrap().as_deref(), Some("two"));
+        assert_eq!(decoder.next_frame::<String>().unwrap(), None);
+        assert!(!decoder.is_empty());
+        decoder.extend(&third[3..]);
+        assert_eq!(decoder.next_frame::<String>().unwrap().as_deref(), Some("three"));
+
+        let mut reader = scripted(vec![Ok(bytes), Ok(third[3..].to_vec())]);
+        for text in ["one", "two", "three"] {
+            assert_eq!(reader.read_frame::<String>().unwrap(), text);
+        }
+        assert!(matches!(reader.read_frame::<String>(), Err(Error::PeerGone)));
+    }
+
+    #[test]
+    fn oversize_frames_are_refused_both_ways() {
+        assert!(matches!(encode_frame(&vec![0u8; MAX_FRAME_SIZE]), Err(Error::Protocol(_))));
+        assert!(encode_frame(&vec![0u8; MAX_FRAME_SIZE - 8]).is_ok());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(decoder.next_frame::<String>(), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn undecodable_frames_are_skipped_and_timeouts_keep_what_arrived() {
+        let mut bytes = encode_frame(&[0xffu8; 4]).unwrap();
+        bytes.extend(frame("next"));
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert!(matches!(decoder.next_frame::<String>(), Err(Error::Decode(_))));
+        assert_eq!(decoder.next_frame::<String>().unwrap().as_deref(), Some("next"));
+
+        let bytes = frame("slow");
+        let mut reader = scripted(vec![Ok(bytes[..5].to_vec()), Err(io::ErrorKind::WouldBlock.into()), Ok(bytes[5..].to_vec())]);
+        assert!(matches!(reader.read_frame::<String>(), Err(Error::Timeout)));
+        assert_eq!(reader.read_frame::<String>().unwrap(), "slow");
+
+        let mut reader = scripted(vec![Ok(bytes[..5].to_vec())]);
+        assert!(matches!(reader.read_frame::<String>(), Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,70 @@
+use std::error;
+use std::fmt;
+use std::io;
+use crossbeam_channel::SendError;
+
+/// Everything that can go wrong while talking to the discovery server or another client.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying stream failed.
+    Io(io::Error),
+    /// We received bytes that are not a valid encoding of what we expected.
+    Decode(bincode::Error),
+    /// A value could not be serialized.
+    Encode(bincode::Error),
+    /// The peer sent something that is well-formed but not allowed at this point.
+    Protocol(String),
+    /// The peer did not answer in time.
+    Timeout,
+    /// The peer closed the connection or the channel to it is gone.
+    PeerGone
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn protocol<S: Into<String>>(reason: S) -> Error {
+        Error::Protocol(reason.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Decode(e) => write!(f, "received malformed data: {}", e),
+            Error::Encode(e) => write!(f, "failed to encode data: {}", e),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::Timeout => write!(f, "timed out"),
+            Error::PeerGone => write!(f, "connection closed by peer")
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Decode(e) | Error::Encode(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            // the peer hung up on us, as far as we are concerned that's no different from a clean close
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::NotConnected | io::ErrorKind::UnexpectedEof => Error::PeerGone,
+            _ => Error::Io(e)
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Self {
+        Error::PeerGone
+    }
+}
@@ -0,0 +1,89 @@
+//! Version and capability negotiation.
+//!
+//! This is the first exchange on every connection, discovery server and P2P link alike:
+//! the connecting side sends a Hello, the accepting side answers with a HandshakeReply.
+//! Everything after that may change between protocol versions, these two types may not.
+
+use std::fmt;
+use std::ops::BitOr;
+
+/// Bump this whenever the encoding of anything sent after the handshake changes.
+pub const PROTOCOL_VERSION: u16 = 19;
+
+/// Set of optional features a peer supports.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 1);
+    pub const ROOMS: Capabilities = Capabilities(1 << 2);
+    pub const FILE_TRANSFER: Capabilities = Capabilities(1 << 3);
+
+    const NAMES: [(Capabilities, &'static str); 4] = [
+        (Capabilities::COMPRESSION, "compression"),
+        (Capabilities::ENCRYPTION, "encryption"),
+        (Capabilities::ROOMS, "rooms"),
+        (Capabilities::FILE_TRANSFER, "file transfer")
+    ];
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Capabilities::NAMES.iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub capabilities: Capabilities
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Hello {
+        Hello{protocol_version: PROTOCOL_VERSION, capabilities}
+    }
+
+    /// Checks whether we can talk to the peer that sent `peer`.
+    /// Returns the capabilities both sides support, or a reason that can be shown to the user.
+    pub fn negotiate(&self, peer: &Hello) -> Result<Capabilities, String> {
+        if self.protocol_version != peer.protocol_version {
+            return Err(format!("protocol version {} is not supported, version {} is required",
+                               peer.protocol_version, self.protocol_version));
+        }
+        Ok(self.capabilities.intersection(peer.capabilities))
+    }
+}
+
+/// Answer of the accepting side. Accepted carries the negotiated capabilities.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum HandshakeReply {
+    Accepted(Hello),
+    Rejected(String)
+}
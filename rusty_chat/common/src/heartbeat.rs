@@ -0,0 +1,70 @@
+//! Noticing peers that silently went away.
+//!
+//! Both sides of a connection send a ping every `interval` and answer pings with a pong.
+//! Reads time out after `timeout`, so a peer that sends nothing at all for that long is dead.
+
+use std::env;
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{self as channel, Sender, RecvTimeoutError};
+use crate::Result;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration
+}
+
+impl HeartbeatConfig {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+
+    /// Reads RUSTY_CHAT_HEARTBEAT_INTERVAL and RUSTY_CHAT_HEARTBEAT_TIMEOUT (in seconds),
+    /// falling back to the defaults for anything missing or unparsable.
+    pub fn from_env() -> HeartbeatConfig {
+        let interval = seconds_from_env("RUSTY_CHAT_HEARTBEAT_INTERVAL").unwrap_or(HeartbeatConfig::DEFAULT_INTERVAL);
+        let timeout = seconds_from_env("RUSTY_CHAT_HEARTBEAT_TIMEOUT").unwrap_or(HeartbeatConfig::DEFAULT_TIMEOUT);
+        // a timeout shorter than the interval would kill every idle connection
+        HeartbeatConfig{interval, timeout: timeout.max(interval * 2)}
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig{interval: HeartbeatConfig::DEFAULT_INTERVAL, timeout: HeartbeatConfig::DEFAULT_TIMEOUT}
+    }
+}
+
+fn seconds_from_env(name: &str) -> Option<Duration> {
+    env::var(name).ok()?.parse().ok().filter(|seconds| *seconds > 0).map(Duration::from_secs)
+}
+
+/// Thread that pings the peer every interval. Stops when dropped or once a ping can't be sent.
+pub struct Pinger {
+    stop: Option<Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl Pinger {
+    pub fn spawn<F: FnMut() -> Result<()> + Send + 'static>(interval: Duration, mut send_ping: F) -> Pinger {
+        let (stop, stopped) = channel::bounded::<()>(0);
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if send_ping().is_err() {
+                    break;
+                }
+            }
+        });
+        Pinger{stop: Some(stop), thread: Some(thread)}
+    }
+}
+
+impl Drop for Pinger {
+    /// Waits for the thread, so whatever send_ping captured is gone once this returns.
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
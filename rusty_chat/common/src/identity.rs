@@ -0,0 +1,260 @@
+//! Ed25519 identities and proving them.
+//!
+//! Clients and the discovery server each own a key pair that is kept on disk, so it stays the
+//! same across restarts. Right after the handshake both sides of a connection send an
+//! IdentityChallenge carrying their public key and a fresh nonce, then answer the other side's
+//! nonce with an IdentityProof. A proof only checks out for whoever holds the private key.
+//! Proofs name both keys and the role of the prover, and logging in at the server signs under
+//! another context than direct chats do, so a proof can't be passed on to anybody else.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::{thread_rng, Rng};
+use crate::codec::{self, FrameReader};
+use crate::{Error, Result};
+
+/// Signatures are made over one of these followed by the proven data, so they can't be mistaken for anything else.
+const LOGIN_PROOF_CONTEXT: &[u8] = b"rusty chat login proof v2\0";
+const DIRECT_PROOF_CONTEXT: &[u8] = b"rusty chat direct chat proof v2\0";
+
+/// Which side of a connection proves its identity.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProofRole {
+    /// Logging in at the discovery server
+    Client,
+    Server,
+    /// Listened for the partner of a direct chat
+    Master,
+    /// Connected to the master
+    Connector
+}
+
+impl ProofRole {
+    /// The role of whoever is on the other side.
+    pub fn counterpart(self) -> ProofRole {
+        match self {
+            ProofRole::Client => ProofRole::Server,
+            ProofRole::Server => ProofRole::Client,
+            ProofRole::Master => ProofRole::Connector,
+            ProofRole::Connector => ProofRole::Master
+        }
+    }
+
+    fn context(self) -> &'static [u8] {
+        match self {
+            ProofRole::Client | ProofRole::Server => LOGIN_PROOF_CONTEXT,
+            ProofRole::Master | ProofRole::Connector => DIRECT_PROOF_CONTEXT
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            ProofRole::Client => 0,
+            ProofRole::Server => 1,
+            ProofRole::Master => 2,
+            ProofRole::Connector => 3
+        }
+    }
+}
+
+/// Somebody's Ed25519 public key.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Parses what Display printed, whitespace included.
+    pub fn from_hex(text: &str) -> Option<PublicKey> {
+        let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(digits, &mut bytes).ok()?;
+        Some(PublicKey(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Checks a proof made by the holder of this key, in that role, for the holder of `verifier`.
+    pub fn verify(&self, nonce: &Nonce, verifier: &PublicKey, role: ProofRole, proof: &IdentityProof) -> bool {
+        self.verify_signature(role.context(), &proof_data(nonce, self, verifier, role), &proof.signature)
+    }
+
+    /// Checks a signature made by Identity::sign with the same context.
+    pub fn verify_signature(&self, context: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        let key = match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key,
+            Err(_) => return false
+        };
+        match ed25519_dalek::Signature::from_slice(signature) {
+            Ok(signature) => key.verify_strict(&signed_message(context, data), &signature).is_ok(),
+            Err(_) => false
+        }
+    }
+}
+
+/// Hex in groups of four, short enough to read out to someone.
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = hex::encode(self.0);
+        let groups: Vec<&str> = (0..digits.len()).step_by(4).map(|start| &digits[start..start + 4]).collect();
+        write!(f, "{}", groups.join(" "))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", hex::encode(self.0))
+    }
+}
+
+/// Random bytes the other side has to sign. Never used twice.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct Nonce([u8; 32]);
+
+impl Nonce {
+    pub fn generate() -> Nonce {
+        Nonce(thread_rng().gen())
+    }
+}
+
+/// Who we claim to be and what the other side has to sign to prove who they are.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct IdentityChallenge {
+    pub public_key: PublicKey,
+    pub nonce: Nonce
+}
+
+/// Signature over the other side's nonce.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct IdentityProof {
+    /// 64 bytes, serde doesn't do arrays that long
+    pub signature: Vec<u8>
+}
+
+/// Our own key pair.
+pub struct Identity {
+    signing_key: SigningKey
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity{signing_key: SigningKey::from_bytes(&thread_rng().gen())}
+    }
+
+    /// Reads the secret key from `path`, creating a new identity there if there is none yet.
+    /// The file holds nothing but the secret key in hex and only its owner may read it.
+    pub fn load_or_generate(path: &Path) -> Result<Identity> {
+        let mut text = String::new();
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut text)?;
+                let mut secret = [0u8; 32];
+                hex::decode_to_slice(text.trim(), &mut secret)
+                    .map_err(|e| Error::Protocol(format!("{} does not hold a secret key: {}", path.display(), e)))?;
+                Ok(Identity{signing_key: SigningKey::from_bytes(&secret)})
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut file = create_private_file(path)?;
+                writeln!(file, "{}", hex::encode(identity.signing_key.to_bytes()))?;
+                Ok(identity)
+            },
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn challenge(&self, nonce: Nonce) -> IdentityChallenge {
+        IdentityChallenge{public_key: self.public_key(), nonce}
+    }
+
+    /// Answers the nonce of the holder of `verifier`, who knows us in that role.
+    pub fn prove(&self, nonce: &Nonce, verifier: &PublicKey, role: ProofRole) -> IdentityProof {
+        IdentityProof{signature: self.sign(role.context(), &proof_data(nonce, &self.public_key(), verifier, role))}
+    }
+
+    /// Signs the data for one purpose only. The context tells the purposes apart, so a signature
+    /// made for one of them is worthless for any other.
+    pub fn sign(&self, context: &[u8], data: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(&signed_message(context, data)).to_bytes().to_vec()
+    }
+}
+
+fn proof_data(nonce: &Nonce, prover: &PublicKey, verifier: &PublicKey, role: ProofRole) -> Vec<u8> {
+    let mut data = nonce.0.to_vec();
+    data.extend_from_slice(prover.as_bytes());
+    data.extend_from_slice(verifier.as_bytes());
+    data.push(role.tag());
+    data
+}
+
+fn signed_message(context: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut message = context.to_vec();
+    message.extend_from_slice(data);
+    message
+}
+
+/// Creates a file only its owner may read, for secrets like keys and password hashes.
+/// Fails if there is one already. Other systems get whatever permissions they default to.
+#[cfg(unix)]
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Both sides of a blocking connection call this right after the handshake, each with its own role.
+/// Returns the public key the other side proved to hold.
+pub fn exchange_proofs<W: Write, R: Read>(writer: &mut W, reader: &mut FrameReader<R>, identity: &Identity, role: ProofRole) -> Result<PublicKey> {
+    let own_nonce = Nonce::generate();
+    codec::write_frame(writer, &identity.challenge(own_nonce))?;
+    let challenge: IdentityChallenge = reader.read_frame()?;
+    codec::write_frame(writer, &identity.prove(&challenge.nonce, &challenge.public_key, role))?;
+    let proof: IdentityProof = reader.read_frame()?;
+    if challenge.public_key.verify(&own_nonce, &identity.public_key(), role.counterpart(), &proof) {
+        Ok(challenge.public_key)
+    } else {
+        Err(Error::protocol("the other side could not prove its identity"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_only_check_out_for_the_verifier_and_role_they_were_made_for() {
+        let alice = Identity::generate();
+        let server = Identity::generate();
+        let mallory = Identity::generate();
+        let nonce = Nonce::generate();
+
+        let login = alice.prove(&nonce, &server.public_key(), ProofRole::Client);
+        assert!(alice.public_key().verify(&nonce, &server.public_key(), ProofRole::Client, &login));
+        assert!(!alice.public_key().verify(&Nonce::generate(), &server.public_key(), ProofRole::Client, &login));
+
+        // a server nonce relayed to alice as the challenge of a direct chat gets mallory nowhere
+        for role in [ProofRole::Master, ProofRole::Connector] {
+            let relayed = alice.prove(&nonce, &mallory.public_key(), role);
+            assert!(!alice.public_key().verify(&nonce, &server.public_key(), ProofRole::Client, &relayed));
+        }
+        let relayed = alice.prove(&nonce, &mallory.public_key(), ProofRole::Client);
+        assert!(!alice.public_key().verify(&nonce, &server.public_key(), ProofRole::Client, &relayed));
+        let reflected = alice.prove(&nonce, &server.public_key(), ProofRole::Server);
+        assert!(!alice.public_key().verify(&nonce, &server.public_key(), ProofRole::Client, &reflected));
+    }
+}
@@ -1,92 +1,197 @@
 extern crate bincode;
 extern crate serde;
 extern crate crossbeam_channel;
+extern crate ed25519_dalek;
+extern crate hex;
+extern crate rand;
 #[macro_use] extern crate serde_derive;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct LoginRequest {
-    pub name: String
-}
+pub mod codec;
+pub mod error;
+pub mod handshake;
+pub mod heartbeat;
+pub mod identity;
+pub mod multipart;
+pub mod protocol;
+pub mod validation;
 
-impl LoginRequest {
-    // TODO: call some kind of validate() either at creation or transmission time
-    pub const SIZE: usize = 256;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
+pub use error::{Error, Result};
+use validation::ValidationError;
+use identity::PublicKey;
+use protocol::{Access, RoomRole, RoomSummary, Visibility};
+
+/// Identifies a user for as long as the server runs. Ids are handed out in increasing order and
+/// never reused, so an id that is still lying around can't point to someone who logged in later.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct UserId(u64);
 
-    pub fn to_bytes(&self) -> Vec<u8>{
-        let result: Vec<u8> = bincode::serialize(self).unwrap();
-        result
+impl UserId {
+    pub const FIRST: UserId = UserId(0);
+
+    pub fn next(self) -> UserId {
+        UserId(self.0 + 1)
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct Message {
-    // TODO: Multi-Messages
-    pub message: String
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-impl Message {
-    pub const SIZE: usize = 1024;
+/// Same as UserId, for rooms.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct RoomId(u64);
+
+impl RoomId {
+    pub const FIRST: RoomId = RoomId(0);
+
+    pub fn next(self) -> RoomId {
+        RoomId(self.0 + 1)
+    }
 }
 
-pub struct User {
-    pub id: u8,
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct LoginRequest {
     pub name: String,
-    pub ip_address: String,
-    pub sender: Option<crossbeam_channel::Sender<MasterSelectionResult>>
+    /// None for guests, their name is all there is to them
+    pub password: Option<String>,
+    /// Where we accept direct chats, the partner connects to it if we end up being the master
+    pub p2p_port: u16
 }
 
-impl User {
-    pub const NAME_SIZE: usize = 256;
+impl LoginRequest {
+    pub fn guest(name: String, p2p_port: u16) -> LoginRequest {
+        LoginRequest{name, password: None, p2p_port}
+    }
+
+    /// Only checks the name, see validate_password for the rest.
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        validation::validate_user_name(&self.name)
+    }
+}
 
-    pub fn get_sender(&self) -> Option<crossbeam_channel::Sender<MasterSelectionResult>> {
-        match &self.sender {
-            Some(snd) => {
-                let s = snd.clone();
-                Some(s)
-            },
-            None => None
-        }
+/// Keeps passwords out of logs.
+impl fmt::Debug for LoginRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LoginRequest")
+            .field("name", &self.name)
+            .field("password", &self.password.as_ref().map(|_| "<hidden>"))
+            .field("p2p_port", &self.p2p_port)
+            .finish()
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub enum RemoteMessage {
-    ChatModeMessage(ChatMode),
-    LoginMessage(LoginRequest)
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Message {
+    pub message: String
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+impl Message {
+    /// Longer messages are split into several chunks, see multipart.
+    pub const SIZE: usize = 1024;
+
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        validation::validate_message(&self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 pub enum ChatMode {
     DIRECT,
     ROOM,
     WAIT
 }
 
-impl ChatMode {
-    // TODO: how are enums sized? addendum: i appear to send 4 bytes
-    pub const SIZE: usize = 64;
+#[derive(PartialEq, Debug)]
+pub struct ChatRoom {
+    pub id: RoomId,
+    pub members: Vec<UserId>,
+    pub name: String,
+    pub topic: Option<String>,
+    /// Passwords are only kept in memory, rooms are gone once the server stops
+    pub access: Access,
+    pub visibility: Visibility,
+    /// Key of whoever created the room or had it handed over, see RoomRole. None for the rooms the server starts with
+    pub owner: Option<PublicKey>,
+    pub operators: Vec<PublicKey>,
+    /// Members that can't talk in the room
+    pub muted: Vec<PublicKey>,
+    pub bans: Vec<RoomBan>,
+    /// Expired ones are dropped whenever someone joins or is invited
+    pub invites: Vec<RoomInvite>,
+    /// Recent messages, oldest first, for whoever joins later
+    pub backlog: VecDeque<BacklogEntry>
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct ChatRoom {
-    pub id: u8,
-    pub current_user: u8,
-    pub name: String
+/// A message kept in a room's backlog.
+#[derive(PartialEq, Debug)]
+pub struct BacklogEntry {
+    /// The name the sender had at the time
+    pub sender: String,
+    pub message: Message,
+    pub sent: Instant
+}
+
+/// Lets a user join once, whatever the room's access mode.
+#[derive(PartialEq, Debug)]
+pub struct RoomInvite {
+    pub key: PublicKey,
+    pub expires: Instant
+}
+
+/// Keeps a user out of a room.
+#[derive(PartialEq, Debug)]
+pub struct RoomBan {
+    /// The name the user had when banned, unbanning goes by it
+    pub name: String,
+    pub key: PublicKey,
+    /// Set if everyone from that address is banned as well
+    pub ip_address: Option<String>
 }
 
 impl ChatRoom {
-    // TODO: validate()
-    pub const SIZE: usize = 256;
-    pub const NAME_SIZE: usize = 240;
+    /// Only checks the name, the rest is checked before a room is created.
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        validation::validate_room_name(&self.name)
+    }
+
+    pub fn role(&self, key: &PublicKey) -> RoomRole {
+        if self.owner.as_ref() == Some(key) {
+            RoomRole::Owner
+        } else if self.operators.contains(key) {
+            RoomRole::Operator
+        } else {
+            RoomRole::Member
+        }
+    }
+
+    pub fn is_banned(&self, key: &PublicKey, ip_address: &str) -> bool {
+        self.bans.iter().any(|ban| ban.key == *key || ban.ip_address.as_deref() == Some(ip_address))
+    }
+
+    pub fn summary(&self) -> RoomSummary {
+        RoomSummary{name: self.name.clone(), topic: self.topic.clone(), members: self.members.len(), access: self.access.mode()}
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct MasterSelectionResult {
     pub chat_partner_name: String,
+    /// False if the partner is a guest, anybody could have picked that name
+    pub chat_partner_verified: bool,
+    /// The partner has to prove it holds the matching private key once we are connected
+    pub chat_partner_key: PublicKey,
     pub target_ip: String,
+    pub target_port: u16,
     pub is_own_ip: bool
-}
-
-impl MasterSelectionResult {
-    pub const SIZE: usize = 128;
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+//! Splitting messages into chunks of at most Message::SIZE bytes and putting them back together.
+//!
+//! Every message goes over the P2P link as one or more chunks, short ones simply have a total of 1.
+
+use std::collections::HashMap;
+use crate::{Message, Error, Result};
+use crate::validation::MAX_MESSAGE_BYTES;
+
+pub type MessageId = u32;
+
+/// How many messages may be half received at the same time before we start dropping them.
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct MessageChunk {
+    pub message_id: MessageId,
+    pub index: u16,
+    pub total: u16,
+    pub data: Vec<u8>
+}
+
+/// Splits the message into chunks. Cuts may end up in the middle of a UTF-8 sequence,
+/// the text only has to be valid once it is reassembled.
+pub fn split_message(message_id: MessageId, message: &Message) -> Vec<MessageChunk> {
+    let bytes = message.message.as_bytes();
+    if bytes.is_empty() {
+        return vec![MessageChunk{message_id, index: 0, total: 1, data: Vec::new()}];
+    }
+    let parts: Vec<&[u8]> = bytes.chunks(Message::SIZE).collect();
+    let total = parts.len() as u16;
+    parts.into_iter().enumerate()
+        .map(|(index, data)| MessageChunk{message_id, index: index as u16, total, data: data.to_vec()})
+        .collect()
+}
+
+fn max_chunks() -> usize {
+    MAX_MESSAGE_BYTES.div_ceil(Message::SIZE)
+}
+
+struct PartialMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize
+}
+
+/// Collects chunks until every part of a message has arrived.
+/// Chunks of different messages may arrive interleaved and in any order.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<MessageId, PartialMessage>
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler{partial: HashMap::new()}
+    }
+
+    /// Returns the complete message once the last missing chunk arrived.
+    /// Chunks that don't fit the ones we already have are a protocol error, the message they
+    /// belong to is dropped.
+    pub fn add(&mut self, chunk: MessageChunk) -> Result<Option<Message>> {
+        if chunk.total == 0 || chunk.index >= chunk.total || chunk.total as usize > max_chunks() || chunk.data.len() > Message::SIZE {
+            self.partial.remove(&chunk.message_id);
+            return Err(Error::Protocol(format!("invalid chunk {}/{} of message {}", chunk.index, chunk.total, chunk.message_id)));
+        }
+        if !self.partial.contains_key(&chunk.message_id) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            return Err(Error::protocol("too many incomplete messages"));
+        }
+
+        let total = chunk.total as usize;
+        let partial = self.partial.entry(chunk.message_id)
+            .or_insert_with(|| PartialMessage{chunks: vec![None; total], missing: total});
+        if partial.chunks.len() != total || partial.chunks[chunk.index as usize].is_some() {
+            self.partial.remove(&chunk.message_id);
+            return Err(Error::Protocol(format!("chunk {}/{} does not fit message {}", chunk.index, chunk.total, chunk.message_id)));
+        }
+        partial.chunks[chunk.index as usize] = Some(chunk.data);
+        partial.missing -= 1;
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&chunk.message_id).unwrap();
+        let bytes: Vec<u8> = partial.chunks.into_iter().flatten().flatten().collect();
+        match String::from_utf8(bytes) {
+            Ok(message) => Ok(Some(Message{message})),
+            Err(_) => Err(Error::Protocol(format!("message {} is not valid UTF-8", chunk.message_id)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> Message {
+        Message{message: text.to_string()}
+    }
+
+    /// Three chunks, with a multi-byte character cut in half at the first border.
+    fn long_text() -> String {
+        let mut text = "a".repeat(Message::SIZE - 1);
+        text.push('ä');
+        text.push_str(&"b".repeat(Message::SIZE));
+        text
+    }
+
+    #[test]
+    fn short_and_empty_messages_are_a_single_chunk() {
+        let mut reassembler = Reassembler::new();
+        for text in ["", "hi"] {
+            let chunks = split_message(1, &message(text));
+            assert_eq!(chunks.len(), 1);
+            assert_eq!(reassembler.add(chunks.into_iter().next().unwrap()).unwrap(), Some(message(text)));
+        }
+    }
+
+    #[test]
+    fn chunks_in_any_order_make_up_the_message() {
+        let text = long_text();
+        let mut chunks = split_message(7, &message(&text));
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.data.len() <= Message::SIZE));
+        chunks.reverse();
+        let mut reassembler = Reassembler::new();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(reassembler.add(chunk).unwrap(), None);
+        }
+        assert_eq!(reassembler.add(last).unwrap(), Some(message(&text)));
+    }
+
+    #[test]
+    fn interleaved_messages_are_kept_apart() {
+        let first = long_text();
+        let second = "c".repeat(Message::SIZE + 1);
+        let mut reassembler = Reassembler::new();
+        let mut first_chunks = split_message(1, &message(&first)).into_iter();
+        let mut second_chunks = split_message(2, &message(&second)).into_iter();
+        assert_eq!(reassembler.add(first_chunks.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.add(second_chunks.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.add(first_chunks.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.add(second_chunks.next().unwrap()).unwrap(), Some(message(&second)));
+        assert_eq!(reassembler.add(first_chunks.next().unwrap()).unwrap(), Some(message(&first)));
+    }
+
+    #[test]
+    fn duplicate_and_mismatched_chunks_drop_the_message() {
+        let mut reassembler = Reassembler::new();
+        let chunks = split_message(3, &message(&long_text()));
+        let duplicate = MessageChunk{message_id: 3, index: 0, total: 3, data: chunks[0].data.clone()};
+        assert_eq!(reassembler.add(duplicate).unwrap(), None);
+        assert!(reassembler.add(MessageChunk{message_id: 3, index: 0, total: 3, data: Vec::new()}).is_err());
+        // the message is gone, its remaining chunks start over
+        assert_eq!(reassembler.add(MessageChunk{message_id: 3, index: 1, total: 3, data: Vec::new()}).unwrap(), None);
+        assert!(reassembler.add(MessageChunk{message_id: 3, index: 2, total: 4, data: Vec::new()}).is_err());
+
+        let invalid_utf8 = MessageChunk{message_id: 4, index: 0, total: 1, data: vec![0xff]};
+        assert!(reassembler.add(invalid_utf8).is_err());
+    }
+
+    #[test]
+    fn chunks_beyond_the_limits_are_refused() {
+        let mut reassembler = Reassembler::new();
+        let too_many = (max_chunks() + 1) as u16;
+        assert!(reassembler.add(MessageChunk{message_id: 1, index: 0, total: too_many, data: Vec::new()}).is_err());
+        assert!(reassembler.add(MessageChunk{message_id: 1, index: 0, total: 0, data: Vec::new()}).is_err());
+        assert!(reassembler.add(MessageChunk{message_id: 1, index: 2, total: 2, data: Vec::new()}).is_err());
+        assert!(reassembler.add(MessageChunk{message_id: 1, index: 0, total: 2, data: vec![b'a'; Message::SIZE + 1]}).is_err());
+
+        let longest = "z".repeat(MAX_MESSAGE_BYTES);
+        let chunks = split_message(2, &message(&longest));
+        assert_eq!(chunks.len(), max_chunks());
+        let mut result = None;
+        for chunk in chunks {
+            result = reassembler.add(chunk).unwrap();
+        }
+        assert_eq!(result, Some(message(&longest)));
+
+        for message_id in 0..MAX_PARTIAL_MESSAGES as MessageId {
+            assert_eq!(reassembler.add(MessageChunk{message_id, index: 0, total: 2, data: Vec::new()}).unwrap(), None);
+        }
+        let one_more = MessageChunk{message_id: MAX_PARTIAL_MESSAGES as MessageId, index: 0, total: 2, data: Vec::new()};
+        assert!(reassembler.add(one_more).is_err());
+        assert_eq!(reassembler.add(MessageChunk{message_id: 0, index: 1, total: 2, data: Vec::new()}).unwrap(), Some(message("")));
+    }
+}
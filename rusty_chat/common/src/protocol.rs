@@ -0,0 +1,460 @@
+//! Messages exchanged between clients and the discovery server.
+//!
+//! Every frame on a discovery connection carries exactly one envelope. Clients number their
+//! requests, the server echoes that number on the response so both sides can tell what a
+//! message is answering. Messages the server pushes on its own (e.g. another user picked us
+//! for a chat) carry no request id.
+//!
+//! Both sides ping each other regularly, see heartbeat. Pings are answered with a pong,
+//! neither of them ever reaches the code handling the actual requests.
+//!
+//! Before the first envelope, both sides prove who they are, see identity.
+//!
+//! Once two clients are paired they talk directly and exchange PeerMessages instead, each one
+//! encrypted into a RatchetMessage with keys that start out from the KeyShares.
+
+use std::fmt;
+use crate::{LoginRequest, ChatMode, MasterSelectionResult, Message};
+use crate::validation::ValidationError;
+use crate::codec::MAX_FRAME_SIZE;
+use crate::multipart::MessageChunk;
+
+pub type RequestId = u32;
+
+/// Client -> server
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum ClientMessage {
+    /// Logs in as a guest without a password, otherwise with the account of that name
+    Login(LoginRequest),
+    /// Creates an account and logs in with it, the password is required
+    Register(LoginRequest),
+    SelectChatMode(ChatMode),
+    /// Name of the user we want to chat with
+    ChatRequest(String),
+    /// We leave the room we are in. The password is only needed for rooms that have one and we are not invited to
+    JoinRoom{name: String, password: Option<String>},
+    /// Creates a room owned by us and joins it
+    CreateRoom(NewRoom),
+    /// Only the owner may delete or rename a room or change its topic, the rooms are given by name
+    DeleteRoom(String),
+    RenameRoom{name: String, new_name: String},
+    /// None clears the topic. Operators may change it as well
+    SetTopic{room: String, topic: Option<String>},
+    /// Operators and the owner keep order in the room
+    Moderate{room: String, action: Moderation},
+    /// Lets the user of that name into our room, whatever its access mode
+    Invite{room: String, name: String},
+    LeaveRoom,
+    /// Goes to everyone else in our room, Rejected is pushed if it went nowhere
+    RoomMessage(Message),
+    /// Name we want to be known by from now on
+    Rename(String),
+    Ping,
+    Pong
+}
+
+impl ClientMessage {
+    /// Whether the server responds to this, everything it leaves unanswered is pushed if need be.
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, ClientMessage::RoomMessage(_) | ClientMessage::SelectChatMode(ChatMode::WAIT) | ClientMessage::Pong)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ClientEnvelope {
+    pub request_id: RequestId,
+    pub message: ClientMessage
+}
+
+/// Server -> client
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum ServerMessage {
+    /// Name the user is known by from now on, verified unless it logged in as a guest
+    LoggedIn{name: String, verified: bool},
+    /// At most UserSummary::MAX_LISTED users, along with how many more there are.
+    /// The ones left out can be asked for by name all the same
+    UserList{users: Vec<UserSummary>, unlisted: usize},
+    /// Public rooms only, unlisted ones can be joined by name all the same.
+    /// At most RoomSummary::MAX_LISTED rooms, along with how many more public ones there are
+    RoomList{rooms: Vec<RoomSummary>, unlisted: usize},
+    /// Response to JoinRoom and CreateRoom. Members are listed in the order they joined, us included,
+    /// at most UserSummary::MAX_LISTED of them along with how many more there are.
+    /// The history is what was said in there lately, oldest first
+    JoinedRoom{room: String, topic: Option<String>, members: Vec<UserSummary>, unlisted_members: usize, history: Vec<HistoryMessage>},
+    /// Response to LeaveRoom, the name of the room we left.
+    /// Also pushed to the members of a room that is deleted
+    LeftRoom(String),
+    /// Response to DeleteRoom
+    RoomDeleted(String),
+    /// Response to RenameRoom
+    RoomRenamed{old_name: String, new_name: String},
+    /// Response to SetTopic
+    TopicSet{room: String, topic: Option<String>},
+    /// Response to Moderate, everyone else in the room gets a RoomEvent
+    Moderated(Moderation),
+    /// Response to Invite
+    InviteSent{room: String, name: String},
+    /// Somebody invited us, the invite is good for joining once within that many seconds
+    Invited{room: String, by: String, expires_in_secs: u64},
+    /// Somebody else in our room said something
+    RoomMessage{sender: String, message: Message},
+    /// Something happened in our room
+    RoomEvent(RoomEvent),
+    MasterSelection(MasterSelectionResult),
+    /// Response to Rename, also pushed to everyone else so they can update their screens
+    Renamed{old_name: String, new_name: String},
+    /// The request was refused because of what it contained
+    Rejected(Rejection),
+    /// The request could not be processed, the text is meant to be shown to the user
+    Error(String),
+    Ping,
+    Pong
+}
+
+/// Something said in a room before we joined it.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct HistoryMessage {
+    pub sender: String,
+    pub message: Message,
+    /// How long ago it was said, clocks of client and server need not agree
+    pub age_secs: u64
+}
+
+impl HistoryMessage {
+    /// What the envelope, the message tag and the length of the history add to a JoinedRoom frame, with room to spare.
+    const FRAME_OVERHEAD: usize = 64;
+
+    /// How much of a JoinedRoom frame is left for the history once the rest of it is encoded.
+    pub fn budget(room: &str, topic: &Option<String>, members: &[UserSummary], unlisted_members: usize) -> usize {
+        let rest = bincode::serialized_size(&(room, topic, members, unlisted_members)).map_or(usize::MAX, |size| size as usize);
+        (MAX_FRAME_SIZE - Self::FRAME_OVERHEAD).saturating_sub(rest)
+    }
+
+    /// What it adds to the encoded history.
+    pub fn encoded_size(&self) -> usize {
+        bincode::serialized_size(self).map_or(usize::MAX, |size| size as usize)
+    }
+}
+
+/// What others get to know about a user.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct UserSummary {
+    pub name: String,
+    /// False for guests
+    pub verified: bool
+}
+
+impl UserSummary {
+    /// Even with the longest names a UserList, or the members of a JoinedRoom, this long stays well within a frame.
+    pub const MAX_LISTED: usize = 1000;
+}
+
+impl fmt::Display for UserSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.verified {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} (guest)", self.name)
+        }
+    }
+}
+
+/// Who gets to see a room in the room list.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum Visibility {
+    Public,
+    /// Left out of the room list, whoever knows the name can join anyway
+    Unlisted
+}
+
+/// Who may join a room. The owner and whoever got invited always may.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub enum Access {
+    Open,
+    Password(String),
+    InviteOnly
+}
+
+impl Access {
+    pub fn mode(&self) -> AccessMode {
+        match self {
+            Access::Open => AccessMode::Open,
+            Access::Password(_) => AccessMode::Password,
+            Access::InviteOnly => AccessMode::InviteOnly
+        }
+    }
+}
+
+/// Keeps passwords out of logs.
+impl fmt::Debug for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.mode())
+    }
+}
+
+/// Access without the password, what everybody may know about a room.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum AccessMode {
+    Open,
+    Password,
+    InviteOnly
+}
+
+/// Everything a room is created with.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct NewRoom {
+    pub name: String,
+    pub topic: Option<String>,
+    pub access: Access,
+    pub visibility: Visibility
+}
+
+impl NewRoom {
+    /// A room without topic that anyone can find and join.
+    pub fn public(name: String) -> NewRoom {
+        NewRoom{name, topic: None, access: Access::Open, visibility: Visibility::Public}
+    }
+}
+
+/// What the room list tells about a room.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct RoomSummary {
+    pub name: String,
+    pub topic: Option<String>,
+    /// How many users are in the room
+    pub members: usize,
+    pub access: AccessMode
+}
+
+impl RoomSummary {
+    /// Even with the longest names and topics a RoomList this long stays well within a frame.
+    pub const MAX_LISTED: usize = 150;
+}
+
+impl fmt::Display for RoomSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let members = if self.members == 1 { String::from("1 member") } else { format!("{} members", self.members) };
+        let access = match self.access {
+            AccessMode::Open => "",
+            AccessMode::Password => ", password",
+            AccessMode::InviteOnly => ", invite only"
+        };
+        match &self.topic {
+            Some(topic) => write!(f, "{} ({}{}): {}", self.name, members, access, topic),
+            None => write!(f, "{} ({}{})", self.name, members, access)
+        }
+    }
+}
+
+/// Ranks of the members of a room, each one may do everything the ones below may.
+/// Operators kick, ban, mute and set the topic, the owner also hands out roles, renames and deletes the room.
+/// Nobody can moderate anyone of the same or a higher rank.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum RoomRole {
+    Member,
+    Operator,
+    Owner
+}
+
+impl fmt::Display for RoomRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoomRole::Member => write!(f, "member"),
+            RoomRole::Operator => write!(f, "operator"),
+            RoomRole::Owner => write!(f, "owner")
+        }
+    }
+}
+
+/// What operators can do to other users in a room, each aimed at a user by name.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Moderation {
+    Kick(String),
+    /// Keeps the user's identity out, and everyone using the same IP address if `by_ip`
+    Ban{name: String, by_ip: bool},
+    /// Goes by the name the user had when banned
+    Unban(String),
+    Mute(String),
+    Unmute(String),
+    /// Owner only. Making someone else the owner hands the room over, the old owner becomes an operator
+    SetRole{name: String, role: RoomRole}
+}
+
+impl Moderation {
+    /// Name of the user the action is aimed at.
+    pub fn target(&self) -> &str {
+        match self {
+            Moderation::Kick(name) | Moderation::Ban{name, ..} | Moderation::Unban(name)
+            | Moderation::Mute(name) | Moderation::Unmute(name) | Moderation::SetRole{name, ..} => name
+        }
+    }
+}
+
+/// Pushed to the members of a room. Whoever caused the event gets a response instead.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum RoomEvent {
+    Joined(UserSummary),
+    /// Name of whoever left
+    Left(String),
+    /// The owner's name along with the new topic, None if it was cleared
+    TopicChanged{by: String, topic: Option<String>},
+    Renamed{by: String, new_name: String},
+    /// Everyone is out of the room, a LeftRoom follows
+    Deleted{by: String},
+    /// A kicked or banned member gets a LeftRoom right after
+    Moderated{by: String, action: Moderation}
+}
+
+impl fmt::Display for RoomEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoomEvent::Joined(user) => write!(f, "{} joined the room", user),
+            RoomEvent::Left(name) => write!(f, "{} left the room", name),
+            RoomEvent::TopicChanged{by, topic: Some(topic)} => write!(f, "{} changed the topic to: {}", by, topic),
+            RoomEvent::TopicChanged{by, topic: None} => write!(f, "{} cleared the topic", by),
+            RoomEvent::Renamed{by, new_name} => write!(f, "{} renamed the room to {}", by, new_name),
+            RoomEvent::Deleted{by} => write!(f, "{} deleted the room", by),
+            RoomEvent::Moderated{by, action} => match action {
+                Moderation::Kick(name) => write!(f, "{} kicked {} out of the room", by, name),
+                Moderation::Ban{name, by_ip: false} => write!(f, "{} banned {} from the room", by, name),
+                Moderation::Ban{name, by_ip: true} => write!(f, "{} banned {} and their IP address from the room", by, name),
+                Moderation::Unban(name) => write!(f, "{} lifted the ban on {}", by, name),
+                Moderation::Mute(name) => write!(f, "{} muted {}", by, name),
+                Moderation::Unmute(name) => write!(f, "{} unmuted {}", by, name),
+                Moderation::SetRole{name, role: RoomRole::Owner} => write!(f, "{} handed the room over to {}", by, name),
+                Moderation::SetRole{name, role: RoomRole::Operator} => write!(f, "{} made {} an operator", by, name),
+                Moderation::SetRole{name, role: RoomRole::Member} => write!(f, "{} made {} a regular member", by, name)
+            }
+        }
+    }
+}
+
+/// Why the server refused a request.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Rejection {
+    InvalidName(ValidationError),
+    /// Somebody else already goes by that name, along with free names close to it
+    NameTaken{name: String, suggestions: Vec<String>},
+    InvalidPassword(ValidationError),
+    /// Same answer for unknown accounts and wrong passwords, so nobody learns which names exist
+    InvalidCredentials,
+    /// Only the owner of the account may use the name
+    NameRegistered(String),
+    /// The server doesn't let guests in
+    AuthenticationRequired,
+    /// Registered users keep the name of their account
+    NotAGuest,
+    InvalidRoomName(ValidationError),
+    /// As many users as the server takes are logged in
+    ServerFull,
+    UnknownRoom(String),
+    /// Only members of a room can talk in or leave it
+    NotInRoom,
+    InvalidMessage(ValidationError),
+    /// Another room already goes by that name
+    RoomNameTaken(String),
+    InvalidTopic(ValidationError),
+    /// As many rooms as the server allows are around
+    TooManyRooms,
+    WrongRoomPassword,
+    /// Only the owner of a room may change or delete it
+    NotRoomOwner,
+    /// Only operators and the owner may moderate a room
+    NotRoomOperator,
+    /// Nobody of that name is logged in
+    UnknownUser(String),
+    /// The user has to be in the room for that
+    NotARoomMember(String),
+    /// The user's role is as high as ours or higher
+    Outranked(String),
+    BannedFromRoom,
+    MutedInRoom,
+    /// The room is invite only and we have no invite that is still good
+    NotInvited
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::InvalidName(e) => write!(f, "name {}", e),
+            Rejection::NameTaken{name, suggestions} if suggestions.is_empty() => write!(f, "'{}' is already taken", name),
+            Rejection::NameTaken{name, suggestions} => write!(f, "'{}' is already taken, how about {}?", name, suggestions.join(", ")),
+            Rejection::InvalidPassword(e) => write!(f, "password {}", e),
+            Rejection::InvalidCredentials => write!(f, "wrong name or password"),
+            Rejection::NameRegistered(name) => write!(f, "'{}' belongs to a registered account", name),
+            Rejection::AuthenticationRequired => write!(f, "guests are not allowed on this server, log in or register"),
+            Rejection::NotAGuest => write!(f, "registered users can't change their name"),
+            Rejection::InvalidRoomName(e) => write!(f, "room name {}", e),
+            Rejection::ServerFull => write!(f, "the server is full, try again later"),
+            Rejection::UnknownRoom(name) => write!(f, "there is no room named '{}'", name),
+            Rejection::NotInRoom => write!(f, "you are not in a room"),
+            Rejection::InvalidMessage(e) => write!(f, "message {}", e),
+            Rejection::RoomNameTaken(name) => write!(f, "there already is a room named '{}'", name),
+            Rejection::InvalidTopic(e) => write!(f, "topic {}", e),
+            Rejection::TooManyRooms => write!(f, "there are as many rooms as the server allows"),
+            Rejection::WrongRoomPassword => write!(f, "wrong room password"),
+            Rejection::NotRoomOwner => write!(f, "only the owner of the room can do that"),
+            Rejection::NotRoomOperator => write!(f, "only operators of the room can do that"),
+            Rejection::UnknownUser(name) => write!(f, "there is no user named '{}'", name),
+            Rejection::NotARoomMember(name) => write!(f, "{} is not in the room", name),
+            Rejection::Outranked(name) => write!(f, "{} has a role as high as yours", name),
+            Rejection::BannedFromRoom => write!(f, "you are banned from the room"),
+            Rejection::MutedInRoom => write!(f, "you are muted in the room"),
+            Rejection::NotInvited => write!(f, "the room is invite only and you have no valid invite")
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ServerEnvelope {
+    /// None if the server sends this on its own and not as a response
+    pub request_id: Option<RequestId>,
+    pub message: ServerMessage
+}
+
+impl ServerEnvelope {
+    pub fn response(request_id: RequestId, message: ServerMessage) -> ServerEnvelope {
+        ServerEnvelope{request_id: Some(request_id), message}
+    }
+
+    pub fn push(message: ServerMessage) -> ServerEnvelope {
+        ServerEnvelope{request_id: None, message}
+    }
+}
+
+/// Client <-> client on a direct chat link
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum PeerMessage {
+    /// Part of a chat message, see multipart
+    Chunk(MessageChunk),
+    /// The sender goes by this name from now on
+    Nick(String),
+    Ping,
+    Pong
+}
+
+/// Fresh X25519 key for one direct chat, signed with the sender's identity key
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct KeyShare {
+    pub public: [u8; 32],
+    /// 64 bytes, see IdentityProof
+    pub signature: Vec<u8>
+}
+
+/// Tells the receiver which message key a RatchetMessage was encrypted with.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct RatchetHeader {
+    /// The sender's current ratchet key
+    pub public: [u8; 32],
+    /// How many messages the sender sent with its previous ratchet key
+    pub previous_count: u32,
+    /// Number of this message among the ones sent with the current ratchet key
+    pub number: u32
+}
+
+/// An encrypted PeerMessage, see the client's ratchet module.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    pub ciphertext: Vec<u8>
+}
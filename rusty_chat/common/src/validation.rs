@@ -0,0 +1,185 @@
+//! Rules for everything users get to type: names, passwords, room names, topics and messages.
+//! The server enforces them, clients check them before sending anything.
+
+use std::fmt;
+
+pub const MAX_USER_NAME_BYTES: usize = 32;
+pub const MAX_ROOM_NAME_BYTES: usize = 64;
+pub const MAX_TOPIC_BYTES: usize = 256;
+pub const MIN_PASSWORD_BYTES: usize = 8;
+pub const MAX_PASSWORD_BYTES: usize = 128;
+/// Messages longer than Message::SIZE are sent in chunks, this bounds what a receiver buffers.
+pub const MAX_MESSAGE_BYTES: usize = 32 * 1024;
+
+/// Names that would be confusing on screen or are used by the server itself.
+pub const RESERVED_NAMES: [&str; 4] = ["anon", "me", "server", "system"];
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum ValidationError {
+    Empty,
+    TooShort{min_bytes: usize},
+    TooLong{max_bytes: usize},
+    /// Control characters could be used to mess with the terminal of whoever displays the text.
+    ControlCharacter,
+    InvalidCharacter(char),
+    LeadingOrTrailingWhitespace,
+    Reserved(String)
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Empty => write!(f, "must not be empty"),
+            ValidationError::TooShort{min_bytes} => write!(f, "must be at least {} bytes long", min_bytes),
+            ValidationError::TooLong{max_bytes} => write!(f, "must not be longer than {} bytes", max_bytes),
+            ValidationError::ControlCharacter => write!(f, "must not contain control characters"),
+            ValidationError::InvalidCharacter(c) => write!(f, "must not contain '{}'", c),
+            ValidationError::LeadingOrTrailingWhitespace => write!(f, "must not start or end with whitespace"),
+            ValidationError::Reserved(name) => write!(f, "'{}' is reserved", name)
+        }
+    }
+}
+
+/// User names: letters, digits, '_', '-' and '.', no whitespace at all.
+pub fn validate_user_name(name: &str) -> Result<(), ValidationError> {
+    check_length(name, MAX_USER_NAME_BYTES)?;
+    check_characters(name, |c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')?;
+    check_reserved(name)
+}
+
+/// Room names: like user names, but spaces and '#' are fine in between.
+pub fn validate_room_name(name: &str) -> Result<(), ValidationError> {
+    check_length(name, MAX_ROOM_NAME_BYTES)?;
+    if name.trim() != name {
+        return Err(ValidationError::LeadingOrTrailingWhitespace);
+    }
+    check_characters(name, |c| c.is_alphanumeric() || c == ' ' || c == '#' || c == '_' || c == '-' || c == '.')?;
+    check_reserved(name)
+}
+
+/// Topics may contain anything printable.
+pub fn validate_topic(topic: &str) -> Result<(), ValidationError> {
+    check_length(topic, MAX_TOPIC_BYTES)?;
+    check_characters(topic, |_| true)
+}
+
+/// Passwords may contain anything printable, as long as there is enough of it.
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    check_length(password, MAX_PASSWORD_BYTES)?;
+    if password.len() < MIN_PASSWORD_BYTES {
+        return Err(ValidationError::TooShort{min_bytes: MIN_PASSWORD_BYTES});
+    }
+    check_characters(password, |_| true)
+}
+
+/// Messages may contain anything printable, and tabs: they do no harm on a terminal and
+/// pasted logs and stack traces are full of them.
+pub fn validate_message(text: &str) -> Result<(), ValidationError> {
+    check_length(text, MAX_MESSAGE_BYTES)?;
+    match text.chars().find(|c| c.is_control() && *c != '\t') {
+        Some(_) => Err(ValidationError::ControlCharacter),
+        None => Ok(())
+    }
+}
+
+fn check_length(text: &str, max_bytes: usize) -> Result<(), ValidationError> {
+    if text.is_empty() {
+        Err(ValidationError::Empty)
+    } else if text.len() > max_bytes {
+        Err(ValidationError::TooLong{max_bytes})
+    } else {
+        Ok(())
+    }
+}
+
+fn check_characters<F: Fn(char) -> bool>(text: &str, allowed: F) -> Result<(), ValidationError> {
+    for c in text.chars() {
+        if c.is_control() {
+            return Err(ValidationError::ControlCharacter);
+        }
+        if !allowed(c) {
+            return Err(ValidationError::InvalidCharacter(c));
+        }
+    }
+    Ok(())
+}
+
+fn check_reserved(name: &str) -> Result<(), ValidationError> {
+    match RESERVED_NAMES.iter().find(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        Some(reserved) => Err(ValidationError::Reserved(reserved.to_string())),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_names_stick_to_letters_digits_and_a_few_marks() {
+        assert_eq!(validate_user_name("alice_1.b-c"), Ok(()));
+        assert_eq!(validate_user_name("Ännchen"), Ok(()));
+        assert_eq!(validate_user_name(""), Err(ValidationError::Empty));
+        assert_eq!(validate_user_name(" "), Err(ValidationError::InvalidCharacter(' ')));
+        assert_eq!(validate_user_name("al ice"), Err(ValidationError::InvalidCharacter(' ')));
+        assert_eq!(validate_user_name("alice#1"), Err(ValidationError::InvalidCharacter('#')));
+        assert_eq!(validate_user_name("al\u{1b}[2Jice"), Err(ValidationError::ControlCharacter));
+        assert_eq!(validate_user_name("alice\n"), Err(ValidationError::ControlCharacter));
+    }
+
+    #[test]
+    fn lengths_are_counted_in_bytes() {
+        assert_eq!(validate_user_name(&"a".repeat(MAX_USER_NAME_BYTES)), Ok(()));
+        assert_eq!(validate_user_name(&"a".repeat(MAX_USER_NAME_BYTES + 1)), Err(ValidationError::TooLong{max_bytes: MAX_USER_NAME_BYTES}));
+        assert_eq!(validate_user_name(&"ä".repeat(MAX_USER_NAME_BYTES / 2 + 1)), Err(ValidationError::TooLong{max_bytes: MAX_USER_NAME_BYTES}));
+        assert_eq!(validate_room_name(&"a".repeat(MAX_ROOM_NAME_BYTES)), Ok(()));
+        assert_eq!(validate_room_name(&"a".repeat(MAX_ROOM_NAME_BYTES + 1)), Err(ValidationError::TooLong{max_bytes: MAX_ROOM_NAME_BYTES}));
+        assert_eq!(validate_topic(&"a".repeat(MAX_TOPIC_BYTES)), Ok(()));
+        assert_eq!(validate_topic(&"a".repeat(MAX_TOPIC_BYTES + 1)), Err(ValidationError::TooLong{max_bytes: MAX_TOPIC_BYTES}));
+        assert_eq!(validate_message(&"a".repeat(MAX_MESSAGE_BYTES)), Ok(()));
+        assert_eq!(validate_message(&"a".repeat(MAX_MESSAGE_BYTES + 1)), Err(ValidationError::TooLong{max_bytes: MAX_MESSAGE_BYTES}));
+    }
+
+    #[test]
+    fn reserved_names_are_reserved_whatever_the_case() {
+        assert_eq!(validate_user_name("ANON"), Err(ValidationError::Reserved(String::from("anon"))));
+        assert_eq!(validate_user_name("Me"), Err(ValidationError::Reserved(String::from("me"))));
+        assert_eq!(validate_room_name("sErVeR"), Err(ValidationError::Reserved(String::from("server"))));
+        assert_eq!(validate_user_name("anonymous"), Ok(()));
+        assert_eq!(validate_room_name("system news"), Ok(()));
+    }
+
+    #[test]
+    fn room_names_take_spaces_and_hashes_only_in_between() {
+        assert_eq!(validate_room_name("#rust lounge"), Ok(()));
+        assert_eq!(validate_room_name(""), Err(ValidationError::Empty));
+        assert_eq!(validate_room_name("   "), Err(ValidationError::LeadingOrTrailingWhitespace));
+        assert_eq!(validate_room_name(" Lobby"), Err(ValidationError::LeadingOrTrailingWhitespace));
+        assert_eq!(validate_room_name("Lobby "), Err(ValidationError::LeadingOrTrailingWhitespace));
+        assert_eq!(validate_room_name("a/b"), Err(ValidationError::InvalidCharacter('/')));
+        assert_eq!(validate_room_name("Lob\tby"), Err(ValidationError::ControlCharacter));
+    }
+
+    #[test]
+    fn topics_and_passwords_take_anything_printable() {
+        assert_eq!(validate_topic("Rust & friends: 100% <off topic>"), Ok(()));
+        assert_eq!(validate_topic(""), Err(ValidationError::Empty));
+        assert_eq!(validate_topic("one\ntwo"), Err(ValidationError::ControlCharacter));
+        assert_eq!(validate_password("correct horse"), Ok(()));
+        assert_eq!(validate_password(""), Err(ValidationError::Empty));
+        assert_eq!(validate_password(&"a".repeat(MIN_PASSWORD_BYTES - 1)), Err(ValidationError::TooShort{min_bytes: MIN_PASSWORD_BYTES}));
+        assert_eq!(validate_password(&"a".repeat(MIN_PASSWORD_BYTES)), Ok(()));
+        assert_eq!(validate_password(&"a".repeat(MAX_PASSWORD_BYTES)), Ok(()));
+        assert_eq!(validate_password(&"a".repeat(MAX_PASSWORD_BYTES + 1)), Err(ValidationError::TooLong{max_bytes: MAX_PASSWORD_BYTES}));
+        assert_eq!(validate_password("password\u{7}"), Err(ValidationError::ControlCharacter));
+    }
+
+    #[test]
+    fn messages_keep_tabs_but_no_other_control_characters() {
+        assert_eq!(validate_message("thread 'main' panicked:\tat src/main.rs:3:5"), Ok(()));
+        assert_eq!(validate_message(""), Err(ValidationError::Empty));
+        assert_eq!(validate_message("\u{1b}[2J"), Err(ValidationError::ControlCharacter));
+        assert_eq!(validate_message("one\rtwo"), Err(ValidationError::ControlCharacter));
+        assert_eq!(validate_message("\u{7f}"), Err(ValidationError::ControlCharacter));
+    }
+}
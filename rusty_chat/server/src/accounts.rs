@@ -0,0 +1,216 @@
+//! Registered accounts.
+//!
+//! They live in a plain text file with one `name<TAB>password hash` line per account. Names can't
+//! contain tabs and the hashes are argon2 PHC strings. Hashing is slow on purpose, so it never
+//! happens on the tasks serving connections.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use rand::{thread_rng, Rng};
+use tokio::task;
+use common::identity::create_private_file;
+use crate::registry::name_key;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AuthConfig {
+    /// Turns guests away
+    pub require_auth: bool,
+    pub accounts_file: PathBuf
+}
+
+impl AuthConfig {
+    pub const DEFAULT_ACCOUNTS_FILE: &'static str = "accounts.txt";
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    NameRegistered,
+    /// Unknown name or wrong password, callers must not tell which
+    InvalidCredentials,
+    /// Our fault, not the user's: hashing failed or the account could not be saved
+    Internal(String)
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::NameRegistered => write!(f, "name is already registered"),
+            AccountError::InvalidCredentials => write!(f, "wrong name or password"),
+            AccountError::Internal(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+struct Account {
+    name: String,
+    password_hash: String
+}
+
+/// Cheap to clone, all clones share the same accounts.
+#[derive(Clone)]
+pub struct Accounts {
+    path: PathBuf,
+    /// Keyed by name_key(), like the registry
+    accounts: Arc<Mutex<HashMap<String, Account>>>
+}
+
+impl Accounts {
+    /// A missing file is fine, it gets created along with the first account.
+    pub fn load(path: PathBuf) -> io::Result<Accounts> {
+        let mut accounts = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (index, line) in content.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+                    let (name, password_hash) = line.split_once('\t').ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("line {} of {} is not 'name<TAB>hash'", index + 1, path.display()))
+                    })?;
+                    accounts.insert(name_key(name), Account{name: name.to_string(), password_hash: password_hash.to_string()});
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e)
+        }
+        Ok(Accounts{path, accounts: Arc::new(Mutex::new(accounts))})
+    }
+
+    pub fn count(&self) -> usize {
+        self.accounts.lock().unwrap().len()
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&name_key(name))
+    }
+
+    /// Returns the name as it was registered, which may differ in case from the one given.
+    pub async fn verify(&self, name: String, password: String) -> Result<String, AccountError> {
+        let accounts = self.clone();
+        task::spawn_blocking(move || accounts.verify_blocking(&name, &password)).await.expect("verifying a password panicked")
+    }
+
+    /// Saves the new account right away.
+    pub async fn register(&self, name: String, password: String) -> Result<(), AccountError> {
+        let accounts = self.clone();
+        task::spawn_blocking(move || accounts.register_blocking(name, &password)).await.expect("registering an account panicked")
+    }
+
+    fn verify_blocking(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        let account = self.accounts.lock().unwrap().get(&name_key(name)).map(|account| (account.name.clone(), account.password_hash.clone()));
+        match account {
+            Some((name, password_hash)) => {
+                let hash = PasswordHash::new(&password_hash).map_err(|e| AccountError::Internal(format!("stored hash of {} is broken: {}", name, e)))?;
+                match Argon2::default().verify_password(password.as_bytes(), &hash) {
+                    Ok(()) => Ok(name),
+                    Err(_) => Err(AccountError::InvalidCredentials)
+                }
+            },
+            None => {
+                // takes as long as a wrong password would, so unknown names don't stand out
+                let _ = hash_password(password);
+                Err(AccountError::InvalidCredentials)
+            }
+        }
+    }
+
+    fn register_blocking(&self, name: String, password: &str) -> Result<(), AccountError> {
+        let password_hash = hash_password(password)?;
+        let key = name_key(&name);
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&key) {
+            return Err(AccountError::NameRegistered);
+        }
+        accounts.insert(key.clone(), Account{name, password_hash});
+        if let Err(e) = save(&self.path, &accounts) {
+            accounts.remove(&key);
+            return Err(AccountError::Internal(format!("cannot write {}: {}", self.path.display(), e)));
+        }
+        Ok(())
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt = SaltString::encode_b64(&thread_rng().gen::<[u8; 16]>()).map_err(|e| AccountError::Internal(e.to_string()))?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|e| AccountError::Internal(e.to_string()))?;
+    Ok(hash.to_string())
+}
+
+/// Writes a temporary file first, so a crash can't leave half of the accounts behind.
+/// Only the server's user gets to read the hashes.
+fn save(path: &Path, accounts: &HashMap<String, Account>) -> io::Result<()> {
+    let mut sorted: Vec<&Account> = accounts.values().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+    let content: String = sorted.iter().map(|account| format!("{}\t{}\n", account.name, account.password_hash)).collect();
+    let temporary = path.with_extension("tmp");
+    // whatever an earlier crash left there could have other permissions
+    match fs::remove_file(&temporary) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => ()
+    }
+    create_private_file(&temporary)?.write_all(content.as_bytes())?;
+    fs::rename(&temporary, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A file of its own for every test, gone before the test is over.
+    fn accounts_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rusty_chat_accounts_{}_{}.txt", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn accounts_log_in_regardless_of_case_with_the_right_password_only() {
+        let path = accounts_file("login");
+        let accounts = Accounts::load(path.clone()).unwrap();
+        accounts.register(String::from("Alice"), String::from("correct horse")).await.unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(accounts.is_registered("aLiCe"));
+        assert_eq!(accounts.verify(String::from("alice"), String::from("correct horse")).await.unwrap(), "Alice");
+        assert!(matches!(accounts.verify(String::from("alice"), String::from("battery staple")).await, Err(AccountError::InvalidCredentials)));
+        assert!(matches!(accounts.verify(String::from("bob"), String::from("correct horse")).await, Err(AccountError::InvalidCredentials)));
+        assert!(matches!(accounts.register(String::from("ALICE"), String::from("battery staple")).await, Err(AccountError::NameRegistered)));
+        assert_eq!(accounts.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn accounts_survive_a_restart_in_a_file_only_the_server_can_read() {
+        let path = accounts_file("restart");
+        let accounts = Accounts::load(path.clone()).unwrap();
+        accounts.register(String::from("Alice"), String::from("correct horse")).await.unwrap();
+        accounts.register(String::from("bob"), String::from("battery staple")).await.unwrap();
+
+        let reloaded = Accounts::load(path.clone()).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        fs::remove_file(&path).unwrap();
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+        assert_eq!(reloaded.count(), 2);
+        assert_eq!(reloaded.verify(String::from("ALICE"), String::from("correct horse")).await.unwrap(), "Alice");
+        assert!(matches!(reloaded.verify(String::from("bob"), String::from("correct horse")).await, Err(AccountError::InvalidCredentials)));
+    }
+
+    #[test]
+    fn broken_files_are_refused_instead_of_losing_accounts() {
+        let path = accounts_file("broken");
+        fs::write(&path, "alice\n").unwrap();
+        let loaded = Accounts::load(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Accounts::load(path).unwrap().count(), 0);
+    }
+}
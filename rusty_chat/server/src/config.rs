@@ -0,0 +1,309 @@
+//! Everything the server can be configured with.
+//!
+//! Settings come from the TOML file given with --config, then environment variables, then
+//! command line flags, later ones winning. Anything left open keeps its default. The result is
+//! checked as a whole before the server starts, so a typo stops it right away with a message
+//! saying what is wrong instead of showing up once the first client connects.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::Parser;
+use clap::builder::BoolishValueParser;
+use log::LevelFilter;
+use serde_derive::Deserialize;
+use common::heartbeat::HeartbeatConfig;
+use common::validation;
+use crate::accounts::AuthConfig;
+use crate::registry::{name_key, Limits, RoomSettings};
+use crate::tls::TlsConfig;
+
+const DEFAULT_PORT: u16 = 3333;
+/// Where the server's secret key is kept unless configured otherwise.
+const DEFAULT_KEY_FILE: &str = "server_identity.key";
+const DEFAULT_ROOM: &str = "Lobby";
+/// Rooms users create are dropped once empty, this keeps a few clients from piling up more.
+const DEFAULT_MAX_ROOMS: usize = 1000;
+
+/// Discovery server of Rusty Chat. Flags override the config file.
+#[derive(Parser, Debug)]
+#[command(name = "simple_server")]
+pub struct Args {
+    /// TOML file with the settings, the flags below have the same names with underscores
+    #[arg(long, env = "RUSTY_CHAT_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Address to accept clients on, may be given more than once. IPv6 addresses go in brackets, like [::]:3333
+    #[arg(long = "listen", value_name = "ADDRESS")]
+    listen: Vec<SocketAddr>,
+    /// How many users may be logged in at the same time
+    #[arg(long, value_name = "COUNT")]
+    max_users: Option<usize>,
+    /// How many rooms there may be, 1000 unless set
+    #[arg(long, value_name = "COUNT")]
+    max_rooms: Option<usize>,
+    /// Room that exists from the start, may be given more than once. Replaces the Lobby
+    #[arg(long = "room", value_name = "NAME")]
+    rooms: Vec<String>,
+    /// Registered user who keeps order in the rooms the server starts with, may be given more than once
+    #[arg(long = "operator", value_name = "NAME")]
+    operators: Vec<String>,
+    /// How long an invite to a room can be used
+    #[arg(long, env = "RUSTY_CHAT_INVITE_LIFETIME", value_name = "SECONDS")]
+    invite_lifetime: Option<u64>,
+    /// How many recent messages each room keeps for whoever joins later, 0 keeps none
+    #[arg(long, env = "RUSTY_CHAT_BACKLOG_SIZE", value_name = "COUNT")]
+    backlog_size: Option<usize>,
+    /// Messages older than this are left out of the backlog
+    #[arg(long, env = "RUSTY_CHAT_BACKLOG_AGE", value_name = "SECONDS")]
+    backlog_age: Option<u64>,
+    #[arg(long, env = "RUSTY_CHAT_HEARTBEAT_INTERVAL", value_name = "SECONDS")]
+    heartbeat_interval: Option<u64>,
+    /// Clients that stay quiet this long are dropped, at least twice the interval
+    #[arg(long, env = "RUSTY_CHAT_HEARTBEAT_TIMEOUT", value_name = "SECONDS")]
+    heartbeat_timeout: Option<u64>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "RUSTY_CHAT_LOG_LEVEL", value_name = "LEVEL")]
+    log_level: Option<String>,
+    /// Turn guests away
+    #[arg(long, env = "RUSTY_CHAT_REQUIRE_AUTH", value_name = "BOOL", value_parser = BoolishValueParser::new(),
+          num_args = 0..=1, default_missing_value = "true")]
+    require_auth: Option<bool>,
+    #[arg(long, env = "RUSTY_CHAT_ACCOUNTS_FILE", value_name = "FILE")]
+    accounts_file: Option<PathBuf>,
+    /// Secret key of the server, created if missing
+    #[arg(long, env = "RUSTY_CHAT_SERVER_KEY_FILE", value_name = "FILE")]
+    key_file: Option<PathBuf>,
+    /// PEM certificate chain, turns on TLS along with --tls-key
+    #[arg(long, env = "RUSTY_CHAT_TLS_CERT", value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "RUSTY_CHAT_TLS_KEY", value_name = "FILE")]
+    tls_key: Option<PathBuf>
+}
+
+/// What the config file may contain, everything is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<Vec<SocketAddr>>,
+    max_users: Option<usize>,
+    max_rooms: Option<usize>,
+    rooms: Option<Vec<String>>,
+    operators: Option<Vec<String>>,
+    invite_lifetime: Option<u64>,
+    backlog_size: Option<usize>,
+    backlog_age: Option<u64>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+    log_level: Option<String>,
+    require_auth: Option<bool>,
+    accounts_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub limits: Limits,
+    /// Created at startup, in this order
+    pub rooms: Vec<String>,
+    pub room_settings: RoomSettings,
+    pub heartbeat: HeartbeatConfig,
+    pub log_level: LevelFilter,
+    pub auth: AuthConfig,
+    pub key_file: PathBuf,
+    pub tls: Option<TlsConfig>
+}
+
+impl Config {
+    /// Reads the config file the arguments point to, if any, and checks the outcome.
+    pub fn load(args: Args) -> Result<Config, String> {
+        let file = match &args.config {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| format!("{} is not a valid config file: {}", path.display(), e))?
+            },
+            None => ConfigFile::default()
+        };
+
+        let listen = match (args.listen, file.listen) {
+            (listen, _) if !listen.is_empty() => listen,
+            (_, Some(listen)) => listen,
+            _ => vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DEFAULT_PORT)]
+        };
+        let rooms = match (args.rooms, file.rooms) {
+            (rooms, _) if !rooms.is_empty() => rooms,
+            (_, Some(rooms)) => rooms,
+            _ => vec![String::from(DEFAULT_ROOM)]
+        };
+        let limits = Limits{
+            max_users: args.max_users.or(file.max_users),
+            max_rooms: Some(args.max_rooms.or(file.max_rooms).unwrap_or(DEFAULT_MAX_ROOMS))
+        };
+        let operators = match (args.operators, file.operators) {
+            (operators, _) if !operators.is_empty() => operators,
+            (_, Some(operators)) => operators,
+            _ => Vec::new()
+        };
+        for name in &operators {
+            validation::validate_user_name(name).map_err(|e| format!("operator '{}': name {}", name, e))?;
+        }
+        let room_settings = RoomSettings{
+            invite_lifetime: args.invite_lifetime.or(file.invite_lifetime).map(Duration::from_secs)
+                .unwrap_or(RoomSettings::DEFAULT_INVITE_LIFETIME),
+            backlog_size: args.backlog_size.or(file.backlog_size).unwrap_or(RoomSettings::DEFAULT_BACKLOG_SIZE),
+            backlog_age: args.backlog_age.or(file.backlog_age).map(Duration::from_secs).unwrap_or(RoomSettings::DEFAULT_BACKLOG_AGE),
+            operators: operators.iter().map(|name| name_key(name)).collect()
+        };
+        let heartbeat = heartbeat(
+            args.heartbeat_interval.or(file.heartbeat_interval),
+            args.heartbeat_timeout.or(file.heartbeat_timeout)
+        )?;
+        let log_level = match args.log_level.or(file.log_level) {
+            Some(level) => level.parse().map_err(|_| format!("log level '{}' is none of off, error, warn, info, debug or trace", level))?,
+            None => LevelFilter::Info
+        };
+        let auth = AuthConfig{
+            require_auth: args.require_auth.or(file.require_auth).unwrap_or(false),
+            accounts_file: args.accounts_file.or(file.accounts_file).unwrap_or_else(|| PathBuf::from(AuthConfig::DEFAULT_ACCOUNTS_FILE))
+        };
+        let key_file = args.key_file.or(file.key_file).unwrap_or_else(|| PathBuf::from(DEFAULT_KEY_FILE));
+        let tls = match (args.tls_cert.or(file.tls_cert), args.tls_key.or(file.tls_key)) {
+            (Some(cert_file), Some(key_file)) => Some(TlsConfig{cert_file, key_file}),
+            (None, None) => None,
+            _ => return Err(String::from("tls_cert and tls_key have to be set together"))
+        };
+
+        let config = Config{listen, limits, rooms, room_settings, heartbeat, log_level, auth, key_file, tls};
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() {
+            return Err(String::from("there has to be at least one listen address"));
+        }
+        let mut addresses = HashSet::new();
+        for address in &self.listen {
+            if !addresses.insert(address) {
+                return Err(format!("listen address {} is given twice", address));
+            }
+        }
+        if self.limits.max_users == Some(0) {
+            return Err(String::from("max_users has to be at least 1"));
+        }
+        if let Some(max_rooms) = self.limits.max_rooms {
+            if self.rooms.len() > max_rooms {
+                return Err(format!("there are {} rooms to start with, but max_rooms is {}", self.rooms.len(), max_rooms));
+            }
+        }
+        if self.room_settings.invite_lifetime.is_zero() {
+            return Err(String::from("invite_lifetime has to be at least 1 second"));
+        }
+        if self.room_settings.backlog_age.is_zero() {
+            return Err(String::from("backlog_age has to be at least 1 second, set backlog_size to 0 to keep no backlog"));
+        }
+        let mut room_names = HashSet::new();
+        for name in &self.rooms {
+            if let Err(e) = validation::validate_room_name(name) {
+                return Err(format!("room '{}': name {}", name, e));
+            }
+            if !room_names.insert(name_key(name)) {
+                return Err(format!("room '{}' is given twice", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Unlike HeartbeatConfig::from_env, a timeout that would drop idle clients is refused instead of raised.
+fn heartbeat(interval: Option<u64>, timeout: Option<u64>) -> Result<HeartbeatConfig, String> {
+    let interval = interval.map(Duration::from_secs).unwrap_or(HeartbeatConfig::DEFAULT_INTERVAL);
+    let timeout = timeout.map(Duration::from_secs).unwrap_or_else(|| HeartbeatConfig::DEFAULT_TIMEOUT.max(interval * 2));
+    if interval.is_zero() {
+        return Err(String::from("heartbeat_interval has to be at least 1 second"));
+    }
+    if timeout < interval * 2 {
+        return Err(format!("heartbeat_timeout of {} seconds is shorter than twice the heartbeat_interval of {} seconds, \
+                                idle clients would be dropped", timeout.as_secs(), interval.as_secs()));
+    }
+    Ok(HeartbeatConfig{interval, timeout})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Mutex;
+
+    /// Arguments read the environment, tests that set it must not run alongside the others.
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    /// Loads what the flags and the config file with the given content say.
+    fn load(name: &str, file: &str, flags: &[&str]) -> Result<Config, String> {
+        let path = env::temp_dir().join(format!("rusty_chat_server_{}_{}.toml", std::process::id(), name));
+        fs::write(&path, file).unwrap();
+        let config = path.to_str().unwrap();
+        let args = Args::try_parse_from(["simple_server", "--config", config].iter().chain(flags)).unwrap();
+        let loaded = Config::load(args);
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn defaults_fill_in_whatever_is_left_open() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let config = Config::load(Args::try_parse_from(["simple_server"]).unwrap()).unwrap();
+
+        assert_eq!(config.listen, vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DEFAULT_PORT)]);
+        assert_eq!(config.rooms, vec![DEFAULT_ROOM]);
+        assert_eq!(config.limits, Limits{max_users: None, max_rooms: Some(DEFAULT_MAX_ROOMS)});
+        assert_eq!(config.room_settings, RoomSettings::default());
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn flags_win_over_the_environment_which_wins_over_the_file() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let file = "listen = [\"127.0.0.1:4000\"]\nmax_users = 5\nrooms = [\"Games\"]\noperators = [\"Alice\"]\n\
+                    backlog_size = 10\nbacklog_age = 60\nlog_level = \"warn\"\n";
+        env::set_var("RUSTY_CHAT_BACKLOG_SIZE", "20");
+        env::set_var("RUSTY_CHAT_BACKLOG_AGE", "120");
+        let config = load("precedence", file, &["--listen", "[::1]:5000", "--operator", "bob", "--backlog-age", "180"]);
+        env::remove_var("RUSTY_CHAT_BACKLOG_SIZE");
+        env::remove_var("RUSTY_CHAT_BACKLOG_AGE");
+        let config = config.unwrap();
+
+        assert_eq!(config.listen, vec!["[::1]:5000".parse().unwrap()]);
+        assert_eq!(config.limits.max_users, Some(5));
+        assert_eq!(config.rooms, vec!["Games"]);
+        assert_eq!(config.room_settings.operators, vec!["bob"]);
+        assert_eq!(config.room_settings.backlog_size, 20);
+        assert_eq!(config.room_settings.backlog_age, Duration::from_secs(180));
+        assert_eq!(config.log_level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn mistakes_stop_the_server_before_it_starts() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        let unknown = load("unknown", "max_user = 5\n", &[]).unwrap_err();
+        assert!(unknown.contains("unknown field `max_user`"), "{}", unknown);
+
+        let heartbeat = load("heartbeat", "heartbeat_interval = 10\nheartbeat_timeout = 15\n", &[]).unwrap_err();
+        assert!(heartbeat.contains("shorter than twice"), "{}", heartbeat);
+        assert!(load("heartbeat_flag", "heartbeat_interval = 10\n", &["--heartbeat-timeout", "20"]).is_ok());
+
+        assert_eq!(load("listen", "listen = []\n", &[]).unwrap_err(), "there has to be at least one listen address");
+        assert!(load("listen_twice", "", &["--listen", "127.0.0.1:4000", "--listen", "127.0.0.1:4000"]).is_err());
+        assert!(load("rooms", "rooms = [\"Lobby\", \"lobby\"]\n", &[]).is_err());
+        assert!(load("max_rooms", "rooms = [\"Lobby\", \"Games\"]\nmax_rooms = 1\n", &[]).is_err());
+        assert!(load("operator", "", &["--operator", "no one"]).is_err());
+        assert!(load("tls", "", &["--tls-cert", "cert.pem"]).is_err());
+        assert!(load("log_level", "log_level = \"loud\"\n", &[]).is_err());
+    }
+}
@@ -0,0 +1,545 @@
+//! One task per client. Each pass of the loop handles whatever happens first: bytes from the
+//! client, something another connection hands us, a heartbeat tick, a new user showing up
+//! or the client staying quiet for too long. What a frame means depends on the state we are in.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{self, Instant};
+use tokio_rustls::TlsAcceptor;
+use common::{ChatMode, LoginRequest, UserId, Error, Result};
+use common::codec::{self, FrameDecoder};
+use common::protocol::{ClientEnvelope, ClientMessage, ServerEnvelope, ServerMessage, Access, NewRoom, RequestId, Rejection};
+use common::validation;
+use common::handshake::{Hello, HandshakeReply};
+use common::identity::{IdentityChallenge, IdentityProof, Nonce, ProofRole, PublicKey};
+use crate::{Server, Outgoing, PendingPairing, CAPABILITIES};
+use crate::registry::{AddUserError, JoinedRoom, PairingError, NameTaken, RoomError};
+use crate::accounts::AccountError;
+use crate::tls::ClientStream;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Where a connection is in its life, decides what we expect from the client next.
+enum ConnectionState {
+    /// Nothing but the client's Hello so far
+    Handshake,
+    /// We sent our challenge. The client's challenge comes first, then its proof for ours
+    Identifying{nonce: Nonce, client_key: Option<PublicKey>},
+    /// The next request has to be the login
+    LoggingIn{public_key: PublicKey},
+    LoggedIn{user_id: UserId},
+    /// The client asked for the user list, it gets it as soon as it is not alone anymore
+    WaitingForUsers{user_id: UserId, request_id: RequestId},
+    /// The master selection went out, the client is expected to hang up on us
+    Paired
+}
+
+struct Connection {
+    stream: Box<dyn ClientStream>,
+    peer: SocketAddr,
+    decoder: FrameDecoder,
+    state: ConnectionState,
+    /// Handed out to other connections, so they can reach our client
+    sender: UnboundedSender<Outgoing>,
+    receiver: UnboundedReceiver<Outgoing>,
+    last_heard: Instant,
+    /// Whether the client logged in with an account rather than as a guest
+    verified: bool,
+    server: Arc<Server>
+}
+
+/// Serves one client and reports how that ended. The only place errors of a connection end up in.
+pub async fn handle_client(stream: TcpStream, peer: SocketAddr, server: Arc<Server>) {
+    debug!("new connection: {}", peer);
+    let stream = match &server.tls {
+        Some(acceptor) => match accept_tls(acceptor, stream, &server).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("TLS handshake with {} failed: {}", peer, e);
+                return;
+            }
+        },
+        None => Box::new(stream) as Box<dyn ClientStream>
+    };
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut connection = Connection{
+        stream, peer, decoder: FrameDecoder::new(), state: ConnectionState::Handshake,
+        sender, receiver, last_heard: Instant::now(), verified: false, server
+    };
+
+    match connection.run().await {
+        Ok(()) => info!("terminating connection with {}", peer),
+        Err(Error::PeerGone) => info!("{} disconnected", peer),
+        Err(Error::Timeout) => info!("{} missed its heartbeat, dropping it", peer),
+        Err(e) => warn!("terminating connection with {}: {}", peer, e)
+    }
+    connection.leave();
+    // the client may already be gone, nothing left to do about that
+    let _ = connection.stream.shutdown().await;
+}
+
+/// A client that never finishes the handshake is dropped after the heartbeat timeout.
+async fn accept_tls(acceptor: &TlsAcceptor, stream: TcpStream, server: &Server) -> Result<Box<dyn ClientStream>> {
+    match time::timeout(server.heartbeat.timeout, acceptor.accept(stream)).await {
+        Ok(stream) => Ok(Box::new(stream?)),
+        Err(_) => Err(Error::Timeout)
+    }
+}
+
+impl Connection {
+    /// Every client leaves by disconnecting at some point, so this only ever returns an error.
+    async fn run(&mut self) -> Result<()> {
+        let heartbeat = self.server.heartbeat;
+        let mut ping_timer = time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let server = Arc::clone(&self.server);
+        loop {
+            // registered before looking at the users, so nobody becoming available in between gets missed
+            let user_available = server.user_available.notified();
+            tokio::pin!(user_available);
+            user_available.as_mut().enable();
+            self.answer_if_not_alone().await?;
+
+            let waiting = matches!(self.state, ConnectionState::WaitingForUsers{..});
+            let handshake_done = !matches!(self.state, ConnectionState::Handshake | ConnectionState::Identifying{..});
+            let deadline = self.last_heard + heartbeat.timeout;
+            tokio::select! {
+                read = self.stream.read(&mut chunk) => self.on_data(&chunk[..read?]).await?,
+                Some(outgoing) = self.receiver.recv() => self.on_outgoing(outgoing).await?,
+                _ = ping_timer.tick(), if handshake_done => self.send(&ServerEnvelope::push(ServerMessage::Ping)).await?,
+                _ = &mut user_available, if waiting => (),
+                _ = time::sleep_until(deadline) => return Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Takes the user off the list, which drops the sender other connections could reach us with.
+    /// Whatever is still queued for the client goes with the receiver, pairings included.
+    fn leave(&mut self) {
+        if let Some(user_id) = self.user_id() {
+            self.server.registry.remove_user(user_id);
+        }
+        self.state = ConnectionState::Paired;
+    }
+
+    fn user_id(&self) -> Option<UserId> {
+        match self.state {
+            ConnectionState::LoggedIn{user_id} | ConnectionState::WaitingForUsers{user_id, ..} => Some(user_id),
+            _ => None
+        }
+    }
+
+    async fn send<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let frame = codec::encode_frame(value)?;
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    async fn respond(&mut self, request_id: RequestId, message: ServerMessage) -> Result<()> {
+        self.send(&ServerEnvelope::response(request_id, message)).await
+    }
+
+    /// Handles every complete frame the new bytes finish.
+    async fn on_data(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            if self.decoder.is_empty() {
+                return Err(Error::PeerGone);
+            }
+            return Err(Error::protocol("connection closed in the middle of a frame"));
+        }
+        self.last_heard = Instant::now();
+        self.decoder.extend(data);
+        loop {
+            match self.state {
+                ConnectionState::Handshake => match self.decoder.next_frame::<Hello>()? {
+                    Some(hello) => self.handshake(hello).await?,
+                    None => return Ok(())
+                },
+                ConnectionState::Identifying{client_key: None, ..} => match self.decoder.next_frame::<IdentityChallenge>()? {
+                    Some(challenge) => self.on_challenge(challenge).await?,
+                    None => return Ok(())
+                },
+                ConnectionState::Identifying{client_key: Some(_), ..} => match self.decoder.next_frame::<IdentityProof>()? {
+                    Some(proof) => self.on_proof(proof)?,
+                    None => return Ok(())
+                },
+                _ => match self.decoder.next_frame::<ClientEnvelope>()? {
+                    Some(envelope) => self.on_request(envelope).await?,
+                    None => return Ok(())
+                }
+            }
+        }
+    }
+
+    /// Answers the client's Hello and challenges it to prove its identity.
+    async fn handshake(&mut self, peer_hello: Hello) -> Result<()> {
+        let own_hello = Hello::new(CAPABILITIES);
+        match own_hello.negotiate(&peer_hello) {
+            Ok(capabilities) => {
+                debug!("{} speaks protocol version {}, negotiated capabilities: {}", self.peer, peer_hello.protocol_version, capabilities);
+                self.send(&HandshakeReply::Accepted(Hello::new(capabilities))).await?;
+                let nonce = Nonce::generate();
+                self.send(&self.server.identity.challenge(nonce)).await?;
+                self.state = ConnectionState::Identifying{nonce, client_key: None};
+                Ok(())
+            },
+            Err(reason) => {
+                self.send(&HandshakeReply::Rejected(reason.clone())).await?;
+                Err(Error::Protocol(reason))
+            }
+        }
+    }
+
+    /// Proves our own identity, the client's proof comes next.
+    async fn on_challenge(&mut self, challenge: IdentityChallenge) -> Result<()> {
+        if let ConnectionState::Identifying{nonce, ..} = self.state {
+            self.send(&self.server.identity.prove(&challenge.nonce, &challenge.public_key, ProofRole::Server)).await?;
+            self.state = ConnectionState::Identifying{nonce, client_key: Some(challenge.public_key)};
+        }
+        Ok(())
+    }
+
+    fn on_proof(&mut self, proof: IdentityProof) -> Result<()> {
+        if let ConnectionState::Identifying{nonce, client_key: Some(public_key)} = self.state {
+            if !public_key.verify(&nonce, &self.server.identity.public_key(), ProofRole::Client, &proof) {
+                return Err(Error::protocol("the client could not prove its identity"));
+            }
+            self.state = ConnectionState::LoggingIn{public_key};
+        }
+        Ok(())
+    }
+
+    async fn on_request(&mut self, envelope: ClientEnvelope) -> Result<()> {
+        let request_id = envelope.request_id;
+        match (&self.state, envelope.message) {
+            (_, ClientMessage::Ping) => self.respond(request_id, ServerMessage::Pong).await,
+            (_, ClientMessage::Pong) => Ok(()),
+            (ConnectionState::LoggingIn{public_key}, ClientMessage::Login(request)) => {
+                let public_key = *public_key;
+                self.login(request_id, request, false, public_key).await
+            },
+            (ConnectionState::LoggingIn{public_key}, ClientMessage::Register(request)) => {
+                let public_key = *public_key;
+                self.login(request_id, request, true, public_key).await
+            },
+            (ConnectionState::LoggingIn{..}, message) => Err(Error::Protocol(format!("expected a login request, got {:?}", message))),
+            (ConnectionState::LoggedIn{user_id}, message) => {
+                let user_id = *user_id;
+                self.handle_request(request_id, message, user_id).await
+            },
+            (ConnectionState::WaitingForUsers{..}, message) =>
+                Err(Error::Protocol(format!("expected nothing but heartbeats while waiting for other users, got {:?}", message))),
+            (ConnectionState::Paired, message) =>
+                Err(Error::Protocol(format!("expected the client to hang up after its master selection, got {:?}", message))),
+            (ConnectionState::Handshake, _) | (ConnectionState::Identifying{..}, _) =>
+                unreachable!("frames are decoded as Hello or identities during the handshake")
+        }
+    }
+
+    /// Logs in as a guest, with an account or registers a new one. Rejected logins may be retried.
+    async fn login(&mut self, request_id: RequestId, request: LoginRequest, register: bool, public_key: PublicKey) -> Result<()> {
+        let name = match self.authenticate(&request, register).await {
+            Ok(name) => name,
+            Err(rejection) => {
+                info!("rejecting login of {}: {}", self.peer, rejection);
+                return self.respond(request_id, ServerMessage::Rejected(rejection)).await;
+            }
+        };
+        let verified = request.password.is_some();
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d, the master's partner connects to the plain address
+        let ip_address = self.peer.ip().to_canonical().to_string();
+        let user_id = match self.server.registry.add_user(name.clone(), ip_address, request.p2p_port, self.sender.clone(), verified, public_key).await {
+            Ok(user_id) => user_id,
+            Err(AddUserError::NameTaken(NameTaken{suggestions})) => {
+                info!("rejecting login of {}: '{}' is already taken", self.peer, name);
+                // an account is logged in elsewhere, other names won't help
+                let suggestions = if verified { Vec::new() } else { suggestions };
+                return self.respond(request_id, ServerMessage::Rejected(Rejection::NameTaken{name, suggestions})).await;
+            },
+            Err(AddUserError::ServerFull) => {
+                warn!("rejecting login of {}: the server is full", self.peer);
+                return self.respond(request_id, ServerMessage::Rejected(Rejection::ServerFull)).await;
+            }
+        };
+        if let (true, Some(password)) = (register, request.password) {
+            // the name is ours in the registry now, nobody can log in with it while we store the account
+            if let Err(e) = self.server.accounts.register(name.clone(), password).await {
+                self.server.registry.remove_user(user_id);
+                error!("failed to register {} for {}: {}", name, self.peer, e);
+                return match e {
+                    AccountError::NameRegistered => self.respond(request_id, ServerMessage::Rejected(Rejection::NameRegistered(name))).await,
+                    _ => self.respond(request_id, ServerMessage::Error(String::from("the account could not be created, try again later"))).await
+                };
+            }
+            info!("{} registered {}", self.peer, name);
+        }
+
+        self.verified = verified;
+        self.state = ConnectionState::LoggedIn{user_id};
+        self.respond(request_id, ServerMessage::LoggedIn{name, verified}).await
+    }
+
+    /// Checks what the client claims and returns the name it is going to be known by.
+    /// Names of accounts are looked up regardless of case, logging in uses the registered spelling.
+    async fn authenticate(&self, request: &LoginRequest, register: bool) -> std::result::Result<String, Rejection> {
+        request.validate().map_err(Rejection::InvalidName)?;
+        let accounts = &self.server.accounts;
+        match &request.password {
+            None if register => Err(Rejection::InvalidPassword(validation::ValidationError::Empty)),
+            None if self.server.require_auth => Err(Rejection::AuthenticationRequired),
+            None if accounts.is_registered(&request.name) => Err(Rejection::NameRegistered(request.name.clone())),
+            None => Ok(request.name.clone()),
+            Some(password) => {
+                validation::validate_password(password).map_err(Rejection::InvalidPassword)?;
+                if register {
+                    if accounts.is_registered(&request.name) {
+                        return Err(Rejection::NameRegistered(request.name.clone()));
+                    }
+                    return Ok(request.name.clone());
+                }
+                accounts.verify(request.name.clone(), password.clone()).await.map_err(|e| match e {
+                    AccountError::Internal(reason) => {
+                        error!("cannot check the password of {}: {}", request.name, reason);
+                        Rejection::InvalidCredentials
+                    },
+                    _ => Rejection::InvalidCredentials
+                })
+            }
+        }
+    }
+
+    /// Dispatches one request of a logged in client.
+    async fn handle_request(&mut self, request_id: RequestId, message: ClientMessage, user_id: UserId) -> Result<()> {
+        match message {
+            ClientMessage::SelectChatMode(ChatMode::DIRECT) => {
+                // TODO: send updates, for as long as we are not chatting
+                self.state = ConnectionState::WaitingForUsers{user_id, request_id};
+                Ok(())
+            },
+            ClientMessage::SelectChatMode(ChatMode::ROOM) => {
+                let (rooms, unlisted) = self.server.registry.room_list().await;
+                self.respond(request_id, ServerMessage::RoomList{rooms, unlisted}).await
+            },
+            ClientMessage::SelectChatMode(ChatMode::WAIT) => Ok(()),
+            ClientMessage::ChatRequest(other_name) => self.chat_request(request_id, other_name, user_id).await,
+            ClientMessage::JoinRoom{name, password} => self.join_room(request_id, name, password, user_id).await,
+            ClientMessage::CreateRoom(new_room) => self.create_room(request_id, new_room, user_id).await,
+            ClientMessage::DeleteRoom(room_name) => {
+                let response = match self.server.registry.delete_room(user_id, room_name.clone()).await {
+                    Ok(name) => {
+                        info!("{} deleted {}", self.peer, name);
+                        ServerMessage::RoomDeleted(name)
+                    },
+                    Err(e) => room_rejection(e, room_name)
+                };
+                self.respond(request_id, response).await
+            },
+            ClientMessage::RenameRoom{name, new_name} => {
+                let response = match self.server.registry.rename_room(user_id, name.clone(), new_name.clone()).await {
+                    Ok(old_name) => {
+                        info!("{} renamed {} to {}", self.peer, old_name, new_name);
+                        ServerMessage::RoomRenamed{old_name, new_name}
+                    },
+                    Err(RoomError::NameTaken) => ServerMessage::Rejected(Rejection::RoomNameTaken(new_name)),
+                    Err(e) => room_rejection(e, name)
+                };
+                self.respond(request_id, response).await
+            },
+            ClientMessage::Moderate{room, action} => {
+                let response = match self.server.registry.moderate(user_id, room.clone(), action.clone()).await {
+                    Ok(()) => {
+                        info!("{} in {}: {:?}", self.peer, room, action);
+                        ServerMessage::Moderated(action)
+                    },
+                    Err(e) => room_rejection(e, room)
+                };
+                self.respond(request_id, response).await
+            },
+            ClientMessage::Invite{room, name} => {
+                let response = match self.server.registry.invite(user_id, room.clone(), name.clone()).await {
+                    Ok(()) => {
+                        info!("{} invited {} to {}", self.peer, name, room);
+                        ServerMessage::InviteSent{room, name}
+                    },
+                    Err(e) => room_rejection(e, room)
+                };
+                self.respond(request_id, response).await
+            },
+            ClientMessage::SetTopic{room, topic} => {
+                if let Some(Err(e)) = topic.as_deref().map(validation::validate_topic) {
+                    return self.respond(request_id, ServerMessage::Rejected(Rejection::InvalidTopic(e))).await;
+                }
+                let response = match self.server.registry.set_topic(user_id, room.clone(), topic.clone()).await {
+                    Ok(room) => ServerMessage::TopicSet{room, topic},
+                    Err(e) => room_rejection(e, room)
+                };
+                self.respond(request_id, response).await
+            },
+            ClientMessage::LeaveRoom => {
+                let response = match self.server.registry.leave_room(user_id).await {
+                    Some(room_name) => ServerMessage::LeftRoom(room_name),
+                    None => ServerMessage::Rejected(Rejection::NotInRoom)
+                };
+                self.respond(request_id, response).await
+            },
+            // the other members get it pushed, the sender only hears back if it went nowhere
+            ClientMessage::RoomMessage(message) => {
+                let rejection = match message.validate() {
+                    Err(e) => Rejection::InvalidMessage(e),
+                    Ok(()) => match self.server.registry.send_to_room(user_id, message).await {
+                        Ok(()) => return Ok(()),
+                        Err(RoomError::Muted) => Rejection::MutedInRoom,
+                        Err(_) => Rejection::NotInRoom
+                    }
+                };
+                self.send(&ServerEnvelope::push(ServerMessage::Rejected(rejection))).await
+            },
+            ClientMessage::Rename(new_name) => self.rename(request_id, new_name, user_id).await,
+            ClientMessage::Login(_) | ClientMessage::Register(_) => self.respond(request_id, ServerMessage::Error(String::from("already logged in"))).await,
+            ClientMessage::Ping | ClientMessage::Pong => unreachable!("heartbeats are handled in on_request")
+        }
+    }
+
+    async fn join_room(&mut self, request_id: RequestId, room_name: String, password: Option<String>, user_id: UserId) -> Result<()> {
+        if let Err(e) = validation::validate_room_name(&room_name) {
+            return self.respond(request_id, ServerMessage::Rejected(Rejection::InvalidRoomName(e))).await;
+        }
+        let response = match self.server.registry.join_room(user_id, room_name.clone(), password).await {
+            Ok(JoinedRoom{name, topic, members, unlisted_members, history}) => {
+                info!("{} joined {}", self.peer, name);
+                ServerMessage::JoinedRoom{room: name, topic, members, unlisted_members, history}
+            },
+            Err(e) => room_rejection(e, room_name)
+        };
+        self.respond(request_id, response).await
+    }
+
+    /// The creator owns the new room and is moved in right away.
+    async fn create_room(&mut self, request_id: RequestId, new_room: NewRoom, user_id: UserId) -> Result<()> {
+        if let Some(Err(e)) = new_room.topic.as_deref().map(validation::validate_topic) {
+            return self.respond(request_id, ServerMessage::Rejected(Rejection::InvalidTopic(e))).await;
+        }
+        if let Access::Password(password) = &new_room.access {
+            if let Err(e) = validation::validate_password(password) {
+                return self.respond(request_id, ServerMessage::Rejected(Rejection::InvalidPassword(e))).await;
+            }
+        }
+        let room_name = new_room.name.clone();
+        let response = match self.server.registry.create_room(user_id, new_room).await {
+            Ok(JoinedRoom{name, topic, members, unlisted_members, history}) => {
+                info!("{} created {}", self.peer, name);
+                ServerMessage::JoinedRoom{room: name, topic, members, unlisted_members, history}
+            },
+            Err(e) => room_rejection(e, room_name)
+        };
+        self.respond(request_id, response).await
+    }
+
+    /// Only guests may rename themselves, and not to the name of an account.
+    /// Everyone else learns about the new name from the registry.
+    async fn rename(&mut self, request_id: RequestId, new_name: String, user_id: UserId) -> Result<()> {
+        if self.verified {
+            return self.respond(request_id, ServerMessage::Rejected(Rejection::NotAGuest)).await;
+        }
+        if let Err(e) = validation::validate_user_name(&new_name) {
+            return self.respond(request_id, ServerMessage::Rejected(Rejection::InvalidName(e))).await;
+        }
+        if self.server.accounts.is_registered(&new_name) {
+            return self.respond(request_id, ServerMessage::Rejected(Rejection::NameRegistered(new_name))).await;
+        }
+        match self.server.registry.rename(user_id, new_name.clone()).await {
+            Ok(Some(old_name)) => {
+                info!("{} is now known as {}", old_name, new_name);
+                self.respond(request_id, ServerMessage::Renamed{old_name, new_name}).await
+            },
+            Ok(None) => Err(Error::PeerGone),
+            Err(NameTaken{suggestions}) =>
+                self.respond(request_id, ServerMessage::Rejected(Rejection::NameTaken{name: new_name, suggestions})).await
+        }
+    }
+
+    /// We have to wait for atleast another user before the list is worth sending.
+    async fn answer_if_not_alone(&mut self) -> Result<()> {
+        if let ConnectionState::WaitingForUsers{user_id, request_id} = self.state {
+            let (users, unlisted) = self.server.registry.available_users().await;
+            if users.len() > 1 {
+                self.state = ConnectionState::LoggedIn{user_id};
+                self.respond(request_id, ServerMessage::UserList{users, unlisted}).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pairs us with the requested user and tells both parties who is going to be the master.
+    /// The other user is told first, we only get our selection once theirs reached them.
+    async fn chat_request(&mut self, request_id: RequestId, other_name: String, own_user_id: UserId) -> Result<()> {
+        let registry = self.server.registry.clone();
+        let own_name = registry.get_name(own_user_id).await.ok_or(Error::PeerGone)?;
+        let pairing = match registry.pair(own_user_id, other_name.clone()).await {
+            Ok(pairing) => pairing,
+            Err(PairingError::UnknownUser) => {
+                debug!("{} requested a chat with unknown user '{}'", own_name, other_name);
+                return self.respond(request_id, ServerMessage::Error(format!("there is no user named '{}'", other_name))).await
+            },
+            Err(PairingError::NotAvailable) =>
+                return self.respond(request_id, ServerMessage::Error(format!("{} is not available anymore", other_name))).await
+        };
+        info!("{} wants to chat with {}", own_name, other_name);
+        debug!("master_id: {}, master_ip={}", pairing.master_id, &pairing.own_selection.target_ip);
+
+        // if the other user is gone already the pairing is dropped right here, which answers our client
+        let requester = PendingPairing{
+            registry, requester_id: own_user_id, sender: self.sender.clone(), request_id,
+            partner_name: other_name, selection: Some(pairing.own_selection)
+        };
+        let _ = pairing.other_sender.send(Outgoing::Pairing{selection: pairing.other_selection, requester});
+        Ok(())
+    }
+
+    /// Writes what another connection handed us to our client.
+    async fn on_outgoing(&mut self, outgoing: Outgoing) -> Result<()> {
+        match outgoing {
+            Outgoing::Envelope(envelope) => {
+                self.send(&envelope).await?;
+                if let ServerMessage::MasterSelection(_) = envelope.message {
+                    self.leave();
+                }
+            },
+            // someone who is already paired is not available, dropping the pairing tells the requester so
+            Outgoing::Pairing{..} if self.user_id().is_none() => (),
+            Outgoing::Pairing{selection, requester} => {
+                // if this fails, dropping requester tells it that we are gone
+                self.send(&ServerEnvelope::push(ServerMessage::MasterSelection(selection))).await?;
+                requester.complete();
+                self.leave();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tells the client why a request about the room of that name was refused.
+fn room_rejection(error: RoomError, room_name: String) -> ServerMessage {
+    let rejection = match error {
+        RoomError::InvalidName(e) => Rejection::InvalidRoomName(e),
+        RoomError::TooManyRooms => Rejection::TooManyRooms,
+        RoomError::UnknownRoom => Rejection::UnknownRoom(room_name),
+        RoomError::NotInRoom => Rejection::NotInRoom,
+        RoomError::NameTaken => Rejection::RoomNameTaken(room_name),
+        RoomError::WrongPassword => Rejection::WrongRoomPassword,
+        RoomError::NotOwner => Rejection::NotRoomOwner,
+        RoomError::NotOperator => Rejection::NotRoomOperator,
+        RoomError::UnknownUser(name) => Rejection::UnknownUser(name),
+        RoomError::NotAMember(name) => Rejection::NotARoomMember(name),
+        RoomError::Outranked(name) => Rejection::Outranked(name),
+        RoomError::Banned => Rejection::BannedFromRoom,
+        RoomError::Muted => Rejection::MutedInRoom,
+        RoomError::NotInvited => Rejection::NotInvited
+    };
+    ServerMessage::Rejected(rejection)
+}
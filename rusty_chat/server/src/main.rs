@@ -1,324 +1,194 @@
-use std::thread;
-use std::time;
-use std::sync::{Arc, Mutex};
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
-use crossbeam_channel as channel;
-use crossbeam_channel::{Sender, Receiver};
-use common::{LoginRequest, ChatRoom, User, ChatMode, MasterSelectionResult};
-use rand::{thread_rng, Rng};
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::process;
+use std::sync::Arc;
+use clap::Parser;
+use log::{error, info, warn};
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::UnboundedSender;
+use common::{MasterSelectionResult, UserId};
+use common::protocol::{ServerEnvelope, ServerMessage, NewRoom, RequestId};
+use common::handshake::Capabilities;
+use common::heartbeat::HeartbeatConfig;
+use common::identity::Identity;
+use config::{Args, Config};
+use registry::{Registry, RegistryHandle};
+use accounts::Accounts;
+use tokio_rustls::TlsAcceptor;
+
+mod accounts;
+mod config;
+mod connection;
+mod registry;
+mod tls;
 
-extern crate bincode;
 extern crate rand;
-extern crate crossbeam_channel;
 
-// TODO: receiving 0 bytes in any receive* method means the client quit on us -> do something sensible
-// TODO: find logging crate
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:3333").unwrap();
-
-    let mut room_vec: Vec<ChatRoom> = Vec::new();
-    create_chat_room(String::from("Lobby"), &mut room_vec);
-
-    let rooms: Arc<Mutex<Vec<ChatRoom>>> = Arc::new(Mutex::new(room_vec));
-    let users: Arc<Mutex<Vec<User>>> = Arc::new(Mutex::new(Vec::new()));
-
-    println!("server listening on port 3333");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("new connection: {}", stream.peer_addr().unwrap());
-                thread::spawn({ 
-                    let room_clone = Arc::clone(&rooms);
-                    let users_clone = Arc::clone(&users);
-                    move || {
-                        handle_client(stream, room_clone, users_clone);  
-                    }
-                });
-            },
-            Err(e) => {
-                println!("error: {}", e);
-            }
+/// Optional features this server supports.
+const CAPABILITIES: Capabilities = Capabilities::ROOMS;
+/// Connections waiting to be accepted, per listener.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Everything the connections share.
+struct Server {
+    registry: RegistryHandle,
+    /// Wakes up the connections waiting for someone to chat with, the registry fires it whenever a user becomes available.
+    user_available: Arc<Notify>,
+    heartbeat: HeartbeatConfig,
+    accounts: Accounts,
+    /// Guests are turned away if set
+    require_auth: bool,
+    /// Clients remember our public key and complain if it ever changes
+    identity: Identity,
+    /// Every connection starts with a TLS handshake if set
+    tls: Option<TlsAcceptor>
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            process::exit(2);
         }
-    }
-    drop(listener);
-}
-
-fn handle_client(mut stream: TcpStream, rooms: Arc<Mutex<Vec<ChatRoom>>>, users: Arc<Mutex<Vec<User>>>) {
-    let request = receive_login_request(&mut stream);
-    let user_id = match request {
-        Some(r) => create_and_add_user(r.name, stream.peer_addr().unwrap().ip().to_string(), &users),
-        None => create_and_add_user(String::from("anon"), stream.local_addr().unwrap().to_string(), &users)
     };
-
-    let (sender, receiver) = channel::unbounded();
-	attach_sender_to_user(&users, user_id, sender);
-    
-    let receiver_thread = thread::spawn({
-        let listen_stream_clone = stream.try_clone().unwrap();
-		move || {
-			listen_on_channel(receiver, listen_stream_clone);
-		}
-	});
-
-    while match receive_chat_mode(&mut stream) {
-        Some(m) => {
-            if m == ChatMode::DIRECT {
-                direct_mode(&mut stream, &users, user_id)
-            } else if m == ChatMode::WAIT {
-                false
-            } else {
-                room_mode(&mut stream, &rooms)
-            }
-        },
-        None => {
-            println!("no mode selected, terminating connection");
-            stream.shutdown(Shutdown::Both).unwrap();
-            false
+    env_logger::Builder::new().filter_level(config.log_level).init();
+
+    let mut registry = Registry::new(config.limits, config.room_settings);
+    for room in &config.rooms {
+        // the config was checked already, this can't fail
+        if let Err(e) = registry.create_room(NewRoom::public(room.clone()), None) {
+            error!("cannot create room {}: {}", room, e);
+            process::exit(1);
         }
-    } {}
-
-    receiver_thread.join().unwrap();
-    
-    remove_user(user_id, &users);
-    println!("terminating connection with {}", stream.peer_addr().unwrap().to_string());
-}
-
-// TODO: https://stackoverflow.com/questions/26126683/how-to-match-trait-implementors
-// TODO: Need to work in a chat request for example, probably close request too
-fn listen_on_channel(receiver: Receiver<MasterSelectionResult>, mut stream: TcpStream) {
-	while match receiver.recv() {
-		Ok(selection) => {
-			send_master_selection(&selection, &mut stream);
-			false
-		},
-		Err(e) => {
-			println!("error receiving master selection on channel: {}", e);
-			false
-		}
-	} {}
-}
-
-fn send_master_selection(selection: &MasterSelectionResult, stream: &mut TcpStream) {
-	let serialized = bincode::serialize(selection).unwrap();
-	stream.write(&serialized).unwrap();
-}
-
-fn attach_sender_to_user(users: &Arc<Mutex<Vec<User>>>, id: u8, sender: Sender<MasterSelectionResult>) {
-	let mut user_vec = users.lock().unwrap();
-	match user_vec.iter_mut().find(|u| u.id == id) {
-		Some(user) => user.sender = Some(sender),
-		None => ()
-	}
-}
-
-fn direct_mode(stream: &mut TcpStream, users: &Arc<Mutex<Vec<User>>>, own_user_id: u8) -> bool {
-    // we have to wait for atleast another user
-    // TODO: send updates, for as long as we are not chatting
-    while check_for_users(&users) {
-        thread::sleep(time::Duration::from_millis(1000));
     }
-    let user_names = get_user_names(&users);
-    send_string_vec(user_names, stream);
 
-    let user_name_buffer = vec!(0; User::NAME_SIZE);
-    let to_chat_with = receive_string(stream, user_name_buffer);
-    let own_name = get_name_by_id(own_user_id, &users).unwrap();
-    let other_name = match to_chat_with {
-        Some(name) => name,
-        None => String::from("")
+    let accounts = match Accounts::load(config.auth.accounts_file.clone()) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            error!("failed to load accounts from {}: {}", config.auth.accounts_file.display(), e);
+            process::exit(1);
+        }
     };
-    
-    if &other_name == "" {
-    	println!("no name submitted");
-    	return true
-    }
-    println!("{} wants to chat with {}", own_name, other_name);
-    
-    let other_id = get_id_by_name(&other_name, &users).unwrap();
-    let mut ids = Vec::new();
-    ids.push(own_user_id);
-    ids.push(other_id);
-    let master_id = choose_master(ids);
-    let master_ip = get_address_by_id(own_user_id, &users).unwrap();
-
-    println!("master_id: {}, master_ip={}", master_id, &master_ip);
-
-    let other_sender = get_sender_by_id(&users, other_id).expect("cannot communicate with other client");
-	let mut other_selection_result = MasterSelectionResult{chat_partner_name: own_name, target_ip: master_ip.clone(), is_own_ip: false};
-	if master_id == other_id {
-		other_selection_result.is_own_ip = true;
-	}
-    other_sender.send(other_selection_result).unwrap();
-    
-    println!("result sent to other party");
-    
-    let mut selection_result = MasterSelectionResult{chat_partner_name: other_name, target_ip: master_ip, is_own_ip: false};
-    if master_id == own_user_id {
-        selection_result.is_own_ip = true;
-    }
-
-    // it's neccessary to go via the channel to terminate our own receiver thread
-    let own_sender = get_sender_by_id(&users, own_user_id).expect("cant obtain own sender channel");
-    own_sender.send(selection_result).unwrap();
+    info!("{} registered accounts, guests are {}", accounts.count(), if config.auth.require_auth { "not allowed" } else { "allowed" });
 
-    false
-}
-
-fn get_address_by_id(id: u8, users: &Arc<Mutex<Vec<User>>>) -> Option<String> {
-    let user_vec = users.lock().unwrap();
-    match user_vec.iter().find(|u| u.id == id) {
-        Some(user) => Some(user.ip_address.clone()),
-        None => None
-    }
-}
-
-fn get_sender_by_id(users: &Arc<Mutex<Vec<User>>>, id: u8) -> Option<Sender<MasterSelectionResult>> {
-    let user_vec = users.lock().unwrap();
-    match user_vec.iter().find(|u| u.id == id) {
-        Some(user) =>  {
-            user.get_sender()
-        },
-        None => None
-    }
-}
-
-fn get_id_by_name(name: &String, users: &Arc<Mutex<Vec<User>>>) -> Option<u8> {
-	let user_vec = users.lock().unwrap();
-	match user_vec.iter().find(|u| &u.name == name) {
-		Some(user) => Some(user.id),
-		None => None
-	}
-}
-
-fn choose_master(ids: Vec<u8>) -> u8 {
-	let mut rng = thread_rng();
-	let selection_id: usize = rng.gen_range(0, ids.len());
-	ids[selection_id]
-}
-
-fn room_mode(stream: &mut TcpStream, rooms: &Arc<Mutex<Vec<ChatRoom>>>) -> bool {
-    let room_names = get_room_info(&rooms);
-    send_string_vec(room_names, stream);
-
-    let room_name_buffer = vec!(0; ChatRoom::NAME_SIZE);
-    let room_name = receive_string(stream, room_name_buffer);
-    match room_name {
-        Some(name) => println!("user wants to join {}", name),
-        None => println!("no room name available")
-    }
-    true
-}
-
-fn check_for_users(users: &Arc<Mutex<Vec<User>>>) -> bool {
-    let user_vec = users.lock().unwrap();
-    user_vec.len() <= 1
-}
-
-fn get_name_by_id(id: u8, users: &Arc<Mutex<Vec<User>>>) -> Option<String> {
-    let user_vec = users.lock().unwrap();
-    match user_vec.iter().find(|u| u.id == id) {
-        Some(user) => Some(user.name.clone()),
-        None => None
-    }
-}
+    let identity = match Identity::load_or_generate(&config.key_file) {
+        Ok(identity) => identity,
+        Err(e) => {
+            error!("failed to load the server identity from {}: {}", config.key_file.display(), e);
+            process::exit(1);
+        }
+    };
+    info!("server key: {}", identity.public_key());
 
-fn remove_user(id: u8, users: &Arc<Mutex<Vec<User>>>) -> Option<User> {
-    let mut user_vec = users.lock().unwrap();
-    for i in 0..user_vec.len() {
-        if user_vec[i].id == id {
-            return Some(user_vec.swap_remove(i))
+    let tls = match config.tls.as_ref().map(|tls| tls.acceptor()).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("failed to set up TLS: {}", e);
+            process::exit(1);
         }
+    };
+    if tls.is_none() {
+        warn!("TLS is off, names and addresses go over the network in plain text");
     }
-    None
-}
-
-fn create_and_add_user(user_name: String, ip_address: String, users: &Arc<Mutex<Vec<User>>>) -> u8 {
-    let mut user_vec = users.lock().unwrap();
-    let user_id = user_vec.len() as u8;
-    let user = User{id: user_id, name: user_name, ip_address: ip_address, sender: None};
-    user_vec.push(user);
-    user_id
-}
-
-fn create_chat_room(room_name: String, room_vec: &mut Vec<ChatRoom>) {
-    let room_id = room_vec.len() as u8;
-    room_vec.push(ChatRoom{id: room_id, current_user: 0, name: room_name});
-}
 
-// TODO: i bet i can generify the return type
-fn receive_string(stream: &mut TcpStream, mut buffer: Vec<u8>) -> Option<String> {
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            if size == 0 {
-                None
-            } else {
-                let name: String = bincode::deserialize(&buffer).unwrap();
-                Some(name)
+    let mut listeners = Vec::new();
+    for address in &config.listen {
+        match bind(*address) {
+            Ok(listener) => listeners.push((*address, listener)),
+            Err(e) => {
+                error!("failed to listen on {}: {}", address, e);
+                process::exit(1);
             }
-        },
-        Err(e) => {
-            println!("error reading the room name from client {}: {}", stream.peer_addr().unwrap(), e);
-            None
         }
     }
+
+    let user_available = registry.user_available();
+    let server = Arc::new(Server{
+        registry: RegistryHandle::spawn(registry),
+        user_available,
+        heartbeat: config.heartbeat,
+        accounts,
+        require_auth: config.auth.require_auth,
+        identity,
+        tls
+    });
+
+    let accept_loops: Vec<_> = listeners.into_iter()
+        .map(|(address, listener)| {
+            info!("server listening on {}", address);
+            tokio::spawn(accept_clients(listener, Arc::clone(&server)))
+        })
+        .collect();
+    for accept_loop in accept_loops {
+        let _ = accept_loop.await;
+    }
 }
 
-fn receive_chat_mode(stream: &mut TcpStream) -> Option<ChatMode> {
-    let mut buffer = vec!(0; ChatMode::SIZE);
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            if size == 0 {
-                None
-            } else {
-                let mode: ChatMode = bincode::deserialize(&buffer).unwrap();
-                Some(mode)
+/// IPv6 listeners only take IPv6, so [::] and 0.0.0.0 can be given side by side on the same port.
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(StdTcpListener::from(socket))
+}
+
+async fn accept_clients(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(connection::handle_client(stream, peer, Arc::clone(&server)));
+            },
+            Err(e) => {
+                warn!("error: {}", e);
             }
-        },
-        Err(e) => {
-            println!("error reading the chat mode from the client {}: {}", stream.peer_addr().unwrap(), e);
-            None
         }
     }
 }
 
-fn send_string_vec(room_names: Vec<String>, stream: &mut TcpStream) {
-    let serialized_names = bincode::serialize(&room_names).unwrap();
-    stream.write(&serialized_names).unwrap();
+/// What other connections hand to a client's connection.
+enum Outgoing {
+    Envelope(ServerEnvelope),
+    /// Our half of a chat someone else asked for. The requester only gets its half once ours
+    /// reached our client, so nobody ends up waiting for a partner that is already gone.
+    Pairing{selection: MasterSelectionResult, requester: PendingPairing}
 }
 
-fn get_room_info(rooms: &Arc<Mutex<Vec<ChatRoom>>>) -> Vec<String> {
-    let room_vec = rooms.lock().unwrap();
-    let mut room_names: Vec<String> = Vec::new();
-    for room in room_vec.iter() {
-        room_names.push(String::from(room.name.as_str()));
-    }
-    room_names
+/// The user waiting for the outcome of a chat request.
+/// If this is dropped without being completed, the requester is told the partner went away
+/// and may be picked by others again.
+struct PendingPairing {
+    registry: RegistryHandle,
+    requester_id: UserId,
+    sender: UnboundedSender<Outgoing>,
+    request_id: RequestId,
+    partner_name: String,
+    selection: Option<MasterSelectionResult>
 }
 
-fn get_user_names(users: &Arc<Mutex<Vec<User>>>) -> Vec<String> {
-    let user_vec = users.lock().unwrap();
-    let mut user_names: Vec<String> = Vec::new();
-    for user in user_vec.iter() {
-        user_names.push(String::from(user.name.as_str()))
+impl PendingPairing {
+    fn complete(mut self) {
+        if let Some(selection) = self.selection.take() {
+            // the requester might be gone as well, then there is nobody left to tell
+            let _ = self.sender.send(Outgoing::Envelope(ServerEnvelope::response(self.request_id, ServerMessage::MasterSelection(selection))));
+        }
     }
-    user_names
 }
 
-fn receive_login_request(stream: &mut TcpStream) -> Option<LoginRequest> {
-    let mut read_data = vec!(0; LoginRequest::SIZE);
-    match stream.read(&mut read_data) {
-        Ok(size) => {
-            if size == 0 {
-                None
-            } else {
-                let request: LoginRequest = bincode::deserialize(&read_data).unwrap();
-                Some(request)
-            }
-        },
-        Err(e) => {
-            println!("error reading the login request from client {}: {}", stream.peer_addr().unwrap(), e);
-            None
+impl Drop for PendingPairing {
+    fn drop(&mut self) {
+        if self.selection.take().is_some() {
+            self.registry.release(self.requester_id);
+            let error = ServerMessage::Error(format!("{} is not available anymore", self.partner_name));
+            let _ = self.sender.send(Outgoing::Envelope(ServerEnvelope::response(self.request_id, error)));
         }
     }
 }
@@ -0,0 +1,1506 @@
+//! Users and rooms, owned by a single task.
+//!
+//! Connections talk to it through a RegistryHandle. Commands are processed one after another,
+//! so every operation, pairing two users included, happens as a whole or not at all.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Notify};
+use common::{BacklogEntry, ChatRoom, MasterSelectionResult, Message, RoomBan, RoomInvite, UserId, RoomId};
+use common::identity::PublicKey;
+use common::protocol::{ServerEnvelope, ServerMessage, Access, HistoryMessage, Moderation, NewRoom, RoomRole, RoomEvent, RoomSummary, UserSummary, Visibility};
+use common::validation::{self, ValidationError, MAX_USER_NAME_BYTES};
+use rand::{thread_rng, Rng};
+use crate::Outgoing;
+
+/// How many free names we offer when the one asked for is taken.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// A logged in client. Everything meant for it goes through `sender` to its connection.
+pub struct User {
+    pub id: UserId,
+    pub name: String,
+    pub ip_address: String,
+    /// Where the user accepts direct chats
+    pub p2p_port: u16,
+    pub sender: UnboundedSender<Outgoing>,
+    /// Logged in with the password of a registered account, not as a guest
+    pub verified: bool,
+    /// Proven during the connection's handshake, handed to chat partners
+    pub public_key: PublicKey,
+    /// False while the user is being paired or already is, nobody else may pick it then.
+    /// Members of a room are not available either
+    pub available: bool,
+    pub room: Option<RoomId>
+}
+
+impl User {
+    fn summary(&self) -> UserSummary {
+        UserSummary{name: self.name.clone(), verified: self.verified}
+    }
+}
+
+/// Both halves of a chat, decided on in one go.
+pub struct Pairing {
+    pub master_id: UserId,
+    pub other_sender: UnboundedSender<Outgoing>,
+    pub own_selection: MasterSelectionResult,
+    pub other_selection: MasterSelectionResult
+}
+
+#[derive(PartialEq, Debug)]
+pub enum PairingError {
+    UnknownUser,
+    NotAvailable
+}
+
+/// Somebody else already goes by that name.
+#[derive(PartialEq, Debug)]
+pub struct NameTaken {
+    /// Free names close to the one asked for
+    pub suggestions: Vec<String>
+}
+
+/// Why a user could not be added.
+#[derive(PartialEq, Debug)]
+pub enum AddUserError {
+    NameTaken(NameTaken),
+    /// As many users as the server takes are logged in
+    ServerFull
+}
+
+#[derive(PartialEq, Debug)]
+pub enum RoomError {
+    InvalidName(ValidationError),
+    TooManyRooms,
+    UnknownRoom,
+    NotInRoom,
+    NameTaken,
+    WrongPassword,
+    NotOwner,
+    NotOperator,
+    /// Name of whoever a moderation was aimed at, for this and the ones below
+    UnknownUser(String),
+    NotAMember(String),
+    Outranked(String),
+    Banned,
+    Muted,
+    NotInvited
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoomError::InvalidName(e) => write!(f, "room name {}", e),
+            RoomError::TooManyRooms => write!(f, "there are as many rooms as the server allows"),
+            RoomError::UnknownRoom => write!(f, "there is no such room"),
+            RoomError::NotInRoom => write!(f, "not in a room"),
+            RoomError::NameTaken => write!(f, "there already is a room with that name"),
+            RoomError::WrongPassword => write!(f, "wrong password"),
+            RoomError::NotOwner => write!(f, "not the owner of the room"),
+            RoomError::NotOperator => write!(f, "not an operator of the room"),
+            RoomError::UnknownUser(name) => write!(f, "there is no user named '{}'", name),
+            RoomError::NotAMember(name) => write!(f, "{} is not in the room", name),
+            RoomError::Outranked(name) => write!(f, "{} has a role as high as the moderator's", name),
+            RoomError::Banned => write!(f, "banned from the room"),
+            RoomError::Muted => write!(f, "muted in the room"),
+            RoomError::NotInvited => write!(f, "not invited to the room")
+        }
+    }
+}
+
+/// What a user gets to know about the room they just joined.
+#[derive(PartialEq, Debug)]
+pub struct JoinedRoom {
+    pub name: String,
+    pub topic: Option<String>,
+    /// Everyone in the room, the new member included, in the order they joined.
+    /// At most UserSummary::MAX_LISTED of them
+    pub members: Vec<UserSummary>,
+    /// How many members were left out
+    pub unlisted_members: usize,
+    /// The backlog, oldest first
+    pub history: Vec<HistoryMessage>
+}
+
+/// How much the server takes on, None means no limit.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Limits {
+    pub max_users: Option<usize>,
+    pub max_rooms: Option<usize>
+}
+
+/// How rooms behave.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RoomSettings {
+    /// How long an invite can be used
+    pub invite_lifetime: Duration,
+    /// How many messages each room keeps for whoever joins later, 0 keeps none
+    pub backlog_size: usize,
+    /// Older messages are dropped from the backlog
+    pub backlog_age: Duration,
+    /// Registered users who become operators of the rooms the server starts with once they join one, keyed by name_key()
+    pub operators: Vec<String>
+}
+
+impl RoomSettings {
+    pub const DEFAULT_INVITE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+    pub const DEFAULT_BACKLOG_SIZE: usize = 50;
+    pub const DEFAULT_BACKLOG_AGE: Duration = Duration::from_secs(60 * 60);
+}
+
+impl Default for RoomSettings {
+    fn default() -> RoomSettings {
+        RoomSettings{
+            invite_lifetime: RoomSettings::DEFAULT_INVITE_LIFETIME,
+            backlog_size: RoomSettings::DEFAULT_BACKLOG_SIZE,
+            backlog_age: RoomSettings::DEFAULT_BACKLOG_AGE,
+            operators: Vec::new()
+        }
+    }
+}
+
+pub struct Registry {
+    limits: Limits,
+    settings: RoomSettings,
+    users: HashMap<UserId, User>,
+    /// Keyed by name_key(), so "Bob" and "bob" can't both be around.
+    ids_by_name: HashMap<String, UserId>,
+    rooms: HashMap<RoomId, ChatRoom>,
+    /// Same as ids_by_name, for rooms
+    room_ids_by_name: HashMap<String, RoomId>,
+    /// Fired whenever someone could be asked for a direct chat again, or for the first time
+    user_available: Arc<Notify>,
+    next_user_id: UserId,
+    next_room_id: RoomId
+}
+
+impl Default for Registry {
+    /// Without any limits.
+    fn default() -> Registry {
+        Registry::new(Limits::default(), RoomSettings::default())
+    }
+}
+
+impl Registry {
+    pub fn new(limits: Limits, settings: RoomSettings) -> Registry {
+        Registry{
+            limits, settings, users: HashMap::new(), ids_by_name: HashMap::new(), rooms: HashMap::new(),
+            room_ids_by_name: HashMap::new(), user_available: Arc::new(Notify::new()),
+            next_user_id: UserId::FIRST, next_room_id: RoomId::FIRST
+        }
+    }
+
+    /// Connections waiting for someone to chat with wait for this.
+    pub fn user_available(&self) -> Arc<Notify> {
+        Arc::clone(&self.user_available)
+    }
+
+    pub fn add_user(&mut self, name: String, ip_address: String, p2p_port: u16, sender: UnboundedSender<Outgoing>, verified: bool, public_key: PublicKey) -> Result<UserId, AddUserError> {
+        if self.ids_by_name.contains_key(&name_key(&name)) {
+            return Err(AddUserError::NameTaken(NameTaken{suggestions: self.suggest_names(&name)}));
+        }
+        if self.limits.max_users.is_some_and(|max_users| self.users.len() >= max_users) {
+            return Err(AddUserError::ServerFull);
+        }
+        let id = self.next_user_id;
+        self.next_user_id = id.next();
+        self.ids_by_name.insert(name_key(&name), id);
+        self.users.insert(id, User{id, name, ip_address, p2p_port, sender, verified, public_key, available: true, room: None});
+        self.user_available.notify_waiters();
+        Ok(id)
+    }
+
+    /// Leaves the user's room on the way out.
+    pub fn remove_user(&mut self, id: UserId) -> Option<User> {
+        self.leave_room(id);
+        let user = self.users.remove(&id)?;
+        self.ids_by_name.remove(&name_key(&user.name));
+        Some(user)
+    }
+
+    /// Gives the user a new name and tells everyone else about it.
+    /// Returns the old name, None if there is no such user.
+    pub fn rename(&mut self, id: UserId, new_name: String) -> Result<Option<String>, NameTaken> {
+        match self.ids_by_name.get(&name_key(&new_name)) {
+            // changing the case of one's own name is fine
+            Some(holder) if *holder != id => return Err(NameTaken{suggestions: self.suggest_names(&new_name)}),
+            _ => ()
+        }
+        let user = match self.users.get_mut(&id) {
+            Some(user) => user,
+            None => return Ok(None)
+        };
+        let old_name = std::mem::replace(&mut user.name, new_name.clone());
+        self.ids_by_name.remove(&name_key(&old_name));
+        self.ids_by_name.insert(name_key(&new_name), id);
+
+        for user in self.users.values().filter(|user| user.id != id) {
+            let renamed = ServerMessage::Renamed{old_name: old_name.clone(), new_name: new_name.clone()};
+            // whoever is gone doesn't need to know
+            let _ = user.sender.send(Outgoing::Envelope(ServerEnvelope::push(renamed)));
+        }
+        Ok(Some(old_name))
+    }
+
+    pub fn get_user(&self, id: UserId) -> Option<&User> {
+        self.users.get(&id)
+    }
+
+    pub fn get_id_by_name(&self, name: &str) -> Option<UserId> {
+        self.ids_by_name.get(&name_key(name)).copied()
+    }
+
+    /// The name with a number appended, shortened if it wouldn't fit otherwise.
+    fn suggest_names(&self, name: &str) -> Vec<String> {
+        (2..u32::MAX)
+            .map(|number| {
+                let suffix = number.to_string();
+                let mut end = name.len().min(MAX_USER_NAME_BYTES - suffix.len());
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("{}{}", &name[..end], suffix)
+            })
+            .filter(|candidate| validation::validate_user_name(candidate).is_ok() && !self.ids_by_name.contains_key(&name_key(candidate)))
+            .take(MAX_SUGGESTIONS)
+            .collect()
+    }
+
+    /// Everyone who could be asked for a chat right now, as many as fit a UserList and how many did not.
+    pub fn available_users(&self) -> (Vec<UserSummary>, usize) {
+        let mut users: Vec<&User> = self.users.values().filter(|user| user.available).collect();
+        users.sort_by_key(|user| user.id);
+        let unlisted = users.len().saturating_sub(UserSummary::MAX_LISTED);
+        let listed = users.into_iter().take(UserSummary::MAX_LISTED).map(|user| UserSummary{name: user.name.clone(), verified: user.verified}).collect();
+        (listed, unlisted)
+    }
+
+    /// Room names are unique regardless of case, like user names.
+    /// Only the name is checked, topic and password are up to the caller.
+    pub fn create_room(&mut self, new_room: NewRoom, owner: Option<PublicKey>) -> Result<RoomId, RoomError> {
+        let NewRoom{name, topic, access, visibility} = new_room;
+        let room = ChatRoom{
+            id: self.next_room_id, members: Vec::new(), name, topic, access, visibility, owner,
+            operators: Vec::new(), muted: Vec::new(), bans: Vec::new(), invites: Vec::new(),
+            backlog: VecDeque::new()
+        };
+        room.validate().map_err(RoomError::InvalidName)?;
+        if self.find_room(&room.name).is_some() {
+            return Err(RoomError::NameTaken);
+        }
+        if self.limits.max_rooms.is_some_and(|max_rooms| self.rooms.len() >= max_rooms) {
+            return Err(RoomError::TooManyRooms);
+        }
+        self.next_room_id = room.id.next();
+        let id = room.id;
+        self.room_ids_by_name.insert(name_key(&room.name), id);
+        self.rooms.insert(id, room);
+        Ok(id)
+    }
+
+    /// Creates a room owned by the user and moves the user in.
+    pub fn create_room_for(&mut self, id: UserId, new_room: NewRoom) -> Result<JoinedRoom, RoomError> {
+        let owner = self.users.get(&id).ok_or(RoomError::NotInRoom)?.public_key;
+        let room_id = self.create_room(new_room, Some(owner))?;
+        let name = self.rooms[&room_id].name.clone();
+        self.join_room(id, &name, None)
+    }
+
+    fn find_room(&self, name: &str) -> Option<RoomId> {
+        self.room_ids_by_name.get(&name_key(name)).copied()
+    }
+
+    /// Public rooms in the order they were created, as many as fit a RoomList and how many did not.
+    pub fn room_list(&self) -> (Vec<RoomSummary>, usize) {
+        let mut rooms: Vec<&ChatRoom> = self.rooms.values().filter(|room| room.visibility == Visibility::Public).collect();
+        rooms.sort_by_key(|room| room.id);
+        let unlisted = rooms.len().saturating_sub(RoomSummary::MAX_LISTED);
+        (rooms.into_iter().take(RoomSummary::MAX_LISTED).map(ChatRoom::summary).collect(), unlisted)
+    }
+
+    /// Moves the user into the room, out of the one it was in before. Everyone in there is told.
+    /// Nobody can ask a member of a room for a direct chat.
+    /// The owner gets in regardless of the access mode, so does anyone invited, which uses up the invite.
+    pub fn join_room(&mut self, id: UserId, room_name: &str, password: Option<&str>) -> Result<JoinedRoom, RoomError> {
+        let room_id = self.find_room(room_name).ok_or(RoomError::UnknownRoom)?;
+        let user = self.users.get(&id).ok_or(RoomError::NotInRoom)?;
+        if user.room == Some(room_id) {
+            return Ok(self.joined_room(room_id));
+        }
+        let room = self.rooms.get_mut(&room_id).expect("found above");
+        if room.is_banned(&user.public_key, &user.ip_address) {
+            return Err(RoomError::Banned);
+        }
+        let now = Instant::now();
+        room.invites.retain(|invite| invite.expires > now);
+        let invited = room.invites.iter().any(|invite| invite.key == user.public_key);
+        if room.owner != Some(user.public_key) && !invited {
+            match &room.access {
+                Access::Open => (),
+                Access::Password(expected) if password == Some(expected.as_str()) => (),
+                Access::Password(_) => return Err(RoomError::WrongPassword),
+                Access::InviteOnly => return Err(RoomError::NotInvited)
+            }
+        }
+        let key = user.public_key;
+        room.invites.retain(|invite| invite.key != key);
+        // nobody owns the rooms the server starts with, the configured operators keep order in there
+        if room.owner.is_none() && user.verified && self.settings.operators.contains(&name_key(&user.name)) && !room.operators.contains(&key) {
+            room.operators.push(key);
+        }
+        self.leave_room(id);
+
+        let user = self.users.get_mut(&id).expect("checked above");
+        user.room = Some(room_id);
+        user.available = false;
+        let joined = ServerMessage::RoomEvent(RoomEvent::Joined(user.summary()));
+        self.broadcast(room_id, id, joined);
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.members.push(id);
+        }
+        Ok(self.joined_room(room_id))
+    }
+
+    /// The history is cut short where it would no longer fit in one frame along with the members, the latest messages are kept.
+    fn joined_room(&self, room_id: RoomId) -> JoinedRoom {
+        let room = &self.rooms[&room_id];
+        let unlisted_members = room.members.len().saturating_sub(UserSummary::MAX_LISTED);
+        let members: Vec<UserSummary> = room.members.iter().take(UserSummary::MAX_LISTED).filter_map(|id| self.users.get(id)).map(User::summary).collect();
+        let budget = HistoryMessage::budget(&room.name, &room.topic, &members, unlisted_members);
+        let now = Instant::now();
+        let mut history = Vec::new();
+        let mut history_bytes = 0;
+        for entry in room.backlog.iter().rev() {
+            let age = now.duration_since(entry.sent);
+            if age > self.settings.backlog_age {
+                break;
+            }
+            let message = HistoryMessage{sender: entry.sender.clone(), message: entry.message.clone(), age_secs: age.as_secs()};
+            history_bytes += message.encoded_size();
+            if history_bytes > budget {
+                break;
+            }
+            history.push(message);
+        }
+        history.reverse();
+        JoinedRoom{name: room.name.clone(), topic: room.topic.clone(), members, unlisted_members, history}
+    }
+
+    /// The room of that name, if the user's role in it is `role` or higher, along with the user's name and role.
+    fn room_with_role(&self, id: UserId, room_name: &str, role: RoomRole) -> Result<(RoomId, String, RoomRole), RoomError> {
+        let room_id = self.find_room(room_name).ok_or(RoomError::UnknownRoom)?;
+        let user = self.users.get(&id).map(|user| (user.name.clone(), self.rooms[&room_id].role(&user.public_key)));
+        match user {
+            Some((name, own_role)) if own_role >= role => Ok((room_id, name, own_role)),
+            _ if role == RoomRole::Owner => Err(RoomError::NotOwner),
+            _ => Err(RoomError::NotOperator)
+        }
+    }
+
+    fn owned_room(&self, id: UserId, room_name: &str) -> Result<(RoomId, String), RoomError> {
+        self.room_with_role(id, room_name, RoomRole::Owner).map(|(room_id, name, _)| (room_id, name))
+    }
+
+    /// Throws everyone out of the room, the owner included, and returns its name.
+    pub fn delete_room(&mut self, id: UserId, room_name: &str) -> Result<String, RoomError> {
+        let (room_id, owner_name) = self.owned_room(id, room_name)?;
+        self.broadcast(room_id, id, ServerMessage::RoomEvent(RoomEvent::Deleted{by: owner_name}));
+        let room = self.rooms.remove(&room_id).expect("found above");
+        self.room_ids_by_name.remove(&name_key(&room.name));
+        for member in &room.members {
+            let member = match self.users.get_mut(member) {
+                Some(member) => member,
+                None => continue
+            };
+            member.room = None;
+            member.available = true;
+            // whoever is gone doesn't need to know
+            let _ = member.sender.send(Outgoing::Envelope(ServerEnvelope::push(ServerMessage::LeftRoom(room.name.clone()))));
+        }
+        self.user_available.notify_waiters();
+        Ok(room.name)
+    }
+
+    /// Returns the name the room had.
+    pub fn rename_room(&mut self, id: UserId, room_name: &str, new_name: String) -> Result<String, RoomError> {
+        let (room_id, owner_name) = self.owned_room(id, room_name)?;
+        validation::validate_room_name(&new_name).map_err(RoomError::InvalidName)?;
+        match self.find_room(&new_name) {
+            // changing the case of the room's own name is fine
+            Some(other) if other != room_id => return Err(RoomError::NameTaken),
+            _ => ()
+        }
+        let room = self.rooms.get_mut(&room_id).expect("found above");
+        let old_name = std::mem::replace(&mut room.name, new_name.clone());
+        self.room_ids_by_name.remove(&name_key(&old_name));
+        self.room_ids_by_name.insert(name_key(&new_name), room_id);
+        self.broadcast(room_id, id, ServerMessage::RoomEvent(RoomEvent::Renamed{by: owner_name, new_name}));
+        Ok(old_name)
+    }
+
+    /// None clears the topic, which is expected to be valid. Returns the name of the room.
+    pub fn set_topic(&mut self, id: UserId, room_name: &str, topic: Option<String>) -> Result<String, RoomError> {
+        let (room_id, operator_name, _) = self.room_with_role(id, room_name, RoomRole::Operator)?;
+        let room = self.rooms.get_mut(&room_id).expect("found above");
+        room.topic = topic.clone();
+        let name = room.name.clone();
+        self.broadcast(room_id, id, ServerMessage::RoomEvent(RoomEvent::TopicChanged{by: operator_name, topic}));
+        Ok(name)
+    }
+
+    /// Carries out what an operator or the owner asked for and tells everyone else in the room.
+    /// Only Unban works on users who are not logged in, Ban works on users who are not in the room.
+    pub fn moderate(&mut self, id: UserId, room_name: &str, action: Moderation) -> Result<(), RoomError> {
+        let (room_id, operator_name, operator_role) = self.room_with_role(id, room_name, RoomRole::Operator)?;
+        if let Moderation::SetRole{..} = action {
+            if operator_role != RoomRole::Owner {
+                return Err(RoomError::NotOwner);
+            }
+        }
+        let target = action.target().to_string();
+        if let Moderation::Unban(name) = &action {
+            let room = self.rooms.get_mut(&room_id).expect("found above");
+            room.bans.retain(|ban| name_key(&ban.name) != name_key(name));
+            self.broadcast(room_id, id, ServerMessage::RoomEvent(RoomEvent::Moderated{by: operator_name, action}));
+            return Ok(());
+        }
+
+        let target_id = self.get_id_by_name(&target).ok_or_else(|| RoomError::UnknownUser(target.clone()))?;
+        let user = &self.users[&target_id];
+        let (key, ip_address, in_room) = (user.public_key, user.ip_address.clone(), user.room == Some(room_id));
+        let room = self.rooms.get_mut(&room_id).expect("found above");
+        if room.role(&key) >= operator_role {
+            return Err(RoomError::Outranked(target));
+        }
+        let needs_member = !matches!(action, Moderation::Ban{..} | Moderation::Mute(_) | Moderation::Unmute(_));
+        if needs_member && !in_room {
+            return Err(RoomError::NotAMember(target));
+        }
+        match &action {
+            Moderation::Kick(_) => (),
+            Moderation::Ban{by_ip, ..} => {
+                // banning someone again replaces the ban, with the name and address they have now
+                room.bans.retain(|ban| ban.key != key);
+                room.bans.push(RoomBan{name: target, key, ip_address: by_ip.then_some(ip_address)});
+            },
+            Moderation::Mute(_) if !room.muted.contains(&key) => room.muted.push(key),
+            Moderation::Mute(_) => (),
+            Moderation::Unmute(_) => room.muted.retain(|muted| *muted != key),
+            Moderation::SetRole{role: RoomRole::Owner, ..} => {
+                room.operators.retain(|operator| *operator != key);
+                let old_owner = room.owner.replace(key);
+                room.operators.extend(old_owner);
+            },
+            Moderation::SetRole{role: RoomRole::Operator, ..} if !room.operators.contains(&key) => room.operators.push(key),
+            Moderation::SetRole{role: RoomRole::Operator, ..} => (),
+            Moderation::SetRole{role: RoomRole::Member, ..} => room.operators.retain(|operator| *operator != key),
+            Moderation::Unban(_) => unreachable!("handled above")
+        }
+
+        let throw_out = matches!(action, Moderation::Kick(_) | Moderation::Ban{..}) && in_room;
+        self.broadcast(room_id, id, ServerMessage::RoomEvent(RoomEvent::Moderated{by: operator_name, action}));
+        if throw_out {
+            self.throw_out(room_id, target_id);
+        }
+        Ok(())
+    }
+
+    /// Lets the user of that name join the inviter's room once, for as long as the settings say.
+    /// The invite is pushed to the user right away. Inviting someone again starts over.
+    pub fn invite(&mut self, id: UserId, room_name: &str, name: &str) -> Result<(), RoomError> {
+        let room_id = self.find_room(room_name).ok_or(RoomError::UnknownRoom)?;
+        let inviter = self.users.get(&id).ok_or(RoomError::NotInRoom)?;
+        if inviter.room != Some(room_id) {
+            return Err(RoomError::NotInRoom);
+        }
+        let users = &self.users;
+        let user = self.get_id_by_name(name).and_then(|id| users.get(&id)).ok_or_else(|| RoomError::UnknownUser(name.to_string()))?;
+        let room = self.rooms.get_mut(&room_id).expect("found above");
+        let now = Instant::now();
+        room.invites.retain(|invite| invite.expires > now && invite.key != user.public_key);
+        room.invites.push(RoomInvite{key: user.public_key, expires: now + self.settings.invite_lifetime});
+        let invited = ServerMessage::Invited{room: room.name.clone(), by: inviter.name.clone(), expires_in_secs: self.settings.invite_lifetime.as_secs()};
+        // whoever is gone doesn't need to know
+        let _ = user.sender.send(Outgoing::Envelope(ServerEnvelope::push(invited)));
+        Ok(())
+    }
+
+    /// Takes the user out of the room without telling anyone but the user.
+    /// A room someone created is gone if that was its last member, like on leave_room().
+    fn throw_out(&mut self, room_id: RoomId, id: UserId) {
+        let (room, user) = match (self.rooms.get_mut(&room_id), self.users.get_mut(&id)) {
+            (Some(room), Some(user)) => (room, user),
+            _ => return
+        };
+        room.members.retain(|member| *member != id);
+        user.room = None;
+        user.available = true;
+        // whoever is gone doesn't need to know
+        let _ = user.sender.send(Outgoing::Envelope(ServerEnvelope::push(ServerMessage::LeftRoom(room.name.clone()))));
+        self.user_available.notify_waiters();
+        self.drop_if_abandoned(room_id);
+    }
+
+    /// Drops the room if nobody is left in there and a user created it. Returns whether it did.
+    fn drop_if_abandoned(&mut self, room_id: RoomId) -> bool {
+        let room = match self.rooms.get(&room_id) {
+            Some(room) if room.members.is_empty() && room.owner.is_some() => room,
+            _ => return false
+        };
+        self.room_ids_by_name.remove(&name_key(&room.name));
+        self.rooms.remove(&room_id);
+        true
+    }
+
+    /// Returns the name of the room the user left, None if it was in none.
+    /// Whoever is left in there is told, the user is available again.
+    /// Rooms users created are gone once the last member leaves, only the ones the server started with stay.
+    pub fn leave_room(&mut self, id: UserId) -> Option<String> {
+        let user = self.users.get_mut(&id)?;
+        let room_id = user.room.take()?;
+        user.available = true;
+        self.user_available.notify_waiters();
+        let left = ServerMessage::RoomEvent(RoomEvent::Left(user.name.clone()));
+        let room = self.rooms.get_mut(&room_id)?;
+        room.members.retain(|member| *member != id);
+        let room_name = room.name.clone();
+        if !self.drop_if_abandoned(room_id) {
+            self.broadcast(room_id, id, left);
+        }
+        Some(room_name)
+    }
+
+    /// Hands the message to everyone else in the sender's room.
+    pub fn send_to_room(&mut self, id: UserId, message: Message) -> Result<(), RoomError> {
+        let user = self.users.get(&id).ok_or(RoomError::NotInRoom)?;
+        let room_id = user.room.ok_or(RoomError::NotInRoom)?;
+        if self.rooms.get(&room_id).is_some_and(|room| room.muted.contains(&user.public_key)) {
+            return Err(RoomError::Muted);
+        }
+        let sender = user.name.clone();
+        self.broadcast(room_id, id, ServerMessage::RoomMessage{sender: sender.clone(), message: message.clone()});
+        self.keep_in_backlog(room_id, sender, message);
+        Ok(())
+    }
+
+    /// Drops whatever no longer fits the settings to make room.
+    fn keep_in_backlog(&mut self, room_id: RoomId, sender: String, message: Message) {
+        let (backlog_size, backlog_age) = (self.settings.backlog_size, self.settings.backlog_age);
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(room) => room,
+            None => return
+        };
+        let now = Instant::now();
+        room.backlog.push_back(BacklogEntry{sender, message, sent: now});
+        while room.backlog.len() > backlog_size
+            || room.backlog.front().is_some_and(|entry| now.duration_since(entry.sent) > backlog_age) {
+            room.backlog.pop_front();
+        }
+    }
+
+    fn broadcast(&self, room_id: RoomId, except: UserId, message: ServerMessage) {
+        let room = match self.rooms.get(&room_id) {
+            Some(room) => room,
+            None => return
+        };
+        for member in room.members.iter().filter(|member| **member != except).filter_map(|member| self.users.get(member)) {
+            // whoever is gone doesn't need to know
+            let _ = member.sender.send(Outgoing::Envelope(ServerEnvelope::push(message.clone())));
+        }
+    }
+
+    /// Picks a master and takes both users off the market.
+    pub fn pair(&mut self, requester_id: UserId, other_name: &str) -> Result<Pairing, PairingError> {
+        let other_id = match self.get_id_by_name(other_name) {
+            Some(id) if id != requester_id => id,
+            _ => return Err(PairingError::UnknownUser)
+        };
+        let (requester, other) = match (self.users.get(&requester_id), self.users.get(&other_id)) {
+            (Some(requester), Some(other)) if requester.available && other.available => (requester, other),
+            _ => return Err(PairingError::NotAvailable)
+        };
+
+        let master_id = choose_master(vec![requester_id, other_id]);
+        let master = if master_id == requester_id { requester } else { other };
+        let (master_ip, master_port) = (master.ip_address.clone(), master.p2p_port);
+        let pairing = Pairing{
+            master_id,
+            other_sender: other.sender.clone(),
+            own_selection: MasterSelectionResult{
+                chat_partner_name: other.name.clone(), chat_partner_verified: other.verified, chat_partner_key: other.public_key,
+                target_ip: master_ip.clone(), target_port: master_port, is_own_ip: master_id == requester_id
+            },
+            other_selection: MasterSelectionResult{
+                chat_partner_name: requester.name.clone(), chat_partner_verified: requester.verified, chat_partner_key: requester.public_key,
+                target_ip: master_ip, target_port: master_port, is_own_ip: master_id == other_id
+            }
+        };
+
+        for id in [requester_id, other_id].iter() {
+            if let Some(user) = self.users.get_mut(id) {
+                user.available = false;
+            }
+        }
+        Ok(pairing)
+    }
+
+    /// Makes a user available again after a pairing fell through.
+    pub fn release(&mut self, id: UserId) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.available = true;
+            self.user_available.notify_waiters();
+        }
+    }
+}
+
+/// Names differing only in case belong to the same user.
+pub fn name_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+fn choose_master(ids: Vec<UserId>) -> UserId {
+	let mut rng = thread_rng();
+	let selection_id: usize = rng.gen_range(0, ids.len());
+	ids[selection_id]
+}
+
+enum Command {
+    AddUser{name: String, ip_address: String, p2p_port: u16, sender: UnboundedSender<Outgoing>, verified: bool, public_key: PublicKey, reply: oneshot::Sender<Result<UserId, AddUserError>>},
+    RemoveUser{id: UserId},
+    Rename{id: UserId, new_name: String, reply: oneshot::Sender<Result<Option<String>, NameTaken>>},
+    Release{id: UserId},
+    GetName{id: UserId, reply: oneshot::Sender<Option<String>>},
+    AvailableUsers{reply: oneshot::Sender<(Vec<UserSummary>, usize)>},
+    RoomList{reply: oneshot::Sender<(Vec<RoomSummary>, usize)>},
+    JoinRoom{id: UserId, room_name: String, password: Option<String>, reply: oneshot::Sender<Result<JoinedRoom, RoomError>>},
+    CreateRoom{id: UserId, new_room: NewRoom, reply: oneshot::Sender<Result<JoinedRoom, RoomError>>},
+    DeleteRoom{id: UserId, room_name: String, reply: oneshot::Sender<Result<String, RoomError>>},
+    RenameRoom{id: UserId, room_name: String, new_name: String, reply: oneshot::Sender<Result<String, RoomError>>},
+    SetTopic{id: UserId, room_name: String, topic: Option<String>, reply: oneshot::Sender<Result<String, RoomError>>},
+    Moderate{id: UserId, room_name: String, action: Moderation, reply: oneshot::Sender<Result<(), RoomError>>},
+    Invite{id: UserId, room_name: String, name: String, reply: oneshot::Sender<Result<(), RoomError>>},
+    LeaveRoom{id: UserId, reply: oneshot::Sender<Option<String>>},
+    SendToRoom{id: UserId, message: Message, reply: oneshot::Sender<Result<(), RoomError>>},
+    Pair{requester_id: UserId, other_name: String, reply: oneshot::Sender<Result<Pairing, PairingError>>}
+}
+
+/// Cheap to clone, every connection has one.
+#[derive(Clone)]
+pub struct RegistryHandle {
+    commands: UnboundedSender<Command>
+}
+
+impl RegistryHandle {
+    /// Moves the registry into its own task.
+    pub fn spawn(registry: Registry) -> RegistryHandle {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(registry, receiver));
+        RegistryHandle{commands}
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> T {
+        let (reply, response) = oneshot::channel();
+        self.send(command(reply));
+        response.await.expect("the registry stopped")
+    }
+
+    /// The registry lives as long as any handle does, it only stops if it panicked.
+    /// Then there is nothing left to update and request() notices the missing reply.
+    fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    pub async fn add_user(&self, name: String, ip_address: String, p2p_port: u16, sender: UnboundedSender<Outgoing>, verified: bool, public_key: PublicKey) -> Result<UserId, AddUserError> {
+        self.request(|reply| Command::AddUser{name, ip_address, p2p_port, sender, verified, public_key, reply}).await
+    }
+
+    pub async fn rename(&self, id: UserId, new_name: String) -> Result<Option<String>, NameTaken> {
+        self.request(|reply| Command::Rename{id, new_name, reply}).await
+    }
+
+    /// Doesn't wait for the registry, so it can be used where awaiting is not possible.
+    pub fn remove_user(&self, id: UserId) {
+        self.send(Command::RemoveUser{id});
+    }
+
+    /// Doesn't wait for the registry, so it can be used where awaiting is not possible.
+    pub fn release(&self, id: UserId) {
+        self.send(Command::Release{id});
+    }
+
+    pub async fn get_name(&self, id: UserId) -> Option<String> {
+        self.request(|reply| Command::GetName{id, reply}).await
+    }
+
+    pub async fn available_users(&self) -> (Vec<UserSummary>, usize) {
+        self.request(|reply| Command::AvailableUsers{reply}).await
+    }
+
+    pub async fn room_list(&self) -> (Vec<RoomSummary>, usize) {
+        self.request(|reply| Command::RoomList{reply}).await
+    }
+
+    pub async fn join_room(&self, id: UserId, room_name: String, password: Option<String>) -> Result<JoinedRoom, RoomError> {
+        self.request(|reply| Command::JoinRoom{id, room_name, password, reply}).await
+    }
+
+    pub async fn create_room(&self, id: UserId, new_room: NewRoom) -> Result<JoinedRoom, RoomError> {
+        self.request(|reply| Command::CreateRoom{id, new_room, reply}).await
+    }
+
+    pub async fn delete_room(&self, id: UserId, room_name: String) -> Result<String, RoomError> {
+        self.request(|reply| Command::DeleteRoom{id, room_name, reply}).await
+    }
+
+    pub async fn rename_room(&self, id: UserId, room_name: String, new_name: String) -> Result<String, RoomError> {
+        self.request(|reply| Command::RenameRoom{id, room_name, new_name, reply}).await
+    }
+
+    pub async fn set_topic(&self, id: UserId, room_name: String, topic: Option<String>) -> Result<String, RoomError> {
+        self.request(|reply| Command::SetTopic{id, room_name, topic, reply}).await
+    }
+
+    pub async fn moderate(&self, id: UserId, room_name: String, action: Moderation) -> Result<(), RoomError> {
+        self.request(|reply| Command::Moderate{id, room_name, action, reply}).await
+    }
+
+    pub async fn invite(&self, id: UserId, room_name: String, name: String) -> Result<(), RoomError> {
+        self.request(|reply| Command::Invite{id, room_name, name, reply}).await
+    }
+
+    pub async fn leave_room(&self, id: UserId) -> Option<String> {
+        self.request(|reply| Command::LeaveRoom{id, reply}).await
+    }
+
+    pub async fn send_to_room(&self, id: UserId, message: Message) -> Result<(), RoomError> {
+        self.request(|reply| Command::SendToRoom{id, message, reply}).await
+    }
+
+    pub async fn pair(&self, requester_id: UserId, other_name: String) -> Result<Pairing, PairingError> {
+        self.request(|reply| Command::Pair{requester_id, other_name, reply}).await
+    }
+}
+
+/// Whoever asked might have given up waiting for the reply, so failed replies are ignored.
+async fn run(mut registry: Registry, mut commands: UnboundedReceiver<Command>) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::AddUser{name, ip_address, p2p_port, sender, verified, public_key, reply} => {
+                let _ = reply.send(registry.add_user(name, ip_address, p2p_port, sender, verified, public_key));
+            },
+            Command::RemoveUser{id} => {
+                registry.remove_user(id);
+            },
+            Command::Rename{id, new_name, reply} => {
+                let _ = reply.send(registry.rename(id, new_name));
+            },
+            Command::Release{id} => registry.release(id),
+            Command::GetName{id, reply} => {
+                let _ = reply.send(registry.get_user(id).map(|user| user.name.clone()));
+            },
+            Command::AvailableUsers{reply} => {
+                let _ = reply.send(registry.available_users());
+            },
+            Command::RoomList{reply} => {
+                let _ = reply.send(registry.room_list());
+            },
+            Command::JoinRoom{id, room_name, password, reply} => {
+                let _ = reply.send(registry.join_room(id, &room_name, password.as_deref()));
+            },
+            Command::CreateRoom{id, new_room, reply} => {
+                let _ = reply.send(registry.create_room_for(id, new_room));
+            },
+            Command::DeleteRoom{id, room_name, reply} => {
+                let _ = reply.send(registry.delete_room(id, &room_name));
+            },
+            Command::RenameRoom{id, room_name, new_name, reply} => {
+                let _ = reply.send(registry.rename_room(id, &room_name, new_name));
+            },
+            Command::SetTopic{id, room_name, topic, reply} => {
+                let _ = reply.send(registry.set_topic(id, &room_name, topic));
+            },
+            Command::Moderate{id, room_name, action, reply} => {
+                let _ = reply.send(registry.moderate(id, &room_name, action));
+            },
+            Command::Invite{id, room_name, name, reply} => {
+                let _ = reply.send(registry.invite(id, &room_name, &name));
+            },
+            Command::LeaveRoom{id, reply} => {
+                let _ = reply.send(registry.leave_room(id));
+            },
+            Command::SendToRoom{id, message, reply} => {
+                let _ = reply.send(registry.send_to_room(id, message));
+            },
+            Command::Pair{requester_id, other_name, reply} => {
+                let _ = reply.send(registry.pair(requester_id, &other_name));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::identity::Identity;
+    use common::codec;
+    use common::protocol::AccessMode;
+    use common::validation::{MAX_MESSAGE_BYTES, MAX_ROOM_NAME_BYTES, MAX_TOPIC_BYTES};
+
+    fn add(registry: &mut Registry, name: &str, ip_address: &str) -> UserId {
+        let (sender, _) = mpsc::unbounded_channel();
+        registry.add_user(name.to_string(), ip_address.to_string(), 3334, sender, false, Identity::generate().public_key()).unwrap()
+    }
+
+    fn available_names(registry: &Registry) -> Vec<String> {
+        registry.available_users().0.into_iter().map(|user| user.name).collect()
+    }
+
+    fn create(registry: &mut Registry, name: &str) -> Result<RoomId, RoomError> {
+        registry.create_room(NewRoom::public(name.to_string()), None)
+    }
+
+    fn room_names(registry: &Registry) -> Vec<String> {
+        registry.room_list().0.into_iter().map(|room| room.name).collect()
+    }
+
+    #[test]
+    fn users_can_be_found_by_id_and_name() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+
+        assert_ne!(alice, bob);
+        assert_eq!(registry.get_id_by_name("bob"), Some(bob));
+        assert_eq!(registry.get_user(alice).unwrap().ip_address, "10.0.0.1");
+        assert_eq!(registry.get_id_by_name("carol"), None);
+        assert_eq!(available_names(&registry), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn removed_users_are_gone_from_every_index() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+
+        assert_eq!(registry.remove_user(alice).unwrap().name, "alice");
+        assert!(registry.get_user(alice).is_none());
+        assert_eq!(registry.get_id_by_name("alice"), None);
+        assert!(registry.remove_user(alice).is_none());
+    }
+
+    #[test]
+    fn ids_are_never_handed_out_again() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        registry.remove_user(alice);
+        let carol = add(&mut registry, "carol", "10.0.0.3");
+
+        assert_ne!(carol, alice);
+        assert_ne!(carol, bob);
+        assert!(registry.get_user(alice).is_none());
+        assert_eq!(registry.get_id_by_name("bob"), Some(bob));
+    }
+
+    #[test]
+    fn more_users_than_fit_in_a_byte_get_distinct_ids() {
+        let mut registry = Registry::default();
+        let ids: Vec<UserId> = (0..1000).map(|i| add(&mut registry, &format!("user{}", i), "10.0.0.1")).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), ids.len());
+        assert_eq!(registry.get_id_by_name("user999"), Some(ids[999]));
+    }
+
+    #[test]
+    fn rooms_get_their_own_ids_and_valid_names() {
+        let mut registry = Registry::default();
+        let lobby = create(&mut registry, "Lobby").unwrap();
+        let games = create(&mut registry, "Games #1").unwrap();
+
+        assert_ne!(lobby, games);
+        assert!(create(&mut registry, " padded ").is_err());
+        assert_eq!(room_names(&registry), vec!["Lobby", "Games #1"]);
+        assert_eq!(registry.find_room("GAMES #1"), Some(games));
+        assert_eq!(registry.find_room("Games"), None);
+    }
+
+    fn pushed(receiver: &mut UnboundedReceiver<Outgoing>) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(Outgoing::Envelope(envelope)) = receiver.try_recv() {
+            messages.push(envelope.message);
+        }
+        messages
+    }
+
+    #[test]
+    fn room_members_hear_each_other_and_about_joins_and_leaves() {
+        let mut registry = Registry::default();
+        create(&mut registry, "Lobby").unwrap();
+        create(&mut registry, "Games").unwrap();
+        let (sender, mut alice_receiver) = mpsc::unbounded_channel();
+        let alice = registry.add_user(String::from("alice"), String::from("10.0.0.1"), 3334, sender, true, Identity::generate().public_key()).unwrap();
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+
+        assert_eq!(registry.send_to_room(alice, Message{message: String::from("anyone?")}), Err(RoomError::NotInRoom));
+        assert_eq!(registry.join_room(alice, "nowhere", None), Err(RoomError::UnknownRoom));
+        registry.join_room(alice, "lobby", None).unwrap();
+        let joined = registry.join_room(bob, "Lobby", None).unwrap();
+        assert_eq!(joined.name, "Lobby");
+        assert_eq!(joined.members.iter().map(|member| member.name.as_str()).collect::<Vec<_>>(), vec!["alice", "bob"]);
+        assert!(available_names(&registry).is_empty());
+
+        registry.send_to_room(bob, Message{message: String::from("hi")}).unwrap();
+        registry.join_room(bob, "Games", None).unwrap();
+        assert_eq!(pushed(&mut alice_receiver), vec![
+            ServerMessage::RoomEvent(RoomEvent::Joined(UserSummary{name: String::from("bob"), verified: false})),
+            ServerMessage::RoomMessage{sender: String::from("bob"), message: Message{message: String::from("hi")}},
+            ServerMessage::RoomEvent(RoomEvent::Left(String::from("bob")))
+        ]);
+
+        assert_eq!(registry.leave_room(alice), Some(String::from("Lobby")));
+        assert_eq!(registry.leave_room(alice), None);
+        assert_eq!(available_names(&registry), vec!["alice"]);
+        registry.join_room(alice, "Games", None).unwrap();
+        registry.remove_user(bob);
+        assert_eq!(pushed(&mut alice_receiver), vec![ServerMessage::RoomEvent(RoomEvent::Left(String::from("bob")))]);
+        assert_eq!(registry.joined_room(registry.users[&alice].room.unwrap()).members.len(), 1);
+    }
+
+    #[test]
+    fn owners_manage_their_rooms_and_members_are_told() {
+        let mut registry = Registry::default();
+        create(&mut registry, "Lobby").unwrap();
+        let (sender, mut alice_receiver) = mpsc::unbounded_channel();
+        let alice = registry.add_user(String::from("alice"), String::from("10.0.0.1"), 3334, sender, true, Identity::generate().public_key()).unwrap();
+        let (sender, mut bob_receiver) = mpsc::unbounded_channel();
+        let bob = registry.add_user(String::from("bob"), String::from("10.0.0.2"), 3334, sender, false, Identity::generate().public_key()).unwrap();
+
+        let secret = NewRoom{
+            name: String::from("Secret"), topic: Some(String::from("plans")), access: Access::Password(String::from("hunter22")), visibility: Visibility::Unlisted
+        };
+        assert_eq!(registry.create_room_for(alice, secret).unwrap().topic.as_deref(), Some("plans"));
+        assert_eq!(registry.create_room_for(bob, NewRoom::public(String::from("secret"))), Err(RoomError::NameTaken));
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+        assert_eq!(registry.join_room(bob, "Secret", None), Err(RoomError::WrongPassword));
+        assert_eq!(registry.join_room(bob, "Secret", Some("hunter2")), Err(RoomError::WrongPassword));
+        registry.join_room(bob, "Secret", Some("hunter22")).unwrap();
+
+        assert_eq!(registry.rename_room(bob, "Secret", String::from("Mine")), Err(RoomError::NotOwner));
+        assert_eq!(registry.set_topic(bob, "Secret", None), Err(RoomError::NotOperator));
+        assert_eq!(registry.delete_room(alice, "Lobby"), Err(RoomError::NotOwner));
+        assert_eq!(registry.rename_room(alice, "secret", String::from("lobby")), Err(RoomError::NameTaken));
+        assert_eq!(registry.rename_room(alice, "secret", String::from("Hideout")), Ok(String::from("Secret")));
+        assert_eq!(registry.find_room("Secret"), None);
+        assert_eq!(registry.set_topic(alice, "Hideout", None), Ok(String::from("Hideout")));
+        assert_eq!(registry.delete_room(alice, "Hideout"), Ok(String::from("Hideout")));
+
+        let by = String::from("alice");
+        assert_eq!(pushed(&mut bob_receiver), vec![
+            ServerMessage::RoomEvent(RoomEvent::Renamed{by: by.clone(), new_name: String::from("Hideout")}),
+            ServerMessage::RoomEvent(RoomEvent::TopicChanged{by: by.clone(), topic: None}),
+            ServerMessage::RoomEvent(RoomEvent::Deleted{by}),
+            ServerMessage::LeftRoom(String::from("Hideout"))
+        ]);
+        assert_eq!(pushed(&mut alice_receiver), vec![
+            ServerMessage::RoomEvent(RoomEvent::Joined(UserSummary{name: String::from("bob"), verified: false})),
+            ServerMessage::LeftRoom(String::from("Hideout"))
+        ]);
+        assert_eq!(available_names(&registry), vec!["alice", "bob"]);
+        assert_eq!(registry.join_room(bob, "Hideout", None), Err(RoomError::UnknownRoom));
+        assert_eq!(registry.room_ids_by_name.len(), 1);
+        assert_eq!(registry.room_list(), (vec![RoomSummary{name: String::from("Lobby"), topic: None, members: 0, access: AccessMode::Open}], 0));
+    }
+
+    #[test]
+    fn rooms_users_create_are_gone_once_the_last_member_leaves() {
+        let mut registry = Registry::new(Limits{max_users: None, max_rooms: Some(2)}, RoomSettings::default());
+        create(&mut registry, "Lobby").unwrap();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        registry.create_room_for(alice, NewRoom::public(String::from("Den"))).unwrap();
+        registry.join_room(bob, "Den", None).unwrap();
+        assert_eq!(registry.create_room_for(bob, NewRoom::public(String::from("Attic"))), Err(RoomError::TooManyRooms));
+
+        registry.leave_room(alice);
+        assert_eq!(room_names(&registry), vec!["Lobby", "Den"]);
+        registry.join_room(bob, "Lobby", None).unwrap();
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+        assert_eq!(registry.find_room("Den"), None);
+        registry.leave_room(bob);
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+        registry.create_room_for(bob, NewRoom::public(String::from("Den"))).unwrap();
+    }
+
+    #[test]
+    fn rooms_users_create_are_gone_once_the_last_member_is_thrown_out() {
+        let settings = RoomSettings{operators: vec![name_key("alice")], ..RoomSettings::default()};
+        let mut registry = Registry::new(Limits::default(), settings);
+        create(&mut registry, "Lobby").unwrap();
+        let (sender, _) = mpsc::unbounded_channel();
+        let alice = registry.add_user(String::from("alice"), String::from("10.0.0.1"), 3334, sender, true, Identity::generate().public_key()).unwrap();
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        let carol = add(&mut registry, "carol", "10.0.0.3");
+        registry.join_room(alice, "Lobby", None).unwrap();
+        for (user, room) in [(bob, "Den"), (carol, "Attic")] {
+            registry.create_room_for(alice, NewRoom::public(room.to_string())).unwrap();
+            registry.join_room(user, room, None).unwrap();
+            registry.join_room(alice, "Lobby", None).unwrap();
+        }
+
+        // the owner need not be in there to throw the last member out
+        registry.moderate(alice, "Den", Moderation::Kick(String::from("bob"))).unwrap();
+        registry.moderate(alice, "Attic", Moderation::Ban{name: String::from("carol"), by_ip: false}).unwrap();
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+        assert_eq!((registry.find_room("Den"), registry.find_room("Attic")), (None, None));
+
+        // the rooms the server starts with stay
+        registry.join_room(bob, "Lobby", None).unwrap();
+        registry.leave_room(alice);
+        registry.moderate(alice, "Lobby", Moderation::Kick(String::from("bob"))).unwrap();
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+    }
+
+    #[test]
+    fn configured_operators_keep_order_in_the_rooms_the_server_starts_with() {
+        let settings = RoomSettings{operators: vec![name_key("Alice"), name_key("mallory")], ..RoomSettings::default()};
+        let mut registry = Registry::new(Limits::default(), settings);
+        create(&mut registry, "Lobby").unwrap();
+        let (sender, _) = mpsc::unbounded_channel();
+        let alice = registry.add_user(String::from("alice"), String::from("10.0.0.1"), 3334, sender, true, Identity::generate().public_key()).unwrap();
+        // a guest that got hold of the name is no operator
+        let mallory = add(&mut registry, "mallory", "10.0.0.2");
+        let bob = add(&mut registry, "bob", "10.0.0.3");
+        for user in [alice, mallory, bob] {
+            registry.join_room(user, "Lobby", None).unwrap();
+        }
+
+        assert_eq!(registry.moderate(mallory, "Lobby", Moderation::Kick(String::from("bob"))), Err(RoomError::NotOperator));
+        registry.moderate(alice, "Lobby", Moderation::Mute(String::from("bob"))).unwrap();
+        registry.leave_room(alice);
+        registry.join_room(alice, "Lobby", None).unwrap();
+        let lobby = registry.find_room("Lobby").unwrap();
+        assert_eq!(registry.rooms[&lobby].operators.len(), 1);
+        assert_eq!(registry.moderate(alice, "Lobby", Moderation::SetRole{name: String::from("bob"), role: RoomRole::Operator}), Err(RoomError::NotOwner));
+
+        registry.create_room_for(bob, NewRoom::public(String::from("Den"))).unwrap();
+        registry.join_room(mallory, "Den", None).unwrap();
+        for _ in 0..2 {
+            registry.moderate(bob, "Den", Moderation::SetRole{name: String::from("mallory"), role: RoomRole::Operator}).unwrap();
+        }
+        let den = registry.find_room("Den").unwrap();
+        assert_eq!(registry.rooms[&den].operators.len(), 1);
+        registry.moderate(bob, "Den", Moderation::SetRole{name: String::from("mallory"), role: RoomRole::Member}).unwrap();
+        assert_eq!(registry.rooms[&den].role(&registry.users[&mallory].public_key), RoomRole::Member);
+    }
+
+    #[test]
+    fn banning_someone_again_replaces_the_ban() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        registry.create_room_for(alice, NewRoom::public(String::from("Den"))).unwrap();
+        registry.join_room(bob, "Den", None).unwrap();
+
+        for _ in 0..3 {
+            registry.moderate(alice, "Den", Moderation::Ban{name: String::from("bob"), by_ip: false}).unwrap();
+        }
+        let den = registry.find_room("Den").unwrap();
+        assert_eq!(registry.rooms[&den].bans.len(), 1);
+        registry.rename(bob, String::from("robert")).unwrap();
+        registry.moderate(alice, "Den", Moderation::Ban{name: String::from("robert"), by_ip: true}).unwrap();
+        assert_eq!(registry.rooms[&den].bans, vec![RoomBan{
+            name: String::from("robert"), key: registry.users[&bob].public_key, ip_address: Some(String::from("10.0.0.2"))
+        }]);
+
+        // one unban is enough, whatever the name was before
+        registry.moderate(alice, "Den", Moderation::Unban(String::from("robert"))).unwrap();
+        assert!(registry.join_room(bob, "Den", None).is_ok());
+    }
+
+    #[test]
+    fn moderation_goes_by_role_and_reaches_whoever_it_is_aimed_at() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        let (sender, mut carol_receiver) = mpsc::unbounded_channel();
+        let carol = registry.add_user(String::from("carol"), String::from("10.0.0.3"), 3334, sender, false, Identity::generate().public_key()).unwrap();
+        let dave = add(&mut registry, "dave", "10.0.0.4");
+        let eve = add(&mut registry, "eve", "10.0.0.4");
+        registry.create_room_for(alice, NewRoom::public(String::from("Den"))).unwrap();
+        for user in [bob, carol, dave] {
+            registry.join_room(user, "Den", None).unwrap();
+        }
+        pushed(&mut carol_receiver);
+
+        assert_eq!(registry.moderate(bob, "Den", Moderation::Kick(String::from("carol"))), Err(RoomError::NotOperator));
+        registry.moderate(alice, "Den", Moderation::SetRole{name: String::from("bob"), role: RoomRole::Operator}).unwrap();
+        assert_eq!(registry.set_topic(bob, "Den", Some(String::from("be nice"))), Ok(String::from("Den")));
+        assert_eq!(registry.moderate(bob, "Den", Moderation::Kick(String::from("alice"))), Err(RoomError::Outranked(String::from("alice"))));
+        assert_eq!(registry.moderate(bob, "Den", Moderation::SetRole{name: String::from("dave"), role: RoomRole::Operator}), Err(RoomError::NotOwner));
+        assert_eq!(registry.moderate(bob, "Den", Moderation::Kick(String::from("nobody"))), Err(RoomError::UnknownUser(String::from("nobody"))));
+        registry.moderate(bob, "Den", Moderation::Kick(String::from("carol"))).unwrap();
+        assert_eq!(registry.moderate(bob, "Den", Moderation::Kick(String::from("carol"))), Err(RoomError::NotAMember(String::from("carol"))));
+        let kicked = Moderation::Kick(String::from("carol"));
+        assert_eq!(pushed(&mut carol_receiver), vec![
+            ServerMessage::RoomEvent(RoomEvent::Moderated{by: String::from("alice"), action: Moderation::SetRole{name: String::from("bob"), role: RoomRole::Operator}}),
+            ServerMessage::RoomEvent(RoomEvent::TopicChanged{by: String::from("bob"), topic: Some(String::from("be nice"))}),
+            ServerMessage::RoomEvent(RoomEvent::Moderated{by: String::from("bob"), action: kicked}),
+            ServerMessage::LeftRoom(String::from("Den"))
+        ]);
+
+        registry.moderate(bob, "Den", Moderation::Ban{name: String::from("carol"), by_ip: false}).unwrap();
+        assert_eq!(registry.join_room(carol, "Den", None), Err(RoomError::Banned));
+        registry.moderate(bob, "Den", Moderation::Unban(String::from("Carol"))).unwrap();
+        registry.join_room(carol, "Den", None).unwrap();
+
+        registry.moderate(bob, "Den", Moderation::Mute(String::from("dave"))).unwrap();
+        assert_eq!(registry.send_to_room(dave, Message{message: String::from("hey")}), Err(RoomError::Muted));
+        registry.moderate(bob, "Den", Moderation::Unmute(String::from("dave"))).unwrap();
+        registry.send_to_room(dave, Message{message: String::from("hey")}).unwrap();
+        registry.moderate(bob, "Den", Moderation::Ban{name: String::from("dave"), by_ip: true}).unwrap();
+        assert_eq!(registry.users[&dave].room, None);
+        assert_eq!(registry.join_room(eve, "Den", None), Err(RoomError::Banned));
+
+        registry.moderate(alice, "Den", Moderation::SetRole{name: String::from("bob"), role: RoomRole::Owner}).unwrap();
+        assert_eq!(registry.rename_room(alice, "Den", String::from("Lair")), Err(RoomError::NotOwner));
+        assert_eq!(registry.moderate(alice, "Den", Moderation::Kick(String::from("bob"))), Err(RoomError::Outranked(String::from("bob"))));
+        assert_eq!(registry.moderate(alice, "Den", Moderation::Kick(String::from("carol"))), Ok(()));
+        assert_eq!(registry.rename_room(bob, "Den", String::from("Lair")), Ok(String::from("Den")));
+    }
+
+    #[test]
+    fn invites_get_past_passwords_and_invite_only_rooms_once() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let (sender, mut bob_receiver) = mpsc::unbounded_channel();
+        let bob = registry.add_user(String::from("bob"), String::from("10.0.0.2"), 3334, sender, false, Identity::generate().public_key()).unwrap();
+        let carol = add(&mut registry, "carol", "10.0.0.3");
+        registry.create_room_for(alice, NewRoom{
+            name: String::from("Club"), topic: None, access: Access::InviteOnly, visibility: Visibility::Public
+        }).unwrap();
+        assert_eq!(registry.room_list().0[0].access, AccessMode::InviteOnly);
+
+        assert_eq!(registry.join_room(bob, "Club", None), Err(RoomError::NotInvited));
+        assert_eq!(registry.invite(carol, "Club", "bob"), Err(RoomError::NotInRoom));
+        assert_eq!(registry.invite(alice, "Club", "nobody"), Err(RoomError::UnknownUser(String::from("nobody"))));
+        registry.invite(alice, "Club", "Bob").unwrap();
+        assert_eq!(pushed(&mut bob_receiver), vec![ServerMessage::Invited{
+            room: String::from("Club"), by: String::from("alice"), expires_in_secs: RoomSettings::DEFAULT_INVITE_LIFETIME.as_secs()
+        }]);
+        registry.join_room(bob, "Club", None).unwrap();
+        registry.leave_room(bob);
+        assert_eq!(registry.join_room(bob, "Club", None), Err(RoomError::NotInvited));
+
+        registry.create_room_for(carol, NewRoom{
+            name: String::from("Vault"), topic: None, access: Access::Password(String::from("hunter22")), visibility: Visibility::Public
+        }).unwrap();
+        assert_eq!(registry.join_room(bob, "Vault", Some("hunter2")), Err(RoomError::WrongPassword));
+        registry.join_room(bob, "Vault", Some("hunter22")).unwrap();
+        registry.leave_room(bob);
+        registry.invite(carol, "Vault", "bob").unwrap();
+        registry.join_room(bob, "Vault", None).unwrap();
+
+        let mut registry = Registry::new(Limits::default(), RoomSettings{invite_lifetime: Duration::ZERO, ..RoomSettings::default()});
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        registry.create_room_for(alice, NewRoom{
+            name: String::from("Club"), topic: None, access: Access::InviteOnly, visibility: Visibility::Public
+        }).unwrap();
+        registry.invite(alice, "Club", "bob").unwrap();
+        assert_eq!(registry.join_room(bob, "Club", None), Err(RoomError::NotInvited));
+    }
+
+    #[test]
+    fn whoever_joins_gets_the_latest_messages_that_fit_the_backlog() {
+        let settings = RoomSettings{backlog_size: 2, ..RoomSettings::default()};
+        let mut registry = Registry::new(Limits::default(), settings.clone());
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        create(&mut registry, "Lobby").unwrap();
+        assert_eq!(registry.join_room(alice, "Lobby", None).unwrap().history, Vec::new());
+        for text in ["one", "two", "three"] {
+            registry.send_to_room(alice, Message{message: text.to_string()}).unwrap();
+        }
+
+        let history = registry.join_room(bob, "Lobby", None).unwrap().history;
+        assert_eq!(history.iter().map(|entry| (entry.sender.as_str(), entry.message.message.as_str())).collect::<Vec<_>>(),
+                   vec![("alice", "two"), ("alice", "three")]);
+        assert!(history.iter().all(|entry| entry.age_secs < settings.backlog_age.as_secs()));
+
+        let mut registry = Registry::new(Limits::default(), RoomSettings{backlog_age: Duration::ZERO, ..settings});
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        create(&mut registry, "Lobby").unwrap();
+        registry.join_room(alice, "Lobby", None).unwrap();
+        registry.send_to_room(alice, Message{message: String::from("gone")}).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(registry.join_room(bob, "Lobby", None).unwrap().history, Vec::new());
+    }
+
+    #[test]
+    fn long_messages_leave_out_older_history_instead_of_overflowing_the_frame() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        create(&mut registry, "Lobby").unwrap();
+        registry.join_room(alice, "Lobby", None).unwrap();
+        for text in ["a", "b", "c", "d", "e"] {
+            registry.send_to_room(alice, Message{message: text.repeat(MAX_MESSAGE_BYTES)}).unwrap();
+        }
+
+        let JoinedRoom{name, topic, members, unlisted_members, history} = registry.join_room(bob, "Lobby", None).unwrap();
+        assert!(!history.is_empty() && history.len() < 5);
+        assert_eq!(history.last().unwrap().message.message, "e".repeat(MAX_MESSAGE_BYTES));
+        let joined = ServerMessage::JoinedRoom{room: name, topic, members, unlisted_members, history};
+        assert!(codec::encode_frame(&ServerEnvelope{request_id: Some(0), message: joined}).is_ok());
+    }
+
+    #[test]
+    fn crowded_servers_list_as_many_users_as_fit_a_frame() {
+        let mut registry = Registry::default();
+        // all of them would take up more than a frame
+        for i in 0..2 * UserSummary::MAX_LISTED {
+            add(&mut registry, &format!("{:0>32}", i), "10.0.0.1");
+        }
+
+        let (users, unlisted) = registry.available_users();
+        assert_eq!((users.len(), unlisted), (UserSummary::MAX_LISTED, UserSummary::MAX_LISTED));
+        assert_eq!(users[0].name, format!("{:0>32}", 0));
+        let list = ServerMessage::UserList{users, unlisted};
+        assert!(codec::encode_frame(&ServerEnvelope{request_id: Some(0), message: list}).is_ok());
+    }
+
+    #[test]
+    fn crowded_servers_list_as_many_rooms_as_fit_a_frame() {
+        let mut registry = Registry::default();
+        // all of them would take up more than a frame
+        for i in 0..2 * RoomSummary::MAX_LISTED {
+            let mut room = NewRoom::public(format!("{:0>64}", i));
+            room.topic = Some("t".repeat(MAX_TOPIC_BYTES));
+            registry.create_room(room, None).unwrap();
+        }
+
+        let (rooms, unlisted) = registry.room_list();
+        assert_eq!((rooms.len(), unlisted), (RoomSummary::MAX_LISTED, RoomSummary::MAX_LISTED));
+        assert_eq!(rooms[0].name, format!("{:0>64}", 0));
+        let list = ServerMessage::RoomList{rooms, unlisted};
+        assert!(codec::encode_frame(&ServerEnvelope{request_id: Some(0), message: list}).is_ok());
+    }
+
+    #[test]
+    fn crowded_rooms_list_as_many_members_as_fit_a_frame() {
+        let mut registry = Registry::default();
+        create(&mut registry, "Lobby").unwrap();
+        // all of them would take up more than a frame
+        let mut joined = None;
+        for i in 0..2 * UserSummary::MAX_LISTED {
+            let user = add(&mut registry, &format!("{:0>32}", i), "10.0.0.1");
+            joined = Some(registry.join_room(user, "Lobby", None).unwrap());
+        }
+
+        let JoinedRoom{name, topic, members, unlisted_members, history} = joined.unwrap();
+        assert_eq!((members.len(), unlisted_members), (UserSummary::MAX_LISTED, UserSummary::MAX_LISTED));
+        assert_eq!(members[0].name, format!("{:0>32}", 0));
+        let joined = ServerMessage::JoinedRoom{room: name, topic, members, unlisted_members, history};
+        assert!(codec::encode_frame(&ServerEnvelope{request_id: Some(0), message: joined}).is_ok());
+    }
+
+    #[test]
+    fn full_rooms_with_a_full_backlog_still_fit_a_frame() {
+        let mut registry = Registry::default();
+        let mut room = NewRoom::public("#".repeat(MAX_ROOM_NAME_BYTES));
+        room.topic = Some("t".repeat(MAX_TOPIC_BYTES));
+        registry.create_room(room.clone(), None).unwrap();
+        let mut members = Vec::new();
+        for i in 0..UserSummary::MAX_LISTED {
+            let user = add(&mut registry, &format!("{:0>32}", i), "10.0.0.1");
+            registry.join_room(user, &room.name, None).unwrap();
+            members.push(user);
+        }
+        for text in ["a", "b", "c", "d", "e"] {
+            registry.send_to_room(members[0], Message{message: text.repeat(MAX_MESSAGE_BYTES / 4)}).unwrap();
+        }
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+
+        let JoinedRoom{name, topic, members, unlisted_members, history} = registry.join_room(bob, &room.name, None).unwrap();
+        assert_eq!((members.len(), unlisted_members), (UserSummary::MAX_LISTED, 1));
+        assert!(!history.is_empty() && history.len() < 5);
+        assert_eq!(history.last().unwrap().message.message, "e".repeat(MAX_MESSAGE_BYTES / 4));
+        let joined = ServerMessage::JoinedRoom{room: name, topic, members, unlisted_members, history};
+        assert!(codec::encode_frame(&ServerEnvelope{request_id: Some(0), message: joined}).is_ok());
+    }
+
+    #[test]
+    fn taken_names_are_refused_with_free_suggestions() {
+        let mut registry = Registry::default();
+        let bob = add(&mut registry, "bob", "10.0.0.1");
+        add(&mut registry, "bob2", "10.0.0.2");
+
+        let (sender, _) = mpsc::unbounded_channel();
+        let taken = registry.add_user(String::from("Bob"), String::from("10.0.0.3"), 3334, sender, false, Identity::generate().public_key());
+        assert_eq!(taken, Err(AddUserError::NameTaken(NameTaken{suggestions: vec![String::from("Bob3"), String::from("Bob4"), String::from("Bob5")]})));
+        assert_eq!(registry.get_id_by_name("BOB"), Some(bob));
+        assert_eq!(available_names(&registry), vec!["bob", "bob2"]);
+
+        registry.remove_user(bob);
+        add(&mut registry, "Bob", "10.0.0.3");
+    }
+
+    #[test]
+    fn limits_turn_away_users_and_rooms_beyond_them() {
+        let mut registry = Registry::new(Limits{max_users: Some(2), max_rooms: Some(1)}, RoomSettings::default());
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        add(&mut registry, "bob", "10.0.0.2");
+
+        let (sender, _) = mpsc::unbounded_channel();
+        let full = registry.add_user(String::from("carol"), String::from("10.0.0.3"), 3334, sender, false, Identity::generate().public_key());
+        assert_eq!(full, Err(AddUserError::ServerFull));
+        registry.remove_user(alice);
+        add(&mut registry, "carol", "10.0.0.3");
+
+        create(&mut registry, "Lobby").unwrap();
+        assert_eq!(create(&mut registry, "Games"), Err(RoomError::TooManyRooms));
+        assert_eq!(room_names(&registry), vec!["Lobby"]);
+    }
+
+    #[test]
+    fn suggestions_for_long_names_still_fit() {
+        let mut registry = Registry::default();
+        let long_name = "ä".repeat(MAX_USER_NAME_BYTES / 2);
+        add(&mut registry, &long_name, "10.0.0.1");
+
+        let suggestions = registry.suggest_names(&long_name);
+        assert_eq!(suggestions.len(), MAX_SUGGESTIONS);
+        for suggestion in suggestions {
+            assert!(validation::validate_user_name(&suggestion).is_ok());
+            assert!(suggestion.starts_with(&"ä".repeat(MAX_USER_NAME_BYTES / 2 - 1)));
+        }
+    }
+
+    #[test]
+    fn renaming_frees_the_old_name_and_tells_everyone_else() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let bob = registry.add_user(String::from("bob"), String::from("10.0.0.2"), 3334, sender, false, Identity::generate().public_key()).unwrap();
+
+        assert_eq!(registry.rename(alice, String::from("bob")).unwrap_err().suggestions, vec!["bob2", "bob3", "bob4"]);
+        assert_eq!(registry.rename(alice, String::from("carol")), Ok(Some(String::from("alice"))));
+        assert_eq!(registry.get_id_by_name("carol"), Some(alice));
+        assert_eq!(registry.get_id_by_name("alice"), None);
+        add(&mut registry, "alice", "10.0.0.3");
+
+        match receiver.try_recv() {
+            Ok(Outgoing::Envelope(envelope)) =>
+                assert_eq!(envelope, ServerEnvelope::push(ServerMessage::Renamed{old_name: String::from("alice"), new_name: String::from("carol")})),
+            _ => panic!("bob was not told about the new name")
+        }
+        assert_eq!(registry.rename(bob, String::from("Bob")), Ok(Some(String::from("bob"))));
+        registry.remove_user(bob);
+        assert_eq!(registry.rename(bob, String::from("dave")), Ok(None));
+    }
+
+    #[test]
+    fn pairing_hands_out_matching_selections() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        let (sender, _) = mpsc::unbounded_channel();
+        let bob = registry.add_user(String::from("bob"), String::from("10.0.0.2"), 4000, sender, false, Identity::generate().public_key()).unwrap();
+
+        let pairing = registry.pair(alice, "bob").unwrap();
+        assert!(pairing.master_id == alice || pairing.master_id == bob);
+        assert_eq!(pairing.own_selection.chat_partner_name, "bob");
+        assert!(!pairing.own_selection.chat_partner_verified);
+        assert_eq!(pairing.own_selection.chat_partner_key, registry.get_user(bob).unwrap().public_key);
+        assert_eq!(pairing.other_selection.chat_partner_key, registry.get_user(alice).unwrap().public_key);
+        assert_eq!(pairing.other_selection.chat_partner_name, "alice");
+        assert_eq!(pairing.own_selection.target_ip, pairing.other_selection.target_ip);
+        assert_ne!(pairing.own_selection.is_own_ip, pairing.other_selection.is_own_ip);
+        let (master_ip, master_port) = if pairing.master_id == alice { ("10.0.0.1", 3334) } else { ("10.0.0.2", 4000) };
+        assert_eq!(pairing.own_selection.target_ip, master_ip);
+        assert_eq!(pairing.own_selection.target_port, master_port);
+        assert_eq!(pairing.other_selection.target_port, master_port);
+    }
+
+    #[test]
+    fn paired_users_are_unavailable_until_released() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+        add(&mut registry, "bob", "10.0.0.2");
+        let carol = add(&mut registry, "carol", "10.0.0.3");
+
+        assert!(registry.pair(alice, "bob").is_ok());
+        assert_eq!(registry.pair(carol, "bob").err(), Some(PairingError::NotAvailable));
+        assert_eq!(registry.pair(carol, "alice").err(), Some(PairingError::NotAvailable));
+        assert_eq!(available_names(&registry), vec!["carol"]);
+
+        registry.release(alice);
+        assert!(registry.pair(carol, "alice").is_ok());
+    }
+
+    #[test]
+    fn waiting_connections_hear_of_everyone_who_becomes_available() {
+        let mut registry = Registry::default();
+        let user_available = registry.user_available();
+        // true once the registry fired since the waiter was set up
+        let fired_during = |registry: &mut Registry, change: &dyn Fn(&mut Registry)| {
+            let waiter = user_available.notified();
+            tokio::pin!(waiter);
+            assert!(!waiter.as_mut().enable());
+            change(registry);
+            waiter.as_mut().enable()
+        };
+
+        assert!(fired_during(&mut registry, &|registry| { add(registry, "alice", "10.0.0.1"); }));
+        let alice = registry.get_id_by_name("alice").unwrap();
+        let bob = add(&mut registry, "bob", "10.0.0.2");
+        registry.create_room_for(alice, NewRoom::public(String::from("Den"))).unwrap();
+        registry.join_room(bob, "Den", None).unwrap();
+        assert!(fired_during(&mut registry, &|registry| { registry.moderate(alice, "Den", Moderation::Kick(String::from("bob"))).unwrap(); }));
+        registry.join_room(bob, "Den", None).unwrap();
+        assert!(fired_during(&mut registry, &|registry| { registry.delete_room(alice, "Den").unwrap(); }));
+        create(&mut registry, "Lobby").unwrap();
+        registry.join_room(alice, "Lobby", None).unwrap();
+        assert!(fired_during(&mut registry, &|registry| { registry.leave_room(alice); }));
+        registry.pair(alice, "bob").unwrap();
+        assert!(fired_during(&mut registry, &|registry| registry.release(alice)));
+        assert!(!fired_during(&mut registry, &|registry| { registry.join_room(alice, "Lobby", None).unwrap(); }));
+    }
+
+    #[test]
+    fn nobody_can_pair_with_unknown_users_or_themselves() {
+        let mut registry = Registry::default();
+        let alice = add(&mut registry, "alice", "10.0.0.1");
+
+        assert_eq!(registry.pair(alice, "bob").err(), Some(PairingError::UnknownUser));
+        assert_eq!(registry.pair(alice, "alice").err(), Some(PairingError::UnknownUser));
+        assert_eq!(available_names(&registry), vec!["alice"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_for_the_same_user_pair_only_once() {
+        let mut registry = Registry::default();
+        add(&mut registry, "bob", "10.0.0.1");
+        let requesters: Vec<UserId> = (0..10).map(|i| add(&mut registry, &format!("user{}", i), "10.0.0.2")).collect();
+        let handle = RegistryHandle::spawn(registry);
+
+        let tasks: Vec<_> = requesters.into_iter()
+            .map(|id| {
+                let handle = handle.clone();
+                tokio::spawn(async move { handle.pair(id, String::from("bob")).await.is_ok() })
+            })
+            .collect();
+        let mut successes = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                successes += 1;
+            }
+        }
+        assert_eq!(successes, 1);
+    }
+}
@@ -0,0 +1,41 @@
+//! Optional TLS on the listener, so names, user lists and addresses don't cross the network in
+//! plain text. A self-signed certificate is fine, clients pin it instead of asking a CA.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_pki_types::pem::PemObject;
+
+/// What a connection reads from and writes to, with or without TLS in between.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> ClientStream for S {}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TlsConfig {
+    /// PEM, our certificate first, followed by whatever intermediates clients need
+    pub cert_file: PathBuf,
+    /// PEM
+    pub key_file: PathBuf
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> Result<TlsAcceptor, String> {
+        let certificates = CertificateDer::pem_file_iter(&self.cert_file)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("cannot read certificates from {}: {}", self.cert_file.display(), e))?;
+        if certificates.is_empty() {
+            return Err(format!("{} holds no certificate", self.cert_file.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_file)
+            .map_err(|e| format!("cannot read the private key from {}: {}", self.key_file.display(), e))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(|e| format!("certificate and key don't fit together: {}", e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}